@@ -0,0 +1,329 @@
+//! 主动模式 (active checks)
+//!
+//! agent 定期向服务端发送 `active checks` 请求获取需要采集的监控项，
+//! 按各监控项的间隔在本地采集，再通过 `agent data` 请求批量发送。
+use chrono::prelude::*;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::delay::Delay;
+use super::handler::LogValue;
+
+/// 服务端返回的主动检查项
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActiveCheck {
+    pub key: String,
+    /// 6.4 以前的服务端不返回，为 0
+    #[serde(default)]
+    pub itemid: i64,
+    /// 采集间隔，不需要检查（如包含无法解析的宏）时不采集
+    #[serde(
+        deserialize_with = "deserialize_delay",
+        serialize_with = "serialize_delay"
+    )]
+    pub delay: Delay,
+    #[serde(default)]
+    pub lastlogsize: u64,
+    #[serde(default)]
+    pub mtime: i64,
+}
+
+///
+/// 间隔可能是数字或 `30s;50s/1-5,09:00-18:00` 这样的字符串，无效时为 0
+///
+fn deserialize_delay<'de, D>(deserializer: D) -> std::result::Result<Delay, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let delay = match Value::deserialize(deserializer)? {
+        Value::Number(n) => n.as_u64().and_then(|n| Delay::parse(&n.to_string()).ok()),
+        Value::String(s) => Delay::parse(&s).ok(),
+        _ => None,
+    };
+    Ok(delay.unwrap_or_else(|| Delay::from(0)))
+}
+
+fn serialize_delay<S: Serializer>(
+    delay: &Delay,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(delay)
+}
+
+/// `active checks` 请求
+#[derive(Serialize, Debug)]
+pub(crate) struct ActiveChecksRequest<'a> {
+    pub request: &'static str,
+    pub host: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_metadata: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<&'a str>,
+}
+
+/// `active checks` 应答
+#[derive(Deserialize, Debug)]
+pub(crate) struct ActiveChecksResponse {
+    pub response: String,
+    pub info: Option<String>,
+    #[serde(default)]
+    pub data: Vec<ActiveCheck>,
+}
+
+/// 主动模式采集的值
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AgentValue {
+    pub host: String,
+    pub key: String,
+    /// 只更新日志读取位置时为 None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// 为 1 时表示不支持，value 为原因
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<u8>,
+    /// 日志监控项读取到的位置
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastlogsize: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    pub id: u64,
+    pub clock: i64,
+    pub ns: i64,
+}
+
+impl AgentValue {
+    pub fn new(host: &str, key: &str, value: &str, id: u64) -> Self {
+        let now = Local::now();
+        Self {
+            host: String::from(host),
+            key: String::from(key),
+            value: Some(String::from(value)),
+            state: None,
+            lastlogsize: None,
+            mtime: None,
+            id,
+            clock: now.timestamp(),
+            ns: i64::from(now.timestamp_subsec_nanos()),
+        }
+    }
+
+    ///
+    /// 日志监控项的值，包含读取位置
+    ///
+    pub fn log(host: &str, key: &str, log: &LogValue, id: u64) -> Self {
+        let mut value = Self::new(host, key, &log.value, id);
+        value.lastlogsize = Some(log.lastlogsize);
+        value.mtime = Some(log.mtime);
+        value
+    }
+
+    ///
+    /// 不带值的日志读取位置更新，跳过不匹配的行后发送，服务端在下次 `active checks` 中返回
+    ///
+    pub fn meta(host: &str, key: &str, lastlogsize: u64, mtime: i64, id: u64) -> Self {
+        let mut value = Self::new(host, key, "", id);
+        value.value = None;
+        value.lastlogsize = Some(lastlogsize);
+        value.mtime = Some(mtime);
+        value
+    }
+
+    ///
+    /// 不支持的监控项，value 为原因
+    ///
+    pub fn not_supported(host: &str, key: &str, error: &str, id: u64) -> Self {
+        let mut value = Self::new(host, key, error, id);
+        value.state = Some(1);
+        value
+    }
+}
+
+/// `agent data` 请求
+#[derive(Serialize, Debug)]
+pub(crate) struct AgentDataRequest<'a> {
+    pub request: &'static str,
+    pub session: &'a str,
+    pub data: &'a [AgentValue],
+    pub clock: i64,
+    pub ns: i64,
+}
+
+impl<'a> AgentDataRequest<'a> {
+    pub fn new(session: &'a str, data: &'a [AgentValue]) -> Self {
+        let now = Local::now();
+        Self {
+            request: "agent data",
+            session,
+            data,
+            clock: now.timestamp(),
+            ns: i64::from(now.timestamp_subsec_nanos()),
+        }
+    }
+}
+
+///
+/// 生成 32 位十六进制的会话标识，服务端据此识别重复发送的数据
+///
+pub(crate) fn session_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    (0..2u8)
+        .map(|i| {
+            let mut h = RandomState::new().build_hasher();
+            h.write_u128(nanos);
+            h.write_u32(process::id());
+            h.write_u8(i);
+            format!("{:016x}", h.finish())
+        })
+        .collect()
+}
+
+/// 主动检查项的采集计划，时间为 Unix 时间戳（秒），None 表示不再采集
+#[derive(Debug, Default)]
+pub(crate) struct Schedule {
+    checks: Vec<(ActiveCheck, Option<i64>)>,
+}
+
+impl Schedule {
+    ///
+    /// 更新检查项，新的 key 立即采集；已有的 key 间隔不变时保留原来的下次采集时间，
+    /// 间隔或 itemid 变化时按新的间隔重新计算
+    ///
+    pub fn update(&mut self, checks: Vec<ActiveCheck>, now: i64) {
+        let mut scheduled = Vec::with_capacity(checks.len());
+        for check in checks.into_iter().filter(|c| c.delay.is_active()) {
+            let next = match self.checks.iter().find(|(c, _)| c.key == check.key) {
+                Some((c, next)) if c.delay == check.delay && c.itemid == check.itemid => *next,
+                Some(_) => check.delay.next_check(check.itemid, now),
+                None => Some(now),
+            };
+            scheduled.push((check, next));
+        }
+        self.checks = scheduled;
+    }
+
+    ///
+    /// 返回到期的检查项，并按间隔计算下次采集时间，见 `Delay::next_check`
+    ///
+    pub fn due(&mut self, now: i64) -> Vec<ActiveCheck> {
+        let mut result = vec![];
+        for (check, next) in self.checks.iter_mut() {
+            if next.is_some_and(|next| next <= now) {
+                result.push(check.clone());
+                *next = check.delay.next_check(check.itemid, now);
+            }
+        }
+        result
+    }
+
+    ///
+    /// 最近一次采集时间
+    ///
+    pub fn next_due(&self) -> Option<i64> {
+        self.checks.iter().filter_map(|(_, next)| *next).min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-09-13 12:26:40 UTC
+    const NOW: i64 = 1_600_000_000;
+
+    fn check(key: &str, delay: &str) -> ActiveCheck {
+        ActiveCheck {
+            key: key.to_string(),
+            itemid: 0,
+            delay: Delay::parse(delay).unwrap(),
+            lastlogsize: 0,
+            mtime: 0,
+        }
+    }
+
+    #[test]
+    fn test_active_check_delay() {
+        let checks: Vec<ActiveCheck> = serde_json::from_str(
+            r#"[{"key":"a","delay":30,"lastlogsize":0,"mtime":0},
+                {"key":"b","delay":"1m"},
+                {"key":"c","delay":"10s;50s/1-5,09:00-18:00"},
+                {"key":"d","delay":"{$DELAY}"},
+                {"key":"e","itemid":5,"delay":"0;wd1-5h9"}]"#,
+        )
+        .unwrap();
+        let delays: Vec<u32> = checks.iter().map(|c| c.delay.simple()).collect();
+        assert_eq!(vec![30, 60, 10, 0, 0], delays);
+        assert_eq!(1, checks[2].delay.flexible().len());
+        assert!(!checks[3].delay.is_active());
+        assert_eq!(5, checks[4].itemid);
+        assert!(checks[4].delay.is_active());
+        assert_eq!(
+            r#"{"key":"c","itemid":0,"delay":"10;50/1-5,09:00-18:00","lastlogsize":0,"mtime":0}"#,
+            serde_json::to_string(&checks[2]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut schedule = Schedule::default();
+        schedule.update(
+            vec![check("a", "10"), check("b", "30"), check("c", "0")],
+            NOW,
+        );
+
+        let due = schedule.due(NOW);
+        assert_eq!(
+            vec!["a", "b"],
+            due.iter().map(|c| &c.key).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(NOW + 10), schedule.next_due());
+        assert!(schedule.due(NOW + 5).is_empty());
+
+        let later = NOW + 10;
+        assert_eq!(vec![check("a", "10")], schedule.due(later));
+
+        // 间隔不变时保留已有 key 的采集时间
+        schedule.update(vec![check("b", "30"), check("d", "5")], later);
+        assert_eq!(vec![check("d", "5")], schedule.due(later));
+        assert_eq!(
+            vec![check("b", "30"), check("d", "5")],
+            schedule.due(NOW + 30)
+        );
+
+        // 间隔变化时按新的间隔计算
+        schedule.update(vec![check("b", "3600"), check("d", "5")], NOW + 30);
+        assert_eq!(Some(NOW + 35), schedule.next_due());
+        assert_eq!(vec![check("d", "5")], schedule.due(NOW + 35));
+        schedule.update(vec![check("b", "10"), check("d", "5")], NOW + 35);
+        assert_eq!(Some(NOW + 40), schedule.next_due());
+        assert_eq!(
+            vec![check("b", "10"), check("d", "5")],
+            schedule.due(NOW + 40)
+        );
+        let mut moved = check("b", "10");
+        moved.itemid = 3;
+        schedule.update(vec![moved], NOW + 40);
+        assert_eq!(Some(NOW + 43), schedule.next_due());
+
+        // 只有调度间隔或灵活间隔的检查项
+        let never = check("f", "0;0/1-7,00:00-24:00");
+        schedule.update(vec![check("e", "0;s/10"), never.clone()], NOW + 30);
+        assert_eq!(vec![check("e", "0;s/10"), never], schedule.due(NOW + 30));
+        assert_eq!(Some(NOW + 40), schedule.next_due());
+        assert_eq!(vec![check("e", "0;s/10")], schedule.due(NOW + 40));
+    }
+
+    #[test]
+    fn test_session_token() {
+        let session = session_token();
+        assert_eq!(32, session.len());
+        assert!(session.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(session, session_token());
+    }
+}
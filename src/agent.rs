@@ -1,18 +1,819 @@
 //! zabbix agent
 //!
+//! 被动模式下监听端口（默认 10050），接收服务端发送的监控项 key，
+//! 调用注册的处理函数并返回结果，不支持时返回 `ZBX_NOTSUPPORTED\0<原因>`。
+//!
+//! 主动模式下定期从服务端获取检查项，在本地采集后批量发送，适用于服务端无法连接 agent 的情况。
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::prelude::*;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::active::{
+    session_token, ActiveCheck, ActiveChecksRequest, ActiveChecksResponse, AgentDataRequest,
+    AgentValue, Schedule,
+};
+use super::frame::ZbxStream;
+use super::handler::{ItemHandler, ItemRegistry, ItemValue};
+use super::key::ItemKey;
+use super::logfile::{LogItem, LogState};
+use super::peer::AllowedPeers;
 use super::protocol::ZabbixProtocol;
+use super::response::Response;
+#[cfg(feature = "linux")]
+use super::system::register_system_items;
+#[cfg(feature = "tls")]
+use super::tls::TlsConfig;
+use super::userparam::{SharedUserParameter, UserParamOptions, UserParameter};
+use super::Result;
 
-///
-#[derive(Debug, Clone)]
+/// zabbix agent
+#[derive(Clone)]
 pub struct ZabbixAgent {
     name: String,
     proto: ZabbixProtocol,
+    cluster: Vec<(String, u16)>,
+    active_node: Arc<AtomicUsize>,
+    listen_port: u16,
+    timeout: Duration,
+    host_metadata: Option<String>,
+    interface: Option<String>,
+    refresh_active_checks: Duration,
+    buffer_send: Duration,
+    buffer_size: usize,
+    user_params: Arc<UserParamOptions>,
+    user_parameters: Vec<UserParameter>,
+    allowed_peers: Option<AllowedPeers>,
+    session: String,
+    registry: ItemRegistry,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+impl fmt::Debug for ZabbixAgent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ZabbixAgent")
+            .field("name", &self.name)
+            .field("proto", &self.proto)
+            .field("cluster", &self.cluster)
+            .field("listen_port", &self.listen_port)
+            .field("timeout", &self.timeout)
+            .field("host_metadata", &self.host_metadata)
+            .field("interface", &self.interface)
+            .field("refresh_active_checks", &self.refresh_active_checks)
+            .field("buffer_send", &self.buffer_send)
+            .field("buffer_size", &self.buffer_size)
+            .field("unsafe_user_parameters", &self.user_params.unsafe_params)
+            .field("allowed_peers", &self.allowed_peers)
+            .field("registry", &self.registry)
+            .finish()
+    }
 }
 
 impl ZabbixAgent {
+    pub const LISTEN_PORT: u16 = 10050;
+    pub const ZBX_NOTSUPPORTED: &'static str = "ZBX_NOTSUPPORTED";
+    pub const ACTIVE_CHECKS: &'static str = "active checks";
+    pub const AGENT_DATA: &'static str = "agent data";
+    /// 兼容的 zabbix agent 版本，`agent.version` 返回此值
+    pub const VERSION: &'static str = "6.4.0";
+
+    ///
+    /// name 为主机名 (Hostname)，server 和 port 为主动模式连接的服务端 (ServerActive)
+    ///
     pub fn new(name: &str, server: &str, port: u16) -> Self {
+        Self::with_protocol(name, ZabbixProtocol::new(server, port))
+    }
+
+    pub fn with_protocol(name: &str, proto: ZabbixProtocol) -> Self {
         let name = String::from(name);
-        let proto = ZabbixProtocol::new(server, port);
-        Self { name, proto }
+        Self {
+            name,
+            proto,
+            cluster: vec![],
+            active_node: Arc::new(AtomicUsize::new(0)),
+            listen_port: Self::LISTEN_PORT,
+            timeout: Duration::from_secs(3),
+            host_metadata: None,
+            interface: None,
+            refresh_active_checks: Duration::from_secs(120),
+            buffer_send: Duration::from_secs(5),
+            buffer_size: 100,
+            user_params: Arc::new(UserParamOptions {
+                timeout: Duration::from_secs(3),
+                unsafe_params: false,
+            }),
+            user_parameters: vec![],
+            allowed_peers: None,
+            session: session_token(),
+            registry: ItemRegistry::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    ///
+    /// 主动模式连接的集群节点，即 ServerActive 中以 `;` 分隔的地址。
+    /// 连接失败时依次尝试下一个节点，并记住响应的节点
+    ///
+    pub fn with_cluster(mut self, nodes: &[(String, u16)]) -> Self {
+        self.cluster = nodes.to_vec();
+        self.active_node.store(0, Ordering::Relaxed);
+        self
+    }
+
+    ///
+    /// 主动模式当前连接的服务端地址
+    ///
+    pub fn active_server(&self) -> (&str, u16) {
+        match self.cluster.get(self.active_node.load(Ordering::Relaxed)) {
+            Some((server, port)) => (server, *port),
+            None => (&self.proto.server, self.proto.port),
+        }
+    }
+
+    ///
+    /// 被动模式监听端口 (ListenPort)
+    ///
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = port;
+        self
+    }
+
+    ///
+    /// 被动模式只接受这些地址的连接 (Server)，未设置时接受所有连接
+    ///
+    pub fn with_allowed_peers(mut self, peers: AllowedPeers) -> Self {
+        self.allowed_peers = Some(peers);
+        self
+    }
+
+    ///
+    /// 读写超时和 UserParameter 的执行超时 (Timeout)
+    ///
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.proto = self.proto.with_timeout(timeout);
+        let options = UserParamOptions {
+            timeout,
+            ..*self.user_params
+        };
+        self.with_user_param_options(options)
+    }
+
+    ///
+    /// 主动模式自动注册使用的主机元数据 (HostMetadata)
+    ///
+    pub fn with_host_metadata(mut self, host_metadata: &str) -> Self {
+        self.host_metadata = Some(String::from(host_metadata));
+        self
+    }
+
+    ///
+    /// 主动模式自动注册使用的主机接口地址 (HostInterface)
+    ///
+    pub fn with_interface(mut self, interface: &str) -> Self {
+        self.interface = Some(String::from(interface));
+        self
+    }
+
+    ///
+    /// 刷新主动检查项的间隔 (RefreshActiveChecks)
+    ///
+    pub fn with_refresh_active_checks(mut self, refresh: Duration) -> Self {
+        self.refresh_active_checks = refresh;
+        self
+    }
+
+    ///
+    /// 缓存的值最多保留多长时间后发送 (BufferSend)
+    ///
+    pub fn with_buffer_send(mut self, buffer_send: Duration) -> Self {
+        self.buffer_send = buffer_send;
+        self
+    }
+
+    ///
+    /// 缓存的值的最大数量，达到后立即发送 (BufferSize)
+    ///
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    ///
+    /// 允许 UserParameter 的参数中包含特殊字符 (UnsafeUserParameters)，
+    /// 对已添加的 UserParameter 同样生效
+    ///
+    pub fn with_unsafe_user_parameters(self, allow: bool) -> Self {
+        let options = UserParamOptions {
+            unsafe_params: allow,
+            ..*self.user_params
+        };
+        self.with_user_param_options(options)
+    }
+
+    ///
+    /// 替换为新的选项并重新注册已添加的 UserParameter，clone 出的其他 agent 不受影响
+    ///
+    fn with_user_param_options(mut self, options: UserParamOptions) -> Self {
+        self.user_params = Arc::new(options);
+        for up in &self.user_parameters {
+            let handler = SharedUserParameter::new(up.clone(), self.user_params.clone());
+            self.registry.register_handler(up.name(), handler);
+        }
+        self
+    }
+
+    ///
+    /// 添加 UserParameter，definition 为 `<key>,<command>`，
+    /// 执行时使用 agent 当前的 Timeout 和 UnsafeUserParameters
+    ///
+    pub fn add_user_parameter(&mut self, definition: &str) -> Result<()> {
+        let up = UserParameter::parse(definition)?;
+        if self.registry.contains(up.name()) {
+            return Err(format_err!(
+                "user parameter \"{}\": key \"{}\" already exists",
+                definition,
+                up.name()
+            ));
+        }
+        let handler = SharedUserParameter::new(up.clone(), self.user_params.clone());
+        self.registry.register_handler(up.name(), handler);
+        self.user_parameters.push(up);
+        Ok(())
+    }
+
+    ///
+    /// 主机名 (Hostname)
+    ///
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    ///
+    /// 主动模式的会话标识，每个 agent 实例不同
+    ///
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    ///
+    /// 被动模式连接的 TLS 配置 (TLSAccept)
+    ///
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    ///
+    /// 注册监控项处理函数，name 为 key 的名称（不含参数）
+    ///
+    pub fn register<F, T>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&ItemKey) -> Result<T> + Send + Sync + 'static,
+        T: Into<ItemValue>,
+    {
+        self.user_parameters.retain(|up| up.name() != name);
+        self.registry.register(name, f);
+    }
+
+    ///
+    /// 注册实现了 `ItemHandler` 的处理
+    ///
+    pub fn register_handler<H>(&mut self, name: &str, handler: H)
+    where
+        H: ItemHandler + 'static,
+    {
+        self.user_parameters.retain(|up| up.name() != name);
+        self.registry.register_handler(name, handler);
+    }
+
+    ///
+    /// 注册内置的 Linux 系统监控项，`agent.hostname` 返回 name
+    ///
+    #[cfg(feature = "linux")]
+    pub fn with_system_items(mut self) -> Self {
+        register_system_items(&mut self.registry, &self.name);
+        self
+    }
+
+    ///
+    /// 替换监控项处理，可与 proxy 等共用同一个 registry
+    ///
+    pub fn with_registry(mut self, registry: ItemRegistry) -> Self {
+        self.user_parameters.clear();
+        self.registry = registry;
+        self
+    }
+
+    pub fn registry(&self) -> &ItemRegistry {
+        &self.registry
+    }
+
+    ///
+    /// 获取监控项的值，不支持时返回 `ZBX_NOTSUPPORTED\0<原因>`
+    ///
+    pub fn get_value(&self, key: &str) -> String {
+        match self.process(key) {
+            Ok(value) => value.to_string(),
+            Err(e) => format!("{}\0{}", Self::ZBX_NOTSUPPORTED, e),
+        }
+    }
+
+    fn process(&self, key: &str) -> Result<ItemValue> {
+        self.registry.get(key)
+    }
+
+    ///
+    /// 从服务端获取主动检查项
+    ///
+    pub fn get_active_checks(&self) -> Result<Vec<ActiveCheck>> {
+        let req = ActiveChecksRequest {
+            request: Self::ACTIVE_CHECKS,
+            host: &self.name,
+            host_metadata: self.host_metadata.as_deref(),
+            interface: self.interface.as_deref(),
+        };
+        let read_data = self.send(&serde_json::to_string(&req)?)?;
+        let resp: ActiveChecksResponse = serde_json::from_slice(&read_data)?;
+        if resp.response != "success" {
+            return Err(format_err!(
+                "active checks request failed: {}",
+                resp.info.unwrap_or_default()
+            ));
+        }
+        Ok(resp.data)
+    }
+
+    ///
+    /// 发送主动模式采集的值
+    ///
+    pub fn send_values(&self, values: &[AgentValue]) -> Result<Response> {
+        let req = AgentDataRequest::new(&self.session, values);
+        let read_data = self.send(&serde_json::to_string(&req)?)?;
+        let resp: Response = serde_json::from_slice(&read_data)?;
+        trace!("{:?}", resp);
+        if !resp.success() {
+            return Err(format_err!(
+                "agent data request failed: {}",
+                resp.info().unwrap_or_default()
+            ));
+        }
+        Ok(resp)
+    }
+
+    ///
+    /// 从上次响应的节点开始依次尝试集群中的节点
+    ///
+    fn send(&self, data: &str) -> Result<Vec<u8>> {
+        if self.cluster.is_empty() {
+            return self.proto.send(data);
+        }
+
+        let start = self.active_node.load(Ordering::Relaxed);
+        let mut last_err = None;
+        for i in 0..self.cluster.len() {
+            let n = (start + i) % self.cluster.len();
+            let (server, port) = &self.cluster[n];
+            match self.proto.with_address(server, *port).send(data) {
+                Ok(read_data) => {
+                    if n != start {
+                        warn!("switched to active server {}:{}", server, port);
+                        self.active_node.store(n, Ordering::Relaxed);
+                    }
+                    return Ok(read_data);
+                }
+                Err(e) => {
+                    debug!("cannot send to active server {}:{}: {}", server, port, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| format_err!("no active server")))
+    }
+
+    ///
+    /// 采集一个主动检查项，返回的值的 id 为 0，由调用者按发送顺序设置。
+    /// log[] 和 logrt[] 的每个匹配行为一个值，读取位置保存在 logs 中，
+    /// 新的监控项使用服务端发送的 lastlogsize 和 mtime。
+    /// 最后的位置没有对应的值（如跳过了不匹配的行）时，再发送一个不带值的位置更新
+    ///
+    fn collect(
+        &self,
+        check: &ActiveCheck,
+        logs: &mut HashMap<String, LogState>,
+    ) -> Vec<AgentValue> {
+        let not_supported = |e: failure::Error| {
+            vec![AgentValue::not_supported(
+                &self.name,
+                &check.key,
+                &e.to_string(),
+                0,
+            )]
+        };
+
+        let key = match ItemKey::parse(&check.key) {
+            Ok(key) if LogItem::is_log(&key) => key,
+            _ => {
+                return match self.process(&check.key) {
+                    Ok(ItemValue::Log(log)) => {
+                        vec![AgentValue::log(&self.name, &check.key, &log, 0)]
+                    }
+                    Ok(value) => vec![AgentValue::new(
+                        &self.name,
+                        &check.key,
+                        &value.to_string(),
+                        0,
+                    )],
+                    Err(e) => not_supported(e),
+                }
+            }
+        };
+
+        let item = match LogItem::parse(&key) {
+            Ok(item) => item,
+            Err(e) => return not_supported(e),
+        };
+        let first = !logs.contains_key(&check.key) && check.lastlogsize == 0 && check.mtime == 0;
+        let state = logs.entry(check.key.clone()).or_insert(LogState {
+            lastlogsize: check.lastlogsize,
+            mtime: check.mtime,
+        });
+        let delay = check.delay.delay_at(Local::now().timestamp(), &Local);
+        let before = item.position(state);
+        let lines = match item.read(state, first, delay) {
+            Ok(lines) => lines,
+            Err(e) => return not_supported(e),
+        };
+        let sent = lines
+            .last()
+            .map_or(before, |log| (log.lastlogsize, log.mtime));
+        let mut values: Vec<AgentValue> = lines
+            .iter()
+            .map(|log| AgentValue::log(&self.name, &check.key, log, 0))
+            .collect();
+        let (lastlogsize, mtime) = item.position(state);
+        if (lastlogsize, mtime) != sent {
+            values.push(AgentValue::meta(
+                &self.name,
+                &check.key,
+                lastlogsize,
+                mtime,
+                0,
+            ));
+        }
+        values
+    }
+
+    ///
+    /// 运行主动模式，不会返回。获取检查项或发送失败时记录日志后重试，
+    /// 发送失败的值保留在缓存中，超过 BufferSize 时丢弃最早的值
+    ///
+    pub fn run_active(&self) -> Result<()> {
+        let mut schedule = Schedule::default();
+        let mut buffer: Vec<AgentValue> = vec![];
+        let mut logs: HashMap<String, LogState> = HashMap::new();
+        let mut last_id = 0;
+        let mut next_refresh = Instant::now();
+        let mut next_send = Instant::now() + self.buffer_send;
+
+        loop {
+            let now = Instant::now();
+            let clock = Local::now().timestamp();
+            if now >= next_refresh {
+                match self.get_active_checks() {
+                    Ok(checks) => {
+                        logs.retain(|key, _| checks.iter().any(|c| &c.key == key));
+                        schedule.update(checks, clock);
+                    }
+                    Err(e) => warn!("failed to get active checks: {}", e),
+                }
+                next_refresh = now + self.refresh_active_checks;
+            }
+
+            for check in schedule.due(clock) {
+                for mut value in self.collect(&check, &mut logs) {
+                    last_id += 1;
+                    value.id = last_id;
+                    buffer.push(value);
+                }
+            }
+
+            if !buffer.is_empty() && (now >= next_send || buffer.len() >= self.buffer_size) {
+                match self.send_values(&buffer) {
+                    Ok(_) => buffer.clear(),
+                    Err(e) => {
+                        warn!("failed to send active check values: {}", e);
+                        if buffer.len() > self.buffer_size {
+                            buffer.drain(..buffer.len() - self.buffer_size);
+                        }
+                    }
+                }
+                next_send = now + self.buffer_send;
+            }
+
+            let mut wake = next_refresh.min(next_send);
+            if let Some(next) = schedule.next_due() {
+                wake = wake.min(now + Duration::from_secs((next - clock).max(0) as u64));
+            }
+            let wait = wake.saturating_duration_since(Instant::now());
+            thread::sleep(wait.min(Duration::from_secs(1)));
+        }
+    }
+
+    ///
+    /// 在 ListenPort 上运行被动模式，不会返回
+    ///
+    pub fn run_passive(&self) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.listen_port))?;
+        self.serve(listener)
+    }
+
+    ///
+    /// 在已绑定的端口上处理被动检查请求，每个连接使用一个线程
+    ///
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        #[cfg(feature = "tls")]
+        let acceptor = match &self.tls {
+            Some(tls) => Some(Arc::new(tls.acceptor()?)),
+            None => None,
+        };
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            if let Some(peers) = &self.allowed_peers {
+                if !peers.accepts(&stream) {
+                    continue;
+                }
+            }
+            let timeouts = stream
+                .set_read_timeout(Some(self.timeout))
+                .and_then(|_| stream.set_write_timeout(Some(self.timeout)));
+            if let Err(e) = timeouts {
+                warn!("failed to set connection timeout: {}", e);
+                continue;
+            }
+
+            let agent = self.clone();
+            #[cfg(feature = "tls")]
+            let acceptor = acceptor.clone();
+            thread::spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+
+                #[cfg(feature = "tls")]
+                let result = match acceptor {
+                    Some(acceptor) => acceptor.accept(stream).and_then(|s| agent.handle(s)),
+                    None => agent.handle(stream),
+                };
+                #[cfg(not(feature = "tls"))]
+                let result = agent.handle(stream);
+
+                if let Err(e) = result {
+                    warn!("failed to process passive check from {}: {}", peer, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    ///
+    /// 处理一个被动检查连接：读取 key，返回监控项的值
+    ///
+    pub fn handle<S: Read + Write>(&self, s: S) -> Result<()> {
+        let mut stream = ZbxStream::new(s);
+        let req = stream.read_frame()?;
+        let key = String::from_utf8_lossy(&req);
+        let key = key.trim_end_matches(['\r', '\n']);
+        trace!("passive check: {}", key);
+
+        let value = self.get_value(key);
+        stream.write_frame(value.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Delay;
+
+    #[test]
+    fn test_agent_passive() {
+        let mut agent = ZabbixAgent::new("agent", "127.0.0.1", 10051);
+        agent.register("app.ping", |_| Ok("1".to_string()));
+        agent.add_user_parameter("app.user[*],echo $1 $2").unwrap();
+        assert!(agent.add_user_parameter("app.ping,echo 1").is_err());
+        agent.register("app.echo", |key| Ok(format!("{:?}", key.param(1))));
+        agent.register("app.fail", |_| -> Result<String> {
+            Err(format_err!("Cannot obtain value."))
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || agent.serve(listener));
+
+        let zbx = ZabbixProtocol::new("127.0.0.1", port);
+        let get = |key: &str| String::from_utf8(zbx.send(key).unwrap()).unwrap();
+        assert_eq!("1", get("app.ping"));
+        assert_eq!(r#"Some("b,c")"#, get("app.echo[a,\"b,c\"]\n"));
+        assert_eq!("ZBX_NOTSUPPORTED\0Cannot obtain value.", get("app.fail"));
+        assert_eq!("ZBX_NOTSUPPORTED\0Unsupported item key.", get("app.none"));
+        assert_eq!("x y", get("app.user[x,y]"));
+        assert!(get("app.user[\"$(id)\"]").starts_with("ZBX_NOTSUPPORTED\0Special characters"));
+        assert_eq!(
+            "ZBX_NOTSUPPORTED\0Invalid item key format.",
+            get("app.echo[a")
+        );
+    }
+
+    #[test]
+    fn test_agent_user_parameter_options() {
+        let mut agent = ZabbixAgent::new("agent", "127.0.0.1", 10051);
+        agent.add_user_parameter("app.echo[*],echo $1").unwrap();
+        agent.add_user_parameter("app.slow,sleep 5").unwrap();
+
+        // 添加后修改的选项在执行时生效
+        let agent = agent
+            .with_unsafe_user_parameters(true)
+            .with_timeout(Duration::from_millis(200));
+        assert_eq!("a#b", agent.get_value("app.echo[\"a#b\"]"));
+        let start = Instant::now();
+        assert_eq!(
+            "ZBX_NOTSUPPORTED\0Timeout while executing a shell script.",
+            agent.get_value("app.slow")
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(Some(Duration::from_millis(200)), agent.proto.read_timeout);
+
+        let safe = agent.clone().with_unsafe_user_parameters(false);
+        assert!(safe
+            .get_value("app.echo[\"a#b\"]")
+            .starts_with("ZBX_NOTSUPPORTED\0Special characters"));
+        // 修改 clone 出的 agent 不影响原来的
+        assert_eq!("a#b", agent.get_value("app.echo[\"a#b\"]"));
+
+        // 覆盖了 UserParameter 的处理函数不会被重新注册
+        let mut agent = safe;
+        agent.register("app.slow", |_| Ok("fast"));
+        let agent = agent.with_timeout(Duration::from_secs(1));
+        assert_eq!("fast", agent.get_value("app.slow"));
+    }
+
+    #[test]
+    fn test_agent_cluster_failover() {
+        // 第一个节点接受连接后立即断开
+        let down = TcpListener::bind("127.0.0.1:0").unwrap();
+        let down_port = down.local_addr().unwrap().port();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let count = attempts.clone();
+        thread::spawn(move || {
+            for s in down.incoming() {
+                count.fetch_add(1, Ordering::SeqCst);
+                drop(s);
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for _ in 0..2 {
+                let (s, _) = listener.accept().unwrap();
+                let mut stream = ZbxStream::new(s);
+                stream.read_frame().unwrap();
+                stream
+                    .write_frame(br#"{"response":"success","data":[]}"#)
+                    .unwrap();
+            }
+        });
+
+        let cluster = vec![
+            ("127.0.0.1".to_string(), down_port),
+            ("127.0.0.1".to_string(), port),
+        ];
+        let agent = ZabbixAgent::new("host", "127.0.0.1", down_port)
+            .with_timeout(Duration::from_secs(2))
+            .with_cluster(&cluster);
+        assert_eq!(("127.0.0.1", down_port), agent.active_server());
+        assert!(agent.get_active_checks().unwrap().is_empty());
+        assert_eq!(("127.0.0.1", port), agent.active_server());
+
+        // 之后直接连接响应的节点
+        assert!(agent.get_active_checks().unwrap().is_empty());
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_agent_active() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let replies = [
+                r#"{"response":"success","data":[{"key":"app.ping","delay":30,"lastlogsize":0,"mtime":0},{"key":"app.none","delay":"1m"}]}"#,
+                r#"{"response":"success","info":"processed: 2; failed: 0; total: 2; seconds spent: 0.000100"}"#,
+            ];
+            let mut requests = vec![];
+            for reply in replies.iter() {
+                let (s, _) = listener.accept().unwrap();
+                let mut stream = ZbxStream::new(s);
+                let req = stream.read_frame().unwrap();
+                requests.push(serde_json::from_slice::<serde_json::Value>(&req).unwrap());
+                stream.write_frame(reply.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let mut agent = ZabbixAgent::new("host", "127.0.0.1", port).with_host_metadata("Linux");
+        agent.register("app.ping", |_| Ok("1".to_string()));
+
+        let checks = agent.get_active_checks().unwrap();
+        assert_eq!(2, checks.len());
+        assert_eq!(60, checks[1].delay.simple());
+
+        let mut logs = HashMap::new();
+        let values: Vec<AgentValue> = checks
+            .iter()
+            .flat_map(|c| agent.collect(c, &mut logs))
+            .enumerate()
+            .map(|(i, mut v)| {
+                v.id = i as u64 + 1;
+                v
+            })
+            .collect();
+        assert_eq!(None, values[0].state);
+        assert_eq!(Some(1), values[1].state);
+        assert_eq!(Some("Unsupported item key."), values[1].value.as_deref());
+        assert_eq!(2, agent.send_values(&values).unwrap().processed_cnt());
+
+        let requests = server.join().unwrap();
+        assert_eq!("active checks", requests[0]["request"]);
+        assert_eq!("host", requests[0]["host"]);
+        assert_eq!("Linux", requests[0]["host_metadata"]);
+        assert!(requests[0].get("interface").is_none());
+
+        assert_eq!("agent data", requests[1]["request"]);
+        assert_eq!(agent.session(), requests[1]["session"]);
+        assert_eq!("app.ping", requests[1]["data"][0]["key"]);
+        assert_eq!("1", requests[1]["data"][0]["value"]);
+        assert_eq!(2, requests[1]["data"][1]["id"]);
+        assert_eq!(1, requests[1]["data"][1]["state"]);
+    }
+
+    #[test]
+    fn test_agent_log_meta() {
+        let path = std::env::temp_dir().join(format!("zabbix-meta-{}.log", std::process::id()));
+        std::fs::write(&path, "info\nerror 1\ninfo\n").unwrap();
+        let check = ActiveCheck {
+            key: format!("log[{},error]", path.display()),
+            itemid: 1,
+            delay: Delay::from(1),
+            lastlogsize: 0,
+            mtime: 0,
+        };
+        let agent = ZabbixAgent::new("host", "127.0.0.1", 10051);
+        let mut logs = HashMap::new();
+
+        // 最后一行不匹配，值之后再发送最后的位置
+        let values = agent.collect(&check, &mut logs);
+        assert_eq!(2, values.len());
+        assert_eq!(Some("error 1"), values[0].value.as_deref());
+        assert_eq!(Some(13), values[0].lastlogsize);
+        assert_eq!(None, values[1].value);
+        assert_eq!(Some(18), values[1].lastlogsize);
+        assert_eq!(Some(0), values[1].mtime);
+        let json = serde_json::to_value(&values[1]).unwrap();
+        assert!(json.get("value").is_none());
+        assert_eq!(18, json["lastlogsize"]);
+
+        // 没有新内容时不发送
+        assert!(agent.collect(&check, &mut logs).is_empty());
+
+        // 只有不匹配的行
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(b"debug\n").unwrap();
+        let values = agent.collect(&check, &mut logs);
+        assert_eq!(1, values.len());
+        assert_eq!(None, values[0].value);
+        assert_eq!(Some(24), values[0].lastlogsize);
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,422 @@
+//! 基于 tokio 的异步 zabbix 客户端
+//!
+//! 与同步版本的 `ZabbixProtocol`、`ZabbixProxy`、`ZabbixSender` 功能一致。
+//! TLS 加密需要启用 `async-tls` 特性。
+
+use futures_util::{SinkExt, StreamExt};
+#[cfg(feature = "tls")]
+use openssl::ssl::ConnectConfiguration;
+use serde_json::Value;
+use std::future::Future;
+#[cfg(feature = "async-tls")]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(feature = "async-tls")]
+use tokio_openssl::SslStream;
+use tokio_util::codec::Framed;
+
+use super::active::session_token;
+use super::codec::ZbxCodec;
+use super::configcache::{ConfigCache, ConfigEvent, ProxyConfigRequest};
+use super::error::{from_io, ProtocolError};
+use super::protocol::ZabbixProtocol;
+use super::proxy::{parse_response, ProxyResponse, ZabbixProxy};
+use super::proxydata::{ProxyData, ProxyDataResponse};
+use super::request::{ZabbixHost, ZabbixMetric, ZabbixRequest};
+use super::response::Response;
+use super::sender::{empty_response, sender_request, ZabbixSender};
+#[cfg(feature = "tls")]
+use super::tls::TlsConfig;
+use super::Result;
+
+/// 异步版本的 `ZabbixProtocol`，连接参数与同步版本相同
+#[derive(Debug, Clone)]
+pub struct AsyncZabbixProtocol {
+    proto: ZabbixProtocol,
+}
+
+impl From<ZabbixProtocol> for AsyncZabbixProtocol {
+    fn from(proto: ZabbixProtocol) -> Self {
+        Self { proto }
+    }
+}
+
+impl AsyncZabbixProtocol {
+    pub fn new(server: &str, port: u16) -> Self {
+        Self::from(ZabbixProtocol::new(server, port))
+    }
+
+    pub fn codec(&self) -> ZbxCodec {
+        let codec = ZbxCodec::new().with_max_packet_size(self.proto.max_packet_size);
+        match self.proto.compress_threshold {
+            Some(threshold) => codec.with_compression(threshold),
+            None => codec,
+        }
+    }
+
+    pub async fn send(&self, data: &str) -> Result<Vec<u8>> {
+        timeout(self.proto.timeout, self.exchange(data)).await
+    }
+
+    async fn exchange(&self, data: &str) -> Result<Vec<u8>> {
+        let addr = format!("{0}:{1}", self.proto.server, self.proto.port);
+        let s = timeout(self.proto.connect_timeout, async {
+            TcpStream::connect(addr).await.map_err(from_io)
+        })
+        .await?;
+        trace!(
+            "send {} bytes to {}:{}",
+            data.len(),
+            self.proto.server,
+            self.proto.port
+        );
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.proto.tls {
+                if let Some(config) = tls.connector()? {
+                    return self.exchange_tls(tls, config, s, data).await;
+                }
+            }
+        }
+        self.request(s, data).await
+    }
+
+    #[cfg(feature = "async-tls")]
+    async fn exchange_tls(
+        &self,
+        tls: &TlsConfig,
+        config: ConnectConfiguration,
+        s: TcpStream,
+        data: &str,
+    ) -> Result<Vec<u8>> {
+        let mut stream = SslStream::new(config.into_ssl("")?, s)?;
+        timeout(self.proto.read_timeout, async {
+            Pin::new(&mut stream)
+                .connect()
+                .await
+                .map_err(|e| format_err!("TLS handshake failed: {}", e))
+        })
+        .await?;
+        tls.verify_connected(stream.ssl())?;
+        self.request(stream, data).await
+    }
+
+    #[cfg(all(feature = "tls", not(feature = "async-tls")))]
+    async fn exchange_tls(
+        &self,
+        _tls: &TlsConfig,
+        _config: ConnectConfiguration,
+        _s: TcpStream,
+        _data: &str,
+    ) -> Result<Vec<u8>> {
+        Err(format_err!(
+            "TLS in the async client requires the \"async-tls\" feature"
+        ))
+    }
+
+    async fn request<S>(&self, s: S, data: &str) -> Result<Vec<u8>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut framed = Framed::new(s, self.codec());
+        timeout(self.proto.write_timeout, framed.send(data.as_bytes())).await?;
+        let read_data = timeout(self.proto.read_timeout, async {
+            match framed.next().await {
+                Some(frame) => frame,
+                None => Err(ProtocolError::UnexpectedEof.into()),
+            }
+        })
+        .await?;
+
+        if read_data.is_empty() {
+            return Err(format_err!("packet data length = 0"));
+        }
+        Ok(read_data)
+    }
+}
+
+async fn timeout<T, F>(duration: Option<Duration>, f: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    match duration {
+        Some(d) => match tokio::time::timeout(d, f).await {
+            Ok(r) => r,
+            Err(_) => Err(ProtocolError::Timeout.into()),
+        },
+        None => f.await,
+    }
+}
+
+/// 异步版本的 `ZabbixProxy`
+#[derive(Debug, Clone)]
+pub struct AsyncZabbixProxy {
+    name: String,
+    proto: AsyncZabbixProtocol,
+    session: String,
+    config: Arc<Mutex<ConfigCache>>,
+}
+
+impl AsyncZabbixProxy {
+    pub fn new(name: &str, server: &str, port: u16) -> Self {
+        Self::with_protocol(name, AsyncZabbixProtocol::new(server, port))
+    }
+
+    pub fn with_protocol(name: &str, proto: AsyncZabbixProtocol) -> Self {
+        let name = String::from(name);
+        Self {
+            name,
+            proto,
+            session: session_token(),
+            config: Arc::new(Mutex::new(ConfigCache::new())),
+        }
+    }
+
+    ///
+    /// proxy data 的会话标识，每个 proxy 实例不同
+    ///
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    ///
+    /// 当前配置缓存的副本
+    ///
+    pub fn config(&self) -> ConfigCache {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ConfigCache> {
+        self.config.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn send_request(&self, req: &ZabbixRequest, is_config: bool) -> Result<ProxyResponse> {
+        let read_data = self.proto.send(&req.str()).await?;
+        parse_response(&read_data, is_config)
+    }
+
+    ///
+    /// 从ZABBNIX服务端获取代理配置信息
+    ///
+    pub async fn get_config(&self) -> Option<Value> {
+        let req = ZabbixRequest::new(ZabbixProxy::PROXY_CONFIG, &self.name, Value::Null);
+        if let Ok(ProxyResponse::CONFIG(c)) = self.send_request(&req, true).await {
+            return Some(c);
+        }
+        None
+    }
+
+    ///
+    /// 按配置版本增量同步配置 (Zabbix 6.4 以后)，返回主机和监控项的变化。
+    /// 旧版本的服务端返回完整配置，同样可以使用
+    ///
+    pub async fn sync_config(&self) -> Result<Vec<ConfigEvent>> {
+        let revision = self.lock().revision();
+        let req = ProxyConfigRequest {
+            request: ZabbixProxy::PROXY_CONFIG,
+            host: &self.name,
+            version: ProxyData::VERSION,
+            session: &self.session,
+            config_revision: revision,
+        };
+        let read_data = self.proto.send(&serde_json::to_string(&req)?).await?;
+        let config: Value = serde_json::from_slice(&read_data)?;
+        self.lock().apply(&config)
+    }
+
+    ///
+    /// 自动注册主机
+    ///
+    pub async fn auto_register(&self, hosts: Vec<ZabbixHost>) -> Result<bool> {
+        let hosts = serde_json::to_value(hosts)?;
+        let req = ZabbixRequest::new(ZabbixProxy::AUTO_REGISTRATION, &self.name, hosts);
+        if let Ok(ProxyResponse::RESPONSE(c)) = self.send_request(&req, false).await {
+            return Ok(c.success());
+        }
+        Ok(false)
+    }
+
+    ///
+    /// 向服务端发送心跳信息
+    ///
+    pub async fn heart_beat(&self) -> Result<bool> {
+        let req = ZabbixRequest::new(ZabbixProxy::PROXY_HEARTBEAT, &self.name, Value::Null);
+        if let Ok(ProxyResponse::RESPONSE(c)) = self.send_request(&req, false).await {
+            return Ok(c.success());
+        }
+        Ok(false)
+    }
+
+    ///
+    /// 向服务端发送 proxy data (Zabbix 4.0 以后)，应答中包含需要 proxy 执行的任务
+    ///
+    pub async fn send_proxy_data(&self, data: &ProxyData) -> Result<ProxyDataResponse> {
+        let read_data = self.proto.send(&serde_json::to_string(data)?).await?;
+        Ok(serde_json::from_slice(&read_data)?)
+    }
+
+    ///
+    /// 向服务端发送历史数据，使用 Zabbix 4.0 以前的 history data 请求，
+    /// 新版本的服务端使用 `send_proxy_data`
+    ///
+    pub async fn send_data(&self, data: &[ZabbixMetric]) -> Result<bool> {
+        let data = serde_json::to_value(data)?;
+        let req = ZabbixRequest::new(ZabbixProxy::HISTORY_DATA, &self.name, data);
+        if let Ok(ProxyResponse::RESPONSE(c)) = self.send_request(&req, false).await {
+            return Ok(c.success() && c.ok());
+        }
+        Ok(false)
+    }
+}
+
+/// 异步版本的 `ZabbixSender`
+#[derive(Debug, Clone)]
+pub struct AsyncZabbixSender {
+    name: String,
+    proto: AsyncZabbixProtocol,
+}
+
+impl AsyncZabbixSender {
+    pub fn new(name: &str, server: &str, port: u16) -> Self {
+        Self::with_protocol(name, AsyncZabbixProtocol::new(server, port))
+    }
+
+    pub fn with_protocol(name: &str, proto: AsyncZabbixProtocol) -> Self {
+        let name = String::from(name);
+        Self { name, proto }
+    }
+
+    ///
+    /// 发送监控数据，按 250 个值分批发送，返回合并后的结果
+    ///
+    pub async fn send(&self, data: &[ZabbixMetric]) -> Result<Response> {
+        let mut result = empty_response();
+        for chunk in data.chunks(ZabbixSender::MAX_VALUES) {
+            let req = sender_request(&self.name, chunk)?;
+            let read_data = self.proto.send(&req.str()).await?;
+            let resp: Response = serde_json::from_slice(&read_data)?;
+            result = result.merge(&resp);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxydata::HistoryValue;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_async_proxy_heart_beat() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let (s, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(s, ZbxCodec::new());
+            let req = framed.next().await.unwrap().unwrap();
+            let req: Value = serde_json::from_slice(&req).unwrap();
+            framed.send(r#"{"response":"success"}"#).await.unwrap();
+            req
+        });
+
+        let proxy = AsyncZabbixProxy::new("proxy", "127.0.0.1", port);
+        assert!(proxy.heart_beat().await.unwrap());
+
+        let req = server.await.unwrap();
+        assert_eq!("proxy heartbeat", req["request"]);
+        assert_eq!("proxy", req["host"]);
+    }
+
+    #[tokio::test]
+    async fn test_async_proxy_sync_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = tokio::spawn(async move {
+            let items = json!({"fields": ["itemid", "hostid", "key_", "delay", "status"],
+                               "data": [[10, 1, "a", "30s", 0]]});
+            let responses = [
+                json!({"config_revision": 7, "full_sync": 1, "data": {"items": items}}),
+                json!({"config_revision": 8, "data": {"items": {"del": [10]}}}),
+                json!({"response": "success", "upload": "enabled",
+                       "tasks": [{"type": 2, "command": "uptime"}]}),
+            ];
+            let mut requests = vec![];
+            for response in responses.iter() {
+                let (s, _) = listener.accept().await.unwrap();
+                let mut framed = Framed::new(s, ZbxCodec::new());
+                let req = framed.next().await.unwrap().unwrap();
+                requests.push(serde_json::from_slice::<Value>(&req).unwrap());
+                framed.send(response.to_string().as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let proxy = AsyncZabbixProxy::new("proxy", "127.0.0.1", port);
+        assert_eq!(1, proxy.sync_config().await.unwrap().len());
+        assert_eq!(
+            vec![ConfigEvent::ItemRemoved(10)],
+            proxy.sync_config().await.unwrap()
+        );
+        assert_eq!(8, proxy.config().revision());
+
+        let data =
+            ProxyData::new("proxy", proxy.session()).with_history(vec![HistoryValue::new(10, "1")]);
+        let response = proxy.send_proxy_data(&data).await.unwrap();
+        assert!(response.upload_enabled());
+        assert_eq!(1, response.tasks.len());
+
+        let requests = server.await.unwrap();
+        assert_eq!(json!(0), requests[0]["config_revision"]);
+        assert_eq!(json!(7), requests[1]["config_revision"]);
+        assert_eq!(proxy.session(), requests[1]["session"]);
+        assert_eq!("proxy data", requests[2]["request"]);
+        assert_eq!(10, requests[2]["history data"][0]["itemid"]);
+    }
+
+    #[cfg(feature = "async-tls")]
+    #[tokio::test]
+    async fn test_async_protocol_tls() {
+        use crate::tls::tests::{echo_server, PSK};
+        use crate::tls::{TlsMode, TlsPsk};
+
+        let psk = TlsPsk::new("PSK 001", PSK).unwrap();
+        let server = TlsConfig::from_psk(psk.clone()).with_accept(&[TlsMode::Psk]);
+        let (port, handle) = echo_server(server, 2);
+
+        let proto = AsyncZabbixProtocol::from(
+            ZabbixProtocol::new("127.0.0.1", port).with_tls(TlsConfig::from_psk(psk)),
+        );
+        assert_eq!(b"psk".to_vec(), proto.send("psk").await.unwrap());
+
+        let other = TlsPsk::new("PSK 002", PSK).unwrap();
+        let proto = AsyncZabbixProtocol::from(
+            ZabbixProtocol::new("127.0.0.1", port).with_tls(TlsConfig::from_psk(other)),
+        );
+        assert!(proto.send("other").await.is_err());
+
+        assert_eq!(vec![TlsMode::Psk], handle.join().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_async_protocol_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let proto = AsyncZabbixProtocol::from(
+            ZabbixProtocol::new("127.0.0.1", port).with_timeout(Duration::from_millis(100)),
+        );
+        let e = proto.send("data").await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::Timeout)
+        ));
+        drop(listener);
+    }
+}
@@ -0,0 +1,141 @@
+//! 基于 tokio-util 的 ZBXD 数据包编解码器
+
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::error::ProtocolError;
+use super::frame::{decompress, encode_packet};
+use super::header::ZbxHeader;
+
+/// ZBXD 数据包编解码器，支持压缩和大数据包格式
+#[derive(Debug, Clone)]
+pub struct ZbxCodec {
+    compress_threshold: Option<usize>,
+    max_packet_size: u64,
+}
+
+impl Default for ZbxCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ZbxCodec {
+    pub fn new() -> Self {
+        Self {
+            compress_threshold: None,
+            max_packet_size: ZbxHeader::DEFAULT_MAX_SIZE,
+        }
+    }
+
+    ///
+    /// 数据长度超过 threshold 字节时压缩发送
+    ///
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_threshold = Some(threshold);
+        self
+    }
+
+    ///
+    /// 设置接收数据包的最大长度
+    ///
+    pub fn with_max_packet_size(mut self, size: u64) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+}
+
+impl Decoder for ZbxCodec {
+    type Item = Vec<u8>;
+    type Error = failure::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < ZbxHeader::PREFIX_SIZE {
+            return Ok(None);
+        }
+
+        let flags = ZbxHeader::parse_prefix(src)?;
+        let header_size = ZbxHeader::header_size(flags);
+        if src.len() < header_size {
+            return Ok(None);
+        }
+
+        let header = ZbxHeader::parse(&src[..header_size], self.max_packet_size)?;
+        let data_length = header.data_length as usize;
+        if src.len() < header_size + data_length {
+            src.reserve(header_size + data_length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(header_size);
+        let data = src.split_to(data_length);
+        if header.is_compressed() {
+            return decompress(&data, header.reserved).map(Some);
+        }
+        Ok(Some(data.to_vec()))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(ProtocolError::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> Encoder<T> for ZbxCodec {
+    type Error = failure::Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let packet = encode_packet(item.as_ref(), self.compress_threshold)?;
+        dst.reserve(packet.len());
+        dst.put_slice(&packet);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec_round_trip() {
+        let data = "data".repeat(100);
+        let mut codec = ZbxCodec::new().with_compression(16);
+        let mut buf = BytesMut::new();
+        codec.encode(&data, &mut buf).unwrap();
+        codec.encode("data", &mut buf).unwrap();
+        assert_eq!(b"ZBXD\x03", &buf[..5]);
+
+        // 分段到达时等待完整的数据包
+        let mut src = BytesMut::new();
+        let mut frames = vec![];
+        for chunk in buf.chunks(7) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(vec![data.into_bytes(), b"data".to_vec()], frames);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_codec_large_packet() {
+        let mut src = BytesMut::from(&ZbxHeader::new(0x05, 4, 0).encode()[..]);
+        src.extend_from_slice(b"data");
+        let frame = ZbxCodec::new().decode(&mut src).unwrap();
+        assert_eq!(Some(b"data".to_vec()), frame);
+
+        let mut src = BytesMut::from(&b"ZBXD\x01\x04\0\0\0\0\0\0\0da"[..]);
+        let e = ZbxCodec::new().decode_eof(&mut src).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::UnexpectedEof)
+        ));
+
+        let mut src = BytesMut::from(&ZbxHeader::new(0x05, 1 << 40, 0).encode()[..]);
+        assert!(ZbxCodec::new().decode(&mut src).is_err());
+    }
+}
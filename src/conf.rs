@@ -0,0 +1,1001 @@
+//! 配置文件
+//!
+//! 与 `zabbix_agentd.conf`、`zabbix_proxy.conf` 的格式相同：每行一个 `参数=值`，`#` 开头的行为注释。
+//! `Include` 可以是文件、目录（包含其中所有文件）或文件名中带有 `*` 通配符的路径，
+//! 最多嵌套 10 层。未知的参数记录为带有文件名和行号的警告。
+use failure::Fail;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::agent::ZabbixAgent;
+use super::handler::ItemRegistry;
+use super::peer::AllowedPeers;
+use super::protocol::ZabbixProtocol;
+use super::proxy::{ProxyMode, ZabbixProxy};
+#[cfg(feature = "tls")]
+use super::tls::{TlsConfig, TlsMode, TlsPsk};
+use super::userparam::UserParameter;
+use super::Result;
+
+/// Include 最多嵌套的层数
+const MAX_INCLUDE_LEVEL: usize = 10;
+
+/// 配置文件错误或警告，包含文件名和行号
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfError {
+    pub file: PathBuf,
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file.display(), self.line, self.reason)
+    }
+}
+
+impl Fail for ConfError {}
+
+/// 配置文件中的一个参数
+#[derive(Debug, Clone)]
+pub(crate) struct ConfLine {
+    pub file: PathBuf,
+    pub line: usize,
+    pub key: String,
+    pub value: String,
+}
+
+impl ConfLine {
+    pub fn error(&self, reason: &str) -> ConfError {
+        ConfError {
+            file: self.file.clone(),
+            line: self.line,
+            reason: String::from(reason),
+        }
+    }
+
+    pub fn invalid(&self) -> ConfError {
+        self.error(&format!(
+            "invalid value \"{}\" for parameter \"{}\"",
+            self.value, self.key
+        ))
+    }
+
+    ///
+    /// 解析 min..=max 范围内的整数
+    ///
+    pub fn int<T>(&self, min: T, max: T) -> Result<T>
+    where
+        T: std::str::FromStr + PartialOrd,
+    {
+        match self.value.parse::<T>() {
+            Ok(v) if v >= min && v <= max => Ok(v),
+            _ => Err(self.invalid().into()),
+        }
+    }
+
+    pub fn seconds(&self, min: u64, max: u64) -> Result<Duration> {
+        self.int(min, max).map(Duration::from_secs)
+    }
+
+    pub fn flag(&self) -> Result<bool> {
+        self.int(0u8, 1).map(|v| v == 1)
+    }
+}
+
+///
+/// 读取配置文件，展开 Include，返回所有参数
+///
+pub(crate) fn read_conf(path: &Path) -> Result<Vec<ConfLine>> {
+    let mut result = vec![];
+    read_file(path, 0, &mut result)?;
+    Ok(result)
+}
+
+fn read_file(path: &Path, level: usize, result: &mut Vec<ConfLine>) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format_err!("cannot open config file \"{}\": {}", path.display(), e))?;
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |reason: String| ConfError {
+            file: path.to_path_buf(),
+            line: i + 1,
+            reason,
+        };
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(k), Some(v)) if !k.trim().is_empty() => (k.trim(), v.trim()),
+            _ => {
+                return Err(error(format!(
+                    "invalid entry \"{}\" (not following \"parameter=value\" notation)",
+                    line
+                ))
+                .into())
+            }
+        };
+
+        if key == "Include" {
+            if level >= MAX_INCLUDE_LEVEL {
+                return Err(error(String::from("too many nested includes")).into());
+            }
+            for include in expand_include(value).map_err(|e| error(e.to_string()))? {
+                read_file(&include, level + 1, result)?;
+            }
+            continue;
+        }
+
+        result.push(ConfLine {
+            file: path.to_path_buf(),
+            line: i + 1,
+            key: String::from(key),
+            value: String::from(value),
+        });
+    }
+    Ok(())
+}
+
+///
+/// 展开 Include：目录返回其中所有文件，文件名中的 `*` 匹配任意字符，结果按名称排序
+///
+fn expand_include(include: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(include);
+    let pattern = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| n.contains('*'));
+
+    let (dir, pattern) = match pattern {
+        Some(pattern) => (path.parent().unwrap_or_else(|| Path::new(".")), pattern),
+        None if path.is_dir() => (path, "*"),
+        None => return Ok(vec![path.to_path_buf()]),
+    };
+
+    let mut result = vec![];
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format_err!("cannot open directory \"{}\": {}", dir.display(), e))?;
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let matched = name.to_str().is_some_and(|n| wildcard_match(pattern, n));
+        if matched && entry.path().is_file() {
+            result.push(entry.path());
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+/// 配置文件中的 TLS 参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsOptions {
+    /// TLSConnect：unencrypted、psk 或 cert
+    pub connect: String,
+    /// TLSAccept：可以同时允许多种
+    pub accept: Vec<String>,
+    pub ca_file: Option<PathBuf>,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub psk_identity: Option<String>,
+    pub psk_file: Option<PathBuf>,
+    pub server_cert_issuer: Option<String>,
+    pub server_cert_subject: Option<String>,
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            connect: String::from("unencrypted"),
+            accept: vec![String::from("unencrypted")],
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            psk_identity: None,
+            psk_file: None,
+            server_cert_issuer: None,
+            server_cert_subject: None,
+        }
+    }
+}
+
+const TLS_MODES: [&str; 3] = ["unencrypted", "psk", "cert"];
+
+impl TlsOptions {
+    ///
+    /// 设置 TLS 参数，不是 TLS 参数时返回 false
+    ///
+    pub(crate) fn set(&mut self, line: &ConfLine) -> Result<bool> {
+        let value = || Some(line.value.clone());
+        match line.key.as_str() {
+            "TLSConnect" => {
+                if !TLS_MODES.contains(&line.value.as_str()) {
+                    return Err(line.invalid().into());
+                }
+                self.connect = line.value.clone();
+            }
+            "TLSAccept" => {
+                let modes: Vec<String> = line
+                    .value
+                    .split(',')
+                    .map(|m| m.trim().to_string())
+                    .collect();
+                if modes.iter().any(|m| !TLS_MODES.contains(&m.as_str())) {
+                    return Err(line.invalid().into());
+                }
+                self.accept = modes;
+            }
+            "TLSCAFile" => self.ca_file = value().map(PathBuf::from),
+            "TLSCertFile" => self.cert_file = value().map(PathBuf::from),
+            "TLSKeyFile" => self.key_file = value().map(PathBuf::from),
+            "TLSPSKIdentity" => self.psk_identity = value(),
+            "TLSPSKFile" => self.psk_file = value().map(PathBuf::from),
+            "TLSServerCertIssuer" => self.server_cert_issuer = value(),
+            "TLSServerCertSubject" => self.server_cert_subject = value(),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn modes(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.connect.as_str()).chain(self.accept.iter().map(|m| m.as_str()))
+    }
+
+    ///
+    /// 是否使用加密连接
+    ///
+    pub fn is_encrypted(&self) -> bool {
+        self.modes().any(|m| m != "unencrypted")
+    }
+
+    ///
+    /// 生成 `TlsConfig`，只使用非加密连接时返回 None
+    ///
+    #[cfg(feature = "tls")]
+    pub fn build(&self) -> Result<Option<TlsConfig>> {
+        if !self.is_encrypted() {
+            return Ok(None);
+        }
+        let mode = |m: &str| m.parse::<TlsMode>();
+        let accept = self
+            .accept
+            .iter()
+            .map(|m| mode(m))
+            .collect::<Result<Vec<_>>>()?;
+        let mut tls = TlsConfig::default()
+            .with_connect(mode(&self.connect)?)
+            .with_accept(&accept);
+
+        if self.modes().any(|m| m == "cert") {
+            match (&self.ca_file, &self.cert_file, &self.key_file) {
+                (Some(ca), Some(cert), Some(key)) => tls = tls.with_cert(ca, cert, key),
+                _ => {
+                    return Err(format_err!(
+                        "TLSCAFile, TLSCertFile and TLSKeyFile are required for certificate-based encryption"
+                    ))
+                }
+            }
+        }
+        if self.modes().any(|m| m == "psk") {
+            match (&self.psk_identity, &self.psk_file) {
+                (Some(identity), Some(file)) => {
+                    tls = tls.with_psk(TlsPsk::from_file(identity, file)?)
+                }
+                _ => {
+                    return Err(format_err!(
+                        "TLSPSKIdentity and TLSPSKFile are required for PSK-based encryption"
+                    ))
+                }
+            }
+        }
+        if let Some(subject) = &self.server_cert_subject {
+            tls = tls.with_server_cert_subject(subject);
+        }
+        if let Some(issuer) = &self.server_cert_issuer {
+            tls = tls.with_server_cert_issuer(issuer);
+        }
+        Ok(Some(tls))
+    }
+}
+
+/// agent 配置，对应 `zabbix_agentd.conf`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentConf {
+    /// Server：被动模式允许连接的地址
+    pub server: Option<AllowedPeers>,
+    /// ServerActive：以 `,` 分隔的集群，集群内以 `;` 分隔的节点
+    pub server_active: Vec<Vec<(String, u16)>>,
+    pub hostname: Option<String>,
+    pub hostname_item: String,
+    pub host_metadata: Option<String>,
+    pub host_metadata_item: Option<String>,
+    pub host_interface: Option<String>,
+    pub host_interface_item: Option<String>,
+    pub listen_port: u16,
+    /// StartAgents：为 0 时不运行被动模式，可以不设置 Server
+    pub start_agents: usize,
+    pub timeout: Duration,
+    pub refresh_active_checks: Duration,
+    pub buffer_send: Duration,
+    pub buffer_size: usize,
+    pub unsafe_user_parameters: bool,
+    /// UserParameter 的值，`<key>,<command>`
+    pub user_parameters: Vec<String>,
+    pub tls: TlsOptions,
+    /// 未知参数等警告
+    pub warnings: Vec<ConfError>,
+}
+
+impl Default for AgentConf {
+    fn default() -> Self {
+        Self {
+            server: None,
+            server_active: vec![],
+            hostname: None,
+            hostname_item: String::from("system.hostname"),
+            host_metadata: None,
+            host_metadata_item: None,
+            host_interface: None,
+            host_interface_item: None,
+            listen_port: ZabbixAgent::LISTEN_PORT,
+            start_agents: 3,
+            timeout: Duration::from_secs(3),
+            refresh_active_checks: Duration::from_secs(120),
+            buffer_send: Duration::from_secs(5),
+            buffer_size: 100,
+            unsafe_user_parameters: false,
+            user_parameters: vec![],
+            tls: TlsOptions::default(),
+            warnings: vec![],
+        }
+    }
+}
+
+impl AgentConf {
+    /// ServerActive 未指定端口时使用的端口
+    pub const SERVER_PORT: u16 = 10051;
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conf = Self::default();
+        for line in read_conf(path.as_ref())? {
+            conf.set(&line)?;
+        }
+        if let Err(e) = conf.check_server() {
+            return Err(ConfError {
+                file: path.as_ref().to_path_buf(),
+                line: 0,
+                reason: e.to_string(),
+            }
+            .into());
+        }
+        for warning in &conf.warnings {
+            warn!("{}", warning);
+        }
+        Ok(conf)
+    }
+
+    fn set(&mut self, line: &ConfLine) -> Result<()> {
+        let value = || Some(line.value.clone());
+        match line.key.as_str() {
+            "Server" => {
+                let peers = AllowedPeers::parse(&line.value)
+                    .map_err(|e| line.error(&format!("invalid parameter \"Server\": {}", e)))?;
+                self.server = Some(peers);
+            }
+            "ServerActive" => {
+                self.server_active =
+                    parse_server_active(&line.value, Self::SERVER_PORT).map_err(|e| {
+                        line.error(&format!("invalid parameter \"ServerActive\": {}", e))
+                    })?;
+            }
+            "Hostname" => self.hostname = value(),
+            "HostnameItem" => self.hostname_item = line.value.clone(),
+            "HostMetadata" => self.host_metadata = value(),
+            "HostMetadataItem" => self.host_metadata_item = value(),
+            "HostInterface" => self.host_interface = value(),
+            "HostInterfaceItem" => self.host_interface_item = value(),
+            "ListenPort" => self.listen_port = line.int(1024, 32767)?,
+            "StartAgents" => self.start_agents = line.int(0, 100)?,
+            "Timeout" => self.timeout = line.seconds(1, 30)?,
+            "RefreshActiveChecks" => self.refresh_active_checks = line.seconds(1, 86400)?,
+            "BufferSend" => self.buffer_send = line.seconds(1, 3600)?,
+            "BufferSize" => self.buffer_size = line.int(2, 65535)?,
+            "UnsafeUserParameters" => self.unsafe_user_parameters = line.flag()?,
+            "UserParameter" => {
+                UserParameter::parse(&line.value).map_err(|e| line.error(&e.to_string()))?;
+                self.user_parameters.push(line.value.clone());
+            }
+            _ => {
+                if !self.tls.set(line)? {
+                    self.warnings
+                        .push(line.error(&format!("unknown parameter \"{}\"", line.key)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// 被动模式只接受 Server 中的地址，StartAgents 不为 0 时必须设置
+    ///
+    fn check_server(&self) -> Result<()> {
+        if self.start_agents > 0 && self.server.is_none() {
+            return Err(format_err!(
+                "StartAgents is not 0, parameter \"Server\" must be defined"
+            ));
+        }
+        Ok(())
+    }
+
+    ///
+    /// 生成 agent，主动模式连接第一个 ServerActive 集群，连接失败时依次尝试集群中的节点，
+    /// 未设置 ServerActive 时只能运行被动模式，StartAgents 为 0 时只能运行主动模式
+    ///
+    pub fn build(&self) -> Result<ZabbixAgent> {
+        match self.server_active.first() {
+            Some(cluster) => self.build_for(cluster),
+            None => self.build_for(&[("127.0.0.1".to_string(), Self::SERVER_PORT)]),
+        }
+    }
+
+    ///
+    /// 为每个 ServerActive 集群生成一个 agent，各自运行主动模式
+    ///
+    pub fn build_all(&self) -> Result<Vec<ZabbixAgent>> {
+        self.server_active
+            .iter()
+            .filter(|cluster| !cluster.is_empty())
+            .map(|cluster| self.build_for(cluster))
+            .collect()
+    }
+
+    fn build_for(&self, cluster: &[(String, u16)]) -> Result<ZabbixAgent> {
+        self.check_server()?;
+        let (server, port) = &cluster[0];
+        #[cfg(feature = "tls")]
+        let tls = self.tls.build()?;
+        #[cfg(not(feature = "tls"))]
+        {
+            if self.tls.is_encrypted() {
+                return Err(format_err!("TLS parameters require the \"tls\" feature"));
+            }
+        }
+
+        #[allow(unused_mut)]
+        let mut proto = ZabbixProtocol::new(server, *port).with_timeout(self.timeout);
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &tls {
+                proto = proto.with_tls(tls.clone());
+            }
+        }
+
+        // HostnameItem 等可能是 UserParameter
+        let mut registry = ItemRegistry::new();
+        for definition in &self.user_parameters {
+            let up = UserParameter::parse(definition)?
+                .with_unsafe_params(self.unsafe_user_parameters)
+                .with_timeout(self.timeout);
+            let name = up.name().to_string();
+            registry.register_handler(&name, up);
+        }
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => resolve_item(&registry, &self.hostname_item)?,
+        };
+
+        let mut agent = ZabbixAgent::with_protocol(&hostname, proto)
+            .with_listen_port(self.listen_port)
+            .with_timeout(self.timeout)
+            .with_refresh_active_checks(self.refresh_active_checks)
+            .with_buffer_send(self.buffer_send)
+            .with_buffer_size(self.buffer_size)
+            .with_unsafe_user_parameters(self.unsafe_user_parameters)
+            .with_cluster(cluster);
+        match (&self.host_metadata, &self.host_metadata_item) {
+            (Some(metadata), _) => agent = agent.with_host_metadata(metadata),
+            (None, Some(item)) => agent = agent.with_host_metadata(&resolve_item(&registry, item)?),
+            _ => {}
+        }
+        match (&self.host_interface, &self.host_interface_item) {
+            (Some(interface), _) => agent = agent.with_interface(interface),
+            (None, Some(item)) => agent = agent.with_interface(&resolve_item(&registry, item)?),
+            _ => {}
+        }
+        if let Some(server) = &self.server {
+            agent = agent.with_allowed_peers(server.clone());
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = tls {
+                agent = agent.with_tls(tls);
+            }
+        }
+        #[cfg(feature = "linux")]
+        {
+            agent = agent.with_system_items();
+        }
+
+        for definition in &self.user_parameters {
+            agent.add_user_parameter(definition)?;
+        }
+        Ok(agent)
+    }
+}
+
+/// proxy 配置，对应 `zabbix_proxy.conf`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyConf {
+    pub mode: ProxyMode,
+    /// 主动模式：连接的服务端，集群内以 `;` 分隔的节点
+    pub server: Vec<(String, u16)>,
+    /// 被动模式：允许连接的服务端地址
+    pub allowed_peers: Option<AllowedPeers>,
+    pub hostname: Option<String>,
+    pub hostname_item: String,
+    pub listen_port: u16,
+    pub timeout: Duration,
+    pub config_frequency: Duration,
+    pub data_sender_frequency: Duration,
+    pub heartbeat_frequency: Duration,
+    pub offline_buffer: Duration,
+    pub tls: TlsOptions,
+    /// 未知参数等警告
+    pub warnings: Vec<ConfError>,
+}
+
+impl Default for ProxyConf {
+    fn default() -> Self {
+        Self {
+            mode: ProxyMode::Active,
+            server: vec![],
+            allowed_peers: None,
+            hostname: None,
+            hostname_item: String::from("system.hostname"),
+            listen_port: ZabbixProxy::LISTEN_PORT,
+            timeout: Duration::from_secs(3),
+            config_frequency: Duration::from_secs(3600),
+            data_sender_frequency: Duration::from_secs(1),
+            heartbeat_frequency: Duration::from_secs(60),
+            offline_buffer: Duration::from_secs(3600),
+            tls: TlsOptions::default(),
+            warnings: vec![],
+        }
+    }
+}
+
+impl ProxyConf {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut conf = Self::default();
+        // Server 的格式取决于 ProxyMode，全部读取后再解析
+        let mut server = None;
+        let mut server_port = AgentConf::SERVER_PORT;
+        for line in read_conf(path.as_ref())? {
+            match line.key.as_str() {
+                "Server" => server = Some(line),
+                "ServerPort" => server_port = line.int(1024, 32767)?,
+                _ => conf.set(&line)?,
+            }
+        }
+
+        if let Some(line) = server {
+            let error =
+                |e: failure::Error| line.error(&format!("invalid parameter \"Server\": {}", e));
+            match conf.mode {
+                ProxyMode::Active => {
+                    if line.value.contains(',') {
+                        return Err(line
+                            .error("invalid parameter \"Server\": only one cluster is allowed in active mode")
+                            .into());
+                    }
+                    conf.server = parse_server_active(&line.value, server_port)
+                        .map_err(error)?
+                        .remove(0);
+                }
+                ProxyMode::Passive => {
+                    conf.allowed_peers = Some(AllowedPeers::parse(&line.value).map_err(error)?);
+                }
+            }
+        } else if conf.mode == ProxyMode::Passive {
+            return Err(ConfError {
+                file: path.as_ref().to_path_buf(),
+                line: 0,
+                reason: String::from("parameter \"Server\" must be defined in passive mode"),
+            }
+            .into());
+        }
+
+        for warning in &conf.warnings {
+            warn!("{}", warning);
+        }
+        Ok(conf)
+    }
+
+    fn set(&mut self, line: &ConfLine) -> Result<()> {
+        match line.key.as_str() {
+            "ProxyMode" => {
+                self.mode = match line.int(0u8, 1)? {
+                    0 => ProxyMode::Active,
+                    _ => ProxyMode::Passive,
+                }
+            }
+            "Hostname" => self.hostname = Some(line.value.clone()),
+            "HostnameItem" => self.hostname_item = line.value.clone(),
+            "ListenPort" => self.listen_port = line.int(1024, 32767)?,
+            "Timeout" => self.timeout = line.seconds(1, 30)?,
+            "ConfigFrequency" | "ProxyConfigFrequency" => {
+                self.config_frequency = line.seconds(1, 604_800)?
+            }
+            "DataSenderFrequency" => self.data_sender_frequency = line.seconds(1, 3600)?,
+            "HeartbeatFrequency" => self.heartbeat_frequency = line.seconds(0, 3600)?,
+            "ProxyOfflineBuffer" => self.offline_buffer = line.seconds(1, 720)? * 3600,
+            _ => {
+                if !self.tls.set(line)? {
+                    self.warnings
+                        .push(line.error(&format!("unknown parameter \"{}\"", line.key)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// 生成 proxy，主动模式连接 Server 的第一个节点
+    ///
+    pub fn build(&self) -> Result<ZabbixProxy> {
+        let (server, port) = match (self.mode, self.server.first()) {
+            (ProxyMode::Active, Some(node)) => node.clone(),
+            (ProxyMode::Active, None) => {
+                return Err(format_err!("\"Server\" is required in active mode"))
+            }
+            (ProxyMode::Passive, _) if self.allowed_peers.is_none() => {
+                return Err(format_err!("\"Server\" is required in passive mode"))
+            }
+            (ProxyMode::Passive, _) => (String::from("127.0.0.1"), AgentConf::SERVER_PORT),
+        };
+
+        #[cfg(feature = "tls")]
+        let tls = self.tls.build()?;
+        #[cfg(not(feature = "tls"))]
+        {
+            if self.tls.is_encrypted() {
+                return Err(format_err!("TLS parameters require the \"tls\" feature"));
+            }
+        }
+
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => resolve_item(&ItemRegistry::new(), &self.hostname_item)?,
+        };
+        let mut proxy = ZabbixProxy::new(&hostname, &server, port)
+            .with_mode(self.mode)
+            .with_listen_port(self.listen_port)
+            .with_timeout(self.timeout)
+            .with_config_frequency(self.config_frequency)
+            .with_data_sender_frequency(self.data_sender_frequency)
+            .with_heartbeat_frequency(self.heartbeat_frequency)
+            .with_offline_buffer(self.offline_buffer);
+        if let Some(peers) = &self.allowed_peers {
+            proxy = proxy.with_allowed_peers(peers.clone());
+        }
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = tls {
+                proxy = proxy.with_tls(tls);
+            }
+        }
+        Ok(proxy)
+    }
+}
+
+///
+/// 获取 HostnameItem 等监控项的值，`system.hostname` 未注册时读取系统主机名
+///
+fn resolve_item(registry: &ItemRegistry, key: &str) -> Result<String> {
+    if key == "system.hostname" && !registry.contains(key) {
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| fs::read_to_string("/etc/hostname"))
+            .map_err(|e| format_err!("cannot obtain system hostname: {}", e))?;
+        return Ok(hostname.trim().to_string());
+    }
+    registry
+        .get(key)
+        .map(|v| v.to_string())
+        .map_err(|e| format_err!("cannot get value of item \"{}\": {}", key, e))
+}
+
+///
+/// 解析 `host[:port]`，IPv6 地址带端口时使用 `[addr]:port`
+///
+pub(crate) fn parse_host_port(input: &str, default_port: u16) -> Result<(String, u16)> {
+    let input = input.trim();
+    let (host, port) = if let Some(rest) = input.strip_prefix('[') {
+        match rest.find(']') {
+            Some(i) => {
+                let port = &rest[i + 1..];
+                match port.strip_prefix(':') {
+                    Some(p) => (&rest[..i], Some(p)),
+                    None if port.is_empty() => (&rest[..i], None),
+                    None => return Err(format_err!("invalid address \"{}\"", input)),
+                }
+            }
+            None => return Err(format_err!("invalid address \"{}\"", input)),
+        }
+    } else if input.matches(':').count() == 1 {
+        let i = input.find(':').unwrap_or(0);
+        (&input[..i], Some(&input[i + 1..]))
+    } else {
+        (input, None)
+    };
+
+    if host.is_empty() {
+        return Err(format_err!("invalid address \"{}\"", input));
+    }
+    let port = match port {
+        None => default_port,
+        Some(p) => match p.parse::<u16>() {
+            Ok(p) if p > 0 => p,
+            _ => return Err(format_err!("invalid port in \"{}\"", input)),
+        },
+    };
+    Ok((String::from(host), port))
+}
+
+///
+/// 解析 ServerActive：集群以 `,` 分隔，集群内的节点以 `;` 分隔
+///
+pub(crate) fn parse_server_active(
+    input: &str,
+    default_port: u16,
+) -> Result<Vec<Vec<(String, u16)>>> {
+    input
+        .split(',')
+        .map(|cluster| {
+            cluster
+                .split(';')
+                .map(|node| parse_host_port(node, default_port))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zabbix-conf-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.conf", "a.conf"));
+        assert!(wildcard_match("*", "a.conf"));
+        assert!(wildcard_match("a*b*c", "aXbYc"));
+        assert!(!wildcard_match("*.conf", "a.conf.bak"));
+        assert!(!wildcard_match("a*b", "ba"));
+    }
+
+    #[test]
+    fn test_parse_server_active() {
+        assert_eq!(
+            vec![
+                vec![("zbx1".to_string(), 10051), ("zbx2".to_string(), 20051)],
+                vec![("::1".to_string(), 10051)],
+                vec![("::1".to_string(), 10052)],
+            ],
+            parse_server_active("zbx1;zbx2:20051, ::1,[::1]:10052", 10051).unwrap()
+        );
+        assert!(parse_server_active("zbx1,", 10051).is_err());
+        assert!(parse_server_active("zbx1:0", 10051).is_err());
+        assert!(parse_server_active("[::1]x", 10051).is_err());
+    }
+
+    #[test]
+    fn test_agent_conf() {
+        let dir = temp_dir("agent");
+        let conf = dir.join("zabbix_agentd.conf");
+        fs::create_dir(dir.join("zabbix_agentd.d")).unwrap();
+        fs::write(
+            &conf,
+            format!(
+                "# comment\n\
+                 Server=127.0.0.1,10.0.0.0/8\n\
+                 ServerActive=zbx1:20051;zbx2,zbx3\n\
+                 HostnameItem=host.name\n\
+                 HostMetadata = Linux app\n\
+                 Timeout=10\n\
+                 LogFile=/tmp/zabbix_agentd.log\n\
+                 Include={}/zabbix_agentd.d/*.conf\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("zabbix_agentd.d/userparams.conf"),
+            "UserParameter=host.name,echo my-host\nUserParameter=app.echo[*],echo $1\n",
+        )
+        .unwrap();
+        fs::write(dir.join("zabbix_agentd.d/ignored.bak"), "Bad line\n").unwrap();
+
+        let c = AgentConf::from_file(&conf).unwrap();
+        assert!(c
+            .server
+            .as_ref()
+            .unwrap()
+            .allows("10.1.1.1".parse().unwrap()));
+        assert_eq!(2, c.server_active.len());
+        assert_eq!(("zbx1".to_string(), 20051), c.server_active[0][0]);
+        assert_eq!(Some("Linux app".to_string()), c.host_metadata);
+        assert_eq!(Duration::from_secs(10), c.timeout);
+        assert_eq!(2, c.user_parameters.len());
+        assert_eq!(1, c.warnings.len());
+        assert_eq!(7, c.warnings[0].line);
+        assert_eq!("unknown parameter \"LogFile\"", c.warnings[0].reason);
+
+        let agent = c.build().unwrap();
+        assert_eq!("my-host", agent.name());
+        assert_eq!("x", agent.get_value("app.echo[x]"));
+        assert_eq!(("zbx1", 20051), agent.active_server());
+        assert_eq!(2, c.build_all().unwrap().len());
+
+        // 被动模式必须设置 Server
+        fs::write(&conf, "Hostname=h\nServerActive=zbx\n").unwrap();
+        let e = AgentConf::from_file(&conf)
+            .unwrap_err()
+            .downcast::<ConfError>()
+            .unwrap();
+        assert_eq!(
+            "StartAgents is not 0, parameter \"Server\" must be defined",
+            e.reason
+        );
+        fs::write(&conf, "Hostname=h\nServerActive=zbx\nStartAgents=0\n").unwrap();
+        let c = AgentConf::from_file(&conf).unwrap();
+        assert!(c.build().is_ok());
+        assert!(AgentConf::default().build().is_err());
+
+        fs::write(&conf, "Hostname=h\n\nTimeout=31\n").unwrap();
+        let e = AgentConf::from_file(&conf)
+            .unwrap_err()
+            .downcast::<ConfError>()
+            .unwrap();
+        assert_eq!(3, e.line);
+        assert!(e
+            .to_string()
+            .ends_with(":3: invalid value \"31\" for parameter \"Timeout\""));
+
+        fs::write(&conf, "Hostname\n").unwrap();
+        assert!(AgentConf::from_file(&conf).is_err());
+        fs::write(&conf, "UserParameter=bad key,echo\n").unwrap();
+        assert!(AgentConf::from_file(&conf).is_err());
+        fs::write(&conf, format!("Include={}\n", conf.display())).unwrap();
+        assert!(AgentConf::from_file(&conf).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tls_options() {
+        let dir = temp_dir("tls");
+        let conf = dir.join("zabbix_agentd.conf");
+        fs::write(
+            &conf,
+            "Hostname=h\nServer=127.0.0.1\nTLSConnect=psk\nTLSAccept=unencrypted,psk\nTLSPSKIdentity=id\n",
+        )
+        .unwrap();
+        let c = AgentConf::from_file(&conf).unwrap();
+        assert_eq!("psk", c.tls.connect);
+        assert_eq!(vec!["unencrypted", "psk"], c.tls.accept);
+        assert!(c.tls.is_encrypted());
+        assert!(c.warnings.is_empty());
+        // 缺少 TLSPSKFile，或未启用 tls 功能
+        assert!(c.build().is_err());
+
+        fs::write(&conf, "TLSConnect=ssl\n").unwrap();
+        assert!(AgentConf::from_file(&conf).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_proxy_conf() {
+        let dir = temp_dir("proxy");
+        let conf = dir.join("zabbix_proxy.conf");
+        fs::write(
+            &conf,
+            "Server=zbx1;zbx2:20051\n\
+             ServerPort=10055\n\
+             Hostname=proxy\n\
+             ConfigFrequency=300\n\
+             DataSenderFrequency=5\n\
+             HeartbeatFrequency=0\n\
+             ProxyOfflineBuffer=24\n\
+             DBName=zabbix\n",
+        )
+        .unwrap();
+        let c = ProxyConf::from_file(&conf).unwrap();
+        assert_eq!(ProxyMode::Active, c.mode);
+        assert_eq!(
+            vec![("zbx1".to_string(), 10055), ("zbx2".to_string(), 20051)],
+            c.server
+        );
+        assert_eq!(Duration::from_secs(300), c.config_frequency);
+        assert_eq!(Duration::from_secs(5), c.data_sender_frequency);
+        assert_eq!(Duration::from_secs(0), c.heartbeat_frequency);
+        assert_eq!(Duration::from_secs(24 * 3600), c.offline_buffer);
+        assert_eq!(1, c.warnings.len());
+        assert_eq!(8, c.warnings[0].line);
+        let proxy = c.build().unwrap();
+        assert_eq!("proxy", proxy.name());
+        assert_eq!(ProxyMode::Active, proxy.mode());
+
+        // 被动模式的 Server 为允许连接的地址
+        fs::write(
+            &conf,
+            "ProxyMode=1\nServer=10.0.0.0/8,zbx\nHostname=proxy\n",
+        )
+        .unwrap();
+        let c = ProxyConf::from_file(&conf).unwrap();
+        assert!(c.server.is_empty());
+        assert!(c.allowed_peers.unwrap().allows("10.1.1.1".parse().unwrap()));
+
+        fs::write(&conf, "ProxyMode=1\nHostname=proxy\n").unwrap();
+        let e = ProxyConf::from_file(&conf)
+            .unwrap_err()
+            .downcast::<ConfError>()
+            .unwrap();
+        assert_eq!(
+            "parameter \"Server\" must be defined in passive mode",
+            e.reason
+        );
+        let c = ProxyConf {
+            mode: ProxyMode::Passive,
+            hostname: Some(String::from("proxy")),
+            ..ProxyConf::default()
+        };
+        assert!(c.build().is_err());
+
+        fs::write(&conf, "Server=zbx1,zbx2\n").unwrap();
+        assert!(ProxyConf::from_file(&conf).is_err());
+        fs::write(&conf, "Server=zbx\nDataSenderFrequency=0\n").unwrap();
+        let e = ProxyConf::from_file(&conf)
+            .unwrap_err()
+            .downcast::<ConfError>()
+            .unwrap();
+        assert_eq!(2, e.line);
+        fs::write(&conf, "ProxyOfflineBuffer=721\n").unwrap();
+        assert!(ProxyConf::from_file(&conf).is_err());
+        fs::write(&conf, "Hostname=proxy\n").unwrap();
+        assert!(ProxyConf::from_file(&conf).unwrap().build().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,566 @@
+//! proxy 配置缓存
+//!
+//! Zabbix 6.4 以后服务端按 `config_revision` 增量下发 proxy 配置：`full_sync` 为 1 时
+//! 是完整配置，否则只包含变化的行，删除的行放在各表的 `del` 中。
+//! 缓存按表保存所有行（以第一个字段为主键），应用配置后返回主机和监控项的变化事件。
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::delay::Delay;
+use super::proxy::{Host, Item};
+use super::proxyconfig::{
+    table_rows, ConfigError, ConfigHost, ConfigItem, FromRow, ProxyConfig, Row, RowReader,
+};
+use super::usermacro::MacroResolver;
+use super::Result;
+
+/// 配置变化事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigEvent {
+    HostAdded(Host),
+    HostUpdated(Host),
+    /// 主机被删除或停用
+    HostRemoved(i64),
+    ItemAdded(Item),
+    /// 只有采集间隔变化
+    ItemDelayChanged {
+        itemid: i64,
+        old: Delay,
+        new: Delay,
+    },
+    ItemUpdated(Item),
+    /// 监控项被删除或停用
+    ItemRemoved(i64),
+}
+
+/// 一个表的变化
+struct TableDelta {
+    name: String,
+    rows: Vec<(i64, Row)>,
+    del: Vec<i64>,
+}
+
+/// `proxy config` 请求
+#[derive(Serialize, Debug)]
+pub(crate) struct ProxyConfigRequest<'a> {
+    pub request: &'static str,
+    pub host: &'a str,
+    pub version: &'a str,
+    pub session: &'a str,
+    pub config_revision: u64,
+}
+
+/// proxy 配置缓存
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigCache {
+    revision: u64,
+    tables: HashMap<String, BTreeMap<i64, Row>>,
+}
+
+impl ConfigCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// 当前的配置版本，请求配置时发送给服务端
+    ///
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn table_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.tables.keys().map(|n| n.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    ///
+    /// 表中的所有行，按主键排序
+    ///
+    pub fn rows(&self, table: &str) -> Vec<HashMap<String, Value>> {
+        self.tables
+            .get(table)
+            .map(|rows| rows.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn row(&self, table: &str, id: i64) -> Option<&HashMap<String, Value>> {
+        self.tables.get(table).and_then(|rows| rows.get(&id))
+    }
+
+    ///
+    /// 已启用的主机
+    ///
+    pub fn hosts(&self) -> HashSet<Host> {
+        // 格式错误的行在 apply 时已记录
+        let mut errors = vec![];
+        self.tables
+            .get("hosts")
+            .map(|rows| {
+                rows.iter()
+                    .filter_map(|(id, row)| to_host(*id, row, &mut errors))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    ///
+    /// 已启用且需要检查的监控项，compress 的含义与 `ZabbixProxy::get_proxy_config` 相同
+    ///
+    pub fn items(&self, compress: &[&str]) -> HashSet<Item> {
+        let macros = self.macros();
+        let mut errors = vec![];
+        self.tables
+            .get("items")
+            .map(|rows| {
+                rows.iter()
+                    .filter_map(|(id, row)| to_item(*id, row, &macros, compress, &mut errors))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn macros(&self) -> MacroResolver {
+        MacroResolver::new(&ProxyConfig::from_cache_tables(self, MacroResolver::TABLES))
+    }
+
+    ///
+    /// 应用服务端返回的配置，返回主机和监控项的变化。
+    /// 配置中没有 `config_revision` 时（6.4 以前的服务端）视为完整配置。
+    /// 表格式错误时返回错误，缓存保持不变；格式错误的行记录日志后跳过
+    ///
+    pub fn apply(&mut self, config: &Value) -> Result<Vec<ConfigEvent>> {
+        let object = config
+            .as_object()
+            .ok_or_else(|| format_err!("invalid configuration data"))?;
+        if object.get("response").and_then(|r| r.as_str()) == Some("failed") {
+            return Err(format_err!(
+                "server returned error: {}",
+                object
+                    .get("info")
+                    .and_then(|i| i.as_str())
+                    .unwrap_or_default()
+            ));
+        }
+
+        let revision = match object.get("config_revision") {
+            Some(r) => Some(
+                r.as_u64()
+                    .ok_or_else(|| format_err!("invalid config_revision: {}", r))?,
+            ),
+            None => None,
+        };
+        let full_sync =
+            revision.is_none() || object.get("full_sync").and_then(|f| f.as_i64()) == Some(1);
+        let tables = match object.get("data") {
+            Some(Value::Object(data)) => data,
+            _ => object,
+        };
+        let mut errors = vec![];
+        let deltas = parse_tables(tables, &mut errors)?;
+
+        // 只比较本次变化涉及的主机和监控项
+        let mut touched: HashMap<&str, BTreeSet<i64>> = HashMap::new();
+        if full_sync {
+            for (name, rows) in &self.tables {
+                if let Some(name) = watched(name) {
+                    touched.entry(name).or_default().extend(rows.keys());
+                }
+            }
+        }
+        // 宏或模板变化时所有监控项的 key 和间隔都可能变化
+        if deltas
+            .iter()
+            .any(|d| MacroResolver::TABLES.contains(&d.name.as_str()))
+        {
+            let items = self.tables.get("items").into_iter().flat_map(|r| r.keys());
+            touched.entry("items").or_default().extend(items);
+        }
+        for delta in &deltas {
+            if let Some(name) = watched(&delta.name) {
+                let ids = delta
+                    .rows
+                    .iter()
+                    .map(|(id, _)| *id)
+                    .chain(delta.del.iter().cloned());
+                touched.entry(name).or_default().extend(ids);
+            }
+        }
+        let before = self.snapshot(&touched, &mut vec![]);
+
+        if full_sync {
+            self.tables.clear();
+        }
+        for delta in deltas {
+            let rows = self.tables.entry(delta.name).or_default();
+            for id in &delta.del {
+                rows.remove(id);
+            }
+            rows.extend(delta.rows);
+        }
+        if let Some(revision) = revision {
+            self.revision = revision;
+        }
+
+        let after = self.snapshot(&touched, &mut errors);
+        for e in &errors {
+            warn!("invalid proxy configuration: {}", e);
+        }
+        Ok(diff(&before, &after))
+    }
+
+    fn snapshot(
+        &self,
+        touched: &HashMap<&str, BTreeSet<i64>>,
+        errors: &mut Vec<ConfigError>,
+    ) -> Snapshot {
+        let rows = |table: &str| {
+            let rows = self.tables.get(table);
+            touched
+                .get(table)
+                .into_iter()
+                .flatten()
+                .map(move |id| (*id, rows.and_then(|r| r.get(id))))
+        };
+        let macros = self.macros();
+        Snapshot {
+            hosts: rows("hosts")
+                .map(|(id, r)| (id, r.and_then(|r| to_host(id, r, errors))))
+                .collect(),
+            items: rows("items")
+                .map(|(id, r)| (id, r.and_then(|r| to_item(id, r, &macros, &[], errors))))
+                .collect(),
+        }
+    }
+}
+
+/// 变化涉及的主机和监控项，停用或删除的为 None
+struct Snapshot {
+    hosts: BTreeMap<i64, Option<Host>>,
+    items: BTreeMap<i64, Option<Item>>,
+}
+
+fn watched(table: &str) -> Option<&'static str> {
+    match table {
+        "hosts" => Some("hosts"),
+        "items" => Some("items"),
+        _ => None,
+    }
+}
+
+fn diff(before: &Snapshot, after: &Snapshot) -> Vec<ConfigEvent> {
+    let mut events = vec![];
+    for (id, host) in &after.hosts {
+        match (before.hosts.get(id).and_then(|h| h.as_ref()), host) {
+            (None, Some(h)) => events.push(ConfigEvent::HostAdded(h.clone())),
+            (Some(old), Some(h)) if old != h => events.push(ConfigEvent::HostUpdated(h.clone())),
+            (Some(_), None) => events.push(ConfigEvent::HostRemoved(*id)),
+            _ => {}
+        }
+    }
+    for (id, item) in &after.items {
+        match (before.items.get(id).and_then(|i| i.as_ref()), item) {
+            (None, Some(i)) => events.push(ConfigEvent::ItemAdded(i.clone())),
+            (Some(old), Some(i)) if old == i => {}
+            (Some(old), Some(i)) if old.hostid == i.hostid && old.key_ == i.key_ => {
+                events.push(ConfigEvent::ItemDelayChanged {
+                    itemid: *id,
+                    old: old.delay.clone(),
+                    new: i.delay.clone(),
+                })
+            }
+            (Some(_), Some(i)) => events.push(ConfigEvent::ItemUpdated(i.clone())),
+            (Some(_), None) => events.push(ConfigEvent::ItemRemoved(*id)),
+            (None, None) => {}
+        }
+    }
+    events
+}
+
+///
+/// 解析各表的变化，表格式错误时返回错误，与字段不符或主键无效的行记录错误后跳过
+///
+fn parse_tables(
+    tables: &Map<String, Value>,
+    errors: &mut Vec<ConfigError>,
+) -> Result<Vec<TableDelta>> {
+    let mut result = vec![];
+    for (name, table) in tables {
+        let object = match table.as_object() {
+            Some(t) if t.contains_key("fields") || t.contains_key("del") => t,
+            // config_revision、full_sync 等非表数据
+            _ => continue,
+        };
+        let error = |row: Option<usize>, field: Option<&str>, reason: String| ConfigError {
+            table: name.clone(),
+            row,
+            field: field.map(String::from),
+            reason,
+        };
+
+        let mut rows = vec![];
+        if object.contains_key("fields") {
+            let key = table["fields"].get(0).and_then(|f| f.as_str());
+            for (i, row) in table_rows(name, table, errors)? {
+                match key.and_then(|k| row.get(k)).and_then(to_id) {
+                    Some(id) => rows.push((id, row)),
+                    None => errors.push(error(Some(i), key, String::from("invalid id"))),
+                }
+            }
+        }
+
+        let mut del = vec![];
+        match object.get("del") {
+            Some(Value::Array(ids)) => {
+                for id in ids {
+                    match to_id(id) {
+                        Some(id) => del.push(id),
+                        None => {
+                            errors.push(error(None, None, format!("invalid id in del: {}", id)))
+                        }
+                    }
+                }
+            }
+            None => {}
+            Some(_) => return Err(error(None, None, String::from("del is not an array")).into()),
+        }
+
+        result.push(TableDelta {
+            name: name.clone(),
+            rows,
+            del,
+        });
+    }
+    Ok(result)
+}
+
+fn to_id(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn to_host(id: i64, row: &Row, errors: &mut Vec<ConfigError>) -> Option<Host> {
+    let host = match ConfigHost::from_row(&RowReader::new("hosts", 0, row)) {
+        Ok(host) => host,
+        Err(e) => {
+            errors.push(row_error(e, "hostid", id));
+            return None;
+        }
+    };
+    if !host.is_monitored() {
+        return None;
+    }
+    Some(Host::new(host.hostid, host.host))
+}
+
+fn to_item(
+    id: i64,
+    row: &Row,
+    macros: &MacroResolver,
+    compress: &[&str],
+    errors: &mut Vec<ConfigError>,
+) -> Option<Item> {
+    match ConfigItem::from_row(&RowReader::new("items", 0, row)) {
+        Ok(item) => macros.item(&item, compress),
+        Err(e) => {
+            errors.push(row_error(e, "itemid", id));
+            None
+        }
+    }
+}
+
+///
+/// 缓存中的行没有序号，错误中改为主键
+///
+fn row_error(mut e: ConfigError, key: &str, id: i64) -> ConfigError {
+    e.row = None;
+    e.reason = format!("{} {}: {}", key, id, e.reason);
+    e
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(data: Value) -> Value {
+        json!({"fields": ["hostid", "host", "status"], "data": data})
+    }
+
+    fn items(data: Value) -> Value {
+        json!({"fields": ["itemid", "hostid", "key_", "delay", "status"], "data": data})
+    }
+
+    #[test]
+    fn test_config_cache_full_sync() {
+        let mut cache = ConfigCache::new();
+        // 6.4 以前的完整配置
+        let events = cache
+            .apply(&json!({
+                "hosts": hosts(json!([[1, "h1", 0], [2, "h2", 1]])),
+                "items": items(json!([[10, 1, "a", "30s", 0], [11, 1, "b", "0", 0]])),
+            }))
+            .unwrap();
+        assert_eq!(0, cache.revision());
+        assert_eq!(
+            vec![
+                ConfigEvent::HostAdded(Host::new(1, "h1".to_string())),
+                ConfigEvent::ItemAdded(Item::new(10, 1, "a".to_string(), 30)),
+            ],
+            events
+        );
+        assert_eq!(2, cache.rows("hosts").len());
+        assert_eq!(1, cache.hosts().len());
+        assert_eq!(1, cache.items(&[]).len());
+
+        // full_sync 替换所有表
+        let events = cache
+            .apply(&json!({
+                "config_revision": 5,
+                "full_sync": 1,
+                "data": {"hosts": hosts(json!([[2, "h2", 0]]))},
+            }))
+            .unwrap();
+        assert_eq!(5, cache.revision());
+        assert_eq!(
+            vec![
+                ConfigEvent::HostRemoved(1),
+                ConfigEvent::HostAdded(Host::new(2, "h2".to_string())),
+                ConfigEvent::ItemRemoved(10),
+            ],
+            events
+        );
+        assert_eq!(vec!["hosts"], cache.table_names());
+    }
+
+    #[test]
+    fn test_config_cache_incremental() {
+        let mut cache = ConfigCache::new();
+        cache
+            .apply(&json!({
+                "config_revision": 1,
+                "full_sync": 1,
+                "data": {
+                    "hosts": hosts(json!([[1, "h1", 0]])),
+                    "items": items(json!([[10, 1, "a", "30s", 0], [11, 1, "b", "1m", 0], [12, 1, "c", "1m", 0]])),
+                    "globalmacro": {"fields": ["globalmacroid", "macro", "value"], "data": [[1, "{$A}", "1"]]},
+                },
+            }))
+            .unwrap();
+
+        let events = cache
+            .apply(&json!({
+                "config_revision": 2,
+                "data": {
+                    "items": {
+                        "fields": ["itemid", "hostid", "key_", "delay", "status"],
+                        "data": [[10, 1, "a", "1m", 0], [11, 1, "b2", "1m", 0], [13, 1, "d", "5m", 0]],
+                        "del": [12],
+                    },
+                },
+            }))
+            .unwrap();
+        assert_eq!(2, cache.revision());
+        assert_eq!(
+            vec![
+                ConfigEvent::ItemDelayChanged {
+                    itemid: 10,
+                    old: Delay::from(30),
+                    new: Delay::from(60),
+                },
+                ConfigEvent::ItemUpdated(Item::new(11, 1, "b2".to_string(), 60)),
+                ConfigEvent::ItemRemoved(12),
+                ConfigEvent::ItemAdded(Item::new(13, 1, "d".to_string(), 300)),
+            ],
+            events
+        );
+        // 未变化的表保持不变
+        assert_eq!(1, cache.rows("globalmacro").len());
+        assert_eq!(
+            Some(&json!("{$A}")),
+            cache.row("globalmacro", 1).unwrap().get("macro")
+        );
+
+        // 没有变化
+        assert!(cache
+            .apply(&json!({"config_revision": 2}))
+            .unwrap()
+            .is_empty());
+        assert_eq!(3, cache.items(&[]).len());
+
+        // 表格式错误时不修改缓存
+        let bad = json!({"config_revision": 3, "data": {
+            "hosts": hosts(json!([[1, "h1", 1]])),
+            "items": {"fields": "itemid", "data": []},
+        }});
+        let e = cache.apply(&bad).unwrap_err();
+        assert_eq!("table \"items\": fields is not an array", e.to_string());
+        assert_eq!(2, cache.revision());
+        assert_eq!(1, cache.hosts().len());
+
+        // 格式错误的行被跳过，其余的变化正常应用
+        let events = cache
+            .apply(&json!({"config_revision": 3, "data": {
+                "hosts": hosts(json!([[1, "h1", 1]])),
+                "items": items(json!([[14, 1, "e"], [15, 1, "f", "1m", 0], [16, 1, null, "1m", 0]])),
+            }}))
+            .unwrap();
+        assert_eq!(3, cache.revision());
+        assert_eq!(
+            vec![
+                ConfigEvent::HostRemoved(1),
+                ConfigEvent::ItemAdded(Item::new(15, 1, "f".to_string(), 60)),
+            ],
+            events
+        );
+        assert!(cache.row("items", 14).is_none());
+        assert!(cache.row("items", 16).is_some());
+        assert!(cache
+            .apply(&json!({"response": "failed", "info": "x"}))
+            .is_err());
+        assert!(cache.apply(&json!([])).is_err());
+    }
+
+    #[test]
+    fn test_parse_tables_errors() {
+        let tables = json!({
+            "items": {
+                "fields": ["itemid", "hostid", "key_", "delay", "status"],
+                "data": [[10, 1, "a"], ["x", 1, "b", "1m", 0], [11, 1, "c", "1m", 0]],
+                "del": [12, "y"],
+            },
+        });
+        let mut errors = vec![];
+        let deltas = parse_tables(tables.as_object().unwrap(), &mut errors).unwrap();
+        assert_eq!(
+            vec![11],
+            deltas[0].rows.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![12], deltas[0].del);
+        assert_eq!(
+            vec![
+                "table \"items\", row 0: row does not match 5 fields",
+                "table \"items\", row 1, field \"itemid\": invalid id",
+                "table \"items\": invalid id in del: \"y\"",
+            ],
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>()
+        );
+
+        let row = deltas[0].rows[0].1.clone();
+        let mut bad = row.clone();
+        bad.insert("key_".to_string(), Value::Null);
+        let macros = MacroResolver::new(&ProxyConfig::default());
+        let mut errors = vec![];
+        assert!(to_item(11, &row, &macros, &[], &mut errors).is_some());
+        assert!(to_item(11, &bad, &macros, &[], &mut errors).is_none());
+        assert_eq!(1, errors.len());
+        assert!(
+            errors[0].to_string().contains("itemid 11: "),
+            "{}",
+            errors[0]
+        );
+    }
+}
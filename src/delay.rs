@@ -0,0 +1,862 @@
+//! 监控项更新间隔
+//!
+//! 间隔由默认间隔和若干用 `;` 分隔的自定义间隔组成，如 `30s;5m/1-5,09:00-18:00;wd1-5h9`：
+//! - 灵活间隔 `<间隔>/<时间段>`，在时间段内使用该间隔，多个时间段重叠时使用最小的间隔；
+//! - 调度间隔 `[md<过滤>][wd<过滤>][h<过滤>][m<过滤>][s<过滤>]`，在指定的时间点检查。
+//!   过滤为逗号分隔的 `<从>[-<到>][/<步长>]` 或 `/<步长>`，省略的较小单位为 0，省略的较大单位为任意值。
+//!
+//! 下次检查时间的计算与 zabbix 服务端的 `calculate_item_nextcheck` 相同：
+//! 按当前间隔取整后加上以监控项编号为种子的偏移，使不同监控项的检查分散开。
+use chrono::prelude::*;
+use chrono::LocalResult;
+use std::fmt;
+use std::str::FromStr;
+
+use super::Result;
+
+const SEC_PER_DAY: u32 = 86400;
+const SEC_PER_YEAR: i64 = 365 * 86400;
+/// 服务端表示不再检查的时间
+const JAN_2038: i64 = 0x7fff_ffff;
+/// 查找调度间隔的最大天数
+const MAX_SCHEDULE_DAYS: i64 = 10 * 366;
+
+/// 时间段 `d[-d],hh:mm-hh:mm`，星期从 1（周一）到 7，时间为一天中的秒数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimePeriod {
+    pub start_day: u32,
+    pub end_day: u32,
+    pub start_time: u32,
+    pub end_time: u32,
+}
+
+impl TimePeriod {
+    pub fn parse(s: &str) -> Result<Self> {
+        let error = || format_err!("invalid time period \"{}\"", s);
+        let (days, times) = split2(s, ',').ok_or_else(error)?;
+        let (start_day, end_day) = match split2(days, '-') {
+            Some((start, end)) => (parse_day(start), parse_day(end)),
+            None => (parse_day(days), parse_day(days)),
+        };
+        let (start_time, end_time) = split2(times, '-').ok_or_else(error)?;
+        let period = Self {
+            start_day: start_day.ok_or_else(error)?,
+            end_day: end_day.ok_or_else(error)?,
+            start_time: parse_time(start_time).ok_or_else(error)?,
+            end_time: parse_time(end_time).ok_or_else(error)?,
+        };
+        if period.start_day > period.end_day || period.start_time >= period.end_time {
+            return Err(error());
+        }
+        Ok(period)
+    }
+
+    ///
+    /// day 为星期（1 到 7），time 为一天中的秒数
+    ///
+    pub fn contains(&self, day: u32, time: u32) -> bool {
+        self.start_day <= day
+            && day <= self.end_day
+            && self.start_time <= time
+            && time < self.end_time
+    }
+}
+
+impl fmt::Display for TimePeriod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = |t: u32| format!("{:02}:{:02}", t / 3600, t % 3600 / 60);
+        if self.start_day == self.end_day {
+            write!(f, "{}", self.start_day)?;
+        } else {
+            write!(f, "{}-{}", self.start_day, self.end_day)?;
+        }
+        write!(f, ",{}-{}", time(self.start_time), time(self.end_time))
+    }
+}
+
+fn split2(s: &str, sep: char) -> Option<(&str, &str)> {
+    let i = s.find(sep)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+fn parse_day(s: &str) -> Option<u32> {
+    match s.parse() {
+        Ok(d) if s.len() == 1 && (1..=7).contains(&d) => Some(d),
+        _ => None,
+    }
+}
+
+/// `h:mm` 或 `hh:mm`，最大为 24:00
+fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = split2(s, ':')?;
+    if h.is_empty() || h.len() > 2 || m.len() != 2 || !is_digits(h) || !is_digits(m) {
+        return None;
+    }
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    if m > 59 || h * 60 + m > 24 * 60 {
+        return None;
+    }
+    Some(h * 3600 + m * 60)
+}
+
+fn is_digits(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_digit())
+}
+
+///
+/// 带单位 (s、m、h、d、w) 的秒数，如 `30`、`30s`、`5m`
+///
+fn parse_seconds(s: &str) -> Option<u32> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    if digits.is_empty() || !is_digits(digits) {
+        return None;
+    }
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => SEC_PER_DAY,
+        'w' => 7 * SEC_PER_DAY,
+        _ => return None,
+    };
+    digits.parse::<u32>().ok()?.checked_mul(multiplier)
+}
+
+/// 灵活间隔
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlexibleInterval {
+    /// 时间段内的间隔，0 表示时间段内不检查
+    pub delay: u32,
+    pub period: TimePeriod,
+}
+
+/// 调度间隔，各单位允许的值保存为位掩码
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SchedulingInterval {
+    text: String,
+    /// 1 到 31
+    mdays: u32,
+    /// 1 到 7
+    wdays: u32,
+    hours: u32,
+    minutes: u64,
+    seconds: u64,
+}
+
+impl SchedulingInterval {
+    pub fn parse(s: &str) -> Result<Self> {
+        let error = || format_err!("invalid scheduling interval \"{}\"", s);
+
+        // 依次为 md、wd、h、m、s，每个单位最多出现一次
+        const UNITS: [(&str, u32, u32, usize); 5] = [
+            ("md", 1, 31, 2),
+            ("wd", 1, 7, 1),
+            ("h", 0, 23, 2),
+            ("m", 0, 59, 2),
+            ("s", 0, 59, 2),
+        ];
+        let mut filters: [Option<u64>; 5] = [None; 5];
+        let mut rest = s;
+        let mut next = 0;
+        while !rest.is_empty() {
+            let unit = (next..UNITS.len())
+                .find(|i| rest.starts_with(UNITS[*i].0))
+                .ok_or_else(error)?;
+            let (prefix, min, max, digits) = UNITS[unit];
+            rest = &rest[prefix.len()..];
+            let len = rest
+                .find(|c: char| c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            filters[unit] = Some(parse_filter(&rest[..len], min, max, digits).ok_or_else(error)?);
+            rest = &rest[len..];
+            next = unit + 1;
+        }
+        if next == 0 {
+            return Err(error());
+        }
+
+        // 最后一个指定的单位之后的单位为 0，之前省略的为任意值
+        let mask = |i: usize, min: u32, max: u32| match filters[i] {
+            Some(mask) => mask,
+            None if i >= next => 1 << min,
+            None => range_mask(min, max, 1),
+        };
+        Ok(Self {
+            text: String::from(s),
+            mdays: filters[0].unwrap_or_else(|| range_mask(1, 31, 1)) as u32,
+            wdays: filters[1].unwrap_or_else(|| range_mask(1, 7, 1)) as u32,
+            hours: mask(2, 0, 23) as u32,
+            minutes: mask(3, 0, 59),
+            seconds: mask(4, 0, 59),
+        })
+    }
+
+    ///
+    /// 第一个晚于 now 的调度时间
+    ///
+    pub fn next_after<Tz: TimeZone>(&self, now: i64, tz: &Tz) -> Option<i64> {
+        let start = tz.timestamp_opt(now + 1, 0).single()?.naive_local();
+        for offset in 0..MAX_SCHEDULE_DAYS {
+            let date = start
+                .date()
+                .checked_add_signed(chrono::Duration::days(offset))?;
+            let wday = date.weekday().number_from_monday();
+            if !has_bit(u64::from(self.mdays), date.day()) || !has_bit(u64::from(self.wdays), wday)
+            {
+                continue;
+            }
+            let mut from = if offset == 0 {
+                start.num_seconds_from_midnight()
+            } else {
+                0
+            };
+            while let Some(time) = self.first_time(from) {
+                let local = date.and_hms_opt(time / 3600, time % 3600 / 60, time % 60)?;
+                match tz.from_local_datetime(&local) {
+                    // 重复的时间只在第一次检查
+                    LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => {
+                        return Some(t.timestamp())
+                    }
+                    // 夏令时跳过的时间，从之后的时间继续查找
+                    LocalResult::None => from = time + 1,
+                }
+            }
+        }
+        None
+    }
+
+    ///
+    /// 一天中不早于 from 的第一个调度时间（秒）
+    ///
+    fn first_time(&self, from: u32) -> Option<u32> {
+        let (h0, m0, s0) = (from / 3600, from % 3600 / 60, from % 60);
+        for h in (h0..24).filter(|h| has_bit(u64::from(self.hours), *h)) {
+            let m_start = if h == h0 { m0 } else { 0 };
+            for m in (m_start..60).filter(|m| has_bit(self.minutes, *m)) {
+                let s_start = if h == h0 && m == m0 { s0 } else { 0 };
+                if let Some(s) = (s_start..60).find(|s| has_bit(self.seconds, *s)) {
+                    return Some(h * 3600 + m * 60 + s);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for SchedulingInterval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn has_bit(mask: u64, bit: u32) -> bool {
+    mask & (1 << bit) != 0
+}
+
+fn range_mask(from: u32, to: u32, step: u32) -> u64 {
+    (from..=to)
+        .step_by(step as usize)
+        .fold(0, |mask, i| mask | 1 << i)
+}
+
+///
+/// 解析逗号分隔的 `<从>[-<到>][/<步长>]` 或 `/<步长>`，数字最多 digits 位
+///
+fn parse_filter(s: &str, min: u32, max: u32, digits: usize) -> Option<u64> {
+    let number = |s: &str| -> Option<u32> {
+        if s.is_empty() || s.len() > digits || !is_digits(s) {
+            return None;
+        }
+        s.parse().ok().filter(|n| (min..=max).contains(n))
+    };
+
+    let mut mask = 0;
+    for part in s.split(',') {
+        let (range, step) = match split2(part, '/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let (from, to) = match (range, split2(range, '-')) {
+            ("", None) if step.is_some() => (min, max),
+            (_, Some((from, to))) => (number(from)?, number(to)?),
+            // 只有起始值和步长时到最大值为止
+            (from, None) if step.is_some() => (number(from)?, max),
+            (from, None) => (number(from)?, number(from)?),
+        };
+        let step = match step {
+            Some(step) if is_digits(step) && !step.is_empty() => step.parse().ok()?,
+            Some(_) => return None,
+            None => 1,
+        };
+        if from > to || step == 0 || step > max {
+            return None;
+        }
+        mask |= range_mask(from, to, step);
+    }
+    Some(mask)
+}
+
+/// 监控项更新间隔
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Delay {
+    simple: u32,
+    flexible: Vec<FlexibleInterval>,
+    scheduling: Vec<SchedulingInterval>,
+}
+
+impl Delay {
+    /// 默认间隔和灵活间隔的最大值
+    pub const MAX: u32 = SEC_PER_DAY;
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parts = s.split(';');
+        let simple = parts.next().unwrap_or_default();
+        let simple = parse_seconds(simple)
+            .filter(|d| *d <= Self::MAX)
+            .ok_or_else(|| format_err!("invalid update interval \"{}\"", simple))?;
+
+        let mut flexible = vec![];
+        let mut scheduling = vec![];
+        for part in parts {
+            if part.starts_with(|c: char| c.is_ascii_digit()) {
+                let (delay, period) = split2(part, '/')
+                    .ok_or_else(|| format_err!("invalid flexible interval \"{}\"", part))?;
+                let delay = parse_seconds(delay)
+                    .filter(|d| *d <= Self::MAX)
+                    .ok_or_else(|| format_err!("invalid flexible interval \"{}\"", part))?;
+                let period = TimePeriod::parse(period)?;
+                flexible.push(FlexibleInterval { delay, period });
+            } else {
+                scheduling.push(SchedulingInterval::parse(part)?);
+            }
+        }
+        Ok(Self {
+            simple,
+            flexible,
+            scheduling,
+        })
+    }
+
+    /// 默认间隔（秒）
+    pub fn simple(&self) -> u32 {
+        self.simple
+    }
+
+    pub fn flexible(&self) -> &[FlexibleInterval] {
+        &self.flexible
+    }
+
+    pub fn scheduling(&self) -> &[SchedulingInterval] {
+        &self.scheduling
+    }
+
+    ///
+    /// 默认间隔不为 0 或有自定义间隔时需要检查，否则监控项不会被采集
+    ///
+    pub fn is_active(&self) -> bool {
+        self.simple != 0 || !self.flexible.is_empty() || !self.scheduling.is_empty()
+    }
+
+    ///
+    /// now 时刻使用的间隔：所在时间段的灵活间隔中最小的，不在任何时间段时为默认间隔
+    ///
+    pub fn delay_at<Tz: TimeZone>(&self, now: i64, tz: &Tz) -> u32 {
+        let (day, time) = match day_time(now, tz) {
+            Some(dt) => dt,
+            None => return self.simple,
+        };
+        self.flexible
+            .iter()
+            .filter(|f| f.period.contains(day, time))
+            .map(|f| f.delay)
+            .min()
+            .unwrap_or(self.simple)
+    }
+
+    ///
+    /// now 之后最近的灵活间隔开始或结束的时间
+    ///
+    fn next_interval<Tz: TimeZone>(&self, now: i64, tz: &Tz) -> Option<i64> {
+        let (day, time) = day_time(now, tz)?;
+        let next = self
+            .flexible
+            .iter()
+            .map(|f| {
+                let p = &f.period;
+                if p.start_day <= day && day <= p.end_day && time < p.end_time {
+                    // 今天还会生效或正在生效
+                    if time < p.start_time {
+                        p.start_time
+                    } else {
+                        p.end_time
+                    }
+                } else if day < p.end_day {
+                    // 本周还会生效
+                    if day < p.start_day {
+                        SEC_PER_DAY * (p.start_day - day) + p.start_time
+                    } else {
+                        SEC_PER_DAY + p.start_time
+                    }
+                } else {
+                    SEC_PER_DAY * (p.start_day + 7 - day) + p.start_time
+                }
+            })
+            .min()?;
+        Some(now - i64::from(time) + i64::from(next))
+    }
+
+    ///
+    /// 使用本地时区计算下次检查时间，见 `next_check_tz`
+    ///
+    pub fn next_check(&self, itemid: i64, now: i64) -> Option<i64> {
+        self.next_check_tz(itemid, now, &Local)
+    }
+
+    ///
+    /// 晚于 now 的下次检查时间，偏移由 itemid 决定。一年内都不检查时返回 None
+    ///
+    pub fn next_check_tz<Tz: TimeZone>(&self, itemid: i64, now: i64, tz: &Tz) -> Option<i64> {
+        let scheduled = self
+            .scheduling
+            .iter()
+            .filter_map(|s| s.next_after(now, tz))
+            .min();
+
+        let mut nextcheck = JAN_2038;
+        let mut t = now;
+        let mut attempt = 0;
+        while t < now + SEC_PER_YEAR {
+            let delay = i64::from(self.delay_at(t, tz));
+            nextcheck = if delay != 0 {
+                let mut next = delay * t.div_euclid(delay) + itemid.rem_euclid(delay);
+                // 第一次必须晚于 now，进入新的时间段后可以从时间段开始时检查
+                while next < t || (attempt == 0 && next == t) {
+                    next += delay;
+                }
+                next
+            } else {
+                JAN_2038
+            };
+
+            match self.next_interval(t, tz) {
+                // 超出了当前时间段，从下一个时间段重新计算
+                Some(next_interval) if nextcheck >= next_interval => {
+                    t = next_interval;
+                    attempt += 1;
+                }
+                _ => break,
+            }
+        }
+
+        if let Some(scheduled) = scheduled {
+            nextcheck = nextcheck.min(scheduled);
+        }
+        Some(nextcheck).filter(|n| *n != JAN_2038)
+    }
+}
+
+fn day_time<Tz: TimeZone>(now: i64, tz: &Tz) -> Option<(u32, u32)> {
+    let t = tz.timestamp_opt(now, 0).single()?;
+    Some((
+        t.weekday().number_from_monday(),
+        t.num_seconds_from_midnight(),
+    ))
+}
+
+impl From<u32> for Delay {
+    ///
+    /// 只有默认间隔
+    ///
+    fn from(simple: u32) -> Self {
+        Self {
+            simple,
+            flexible: vec![],
+            scheduling: vec![],
+        }
+    }
+}
+
+impl FromStr for Delay {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.simple)?;
+        for flexible in &self.flexible {
+            write!(f, ";{}/{}", flexible.delay, flexible.period)?;
+        }
+        for scheduling in &self.scheduling {
+            write!(f, ";{}", scheduling)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2020-09-13 12:26:40 UTC，周日
+    const SUNDAY: i64 = 1_600_000_000;
+    // 2020-09-14 00:00:00 UTC，周一
+    const MONDAY: i64 = 1_600_041_600;
+    const HOUR: i64 = 3600;
+
+    // 2021-03-28 01:00 UTC，02:00 CET 调到 03:00 CEST
+    const SPRING: i64 = 1_616_893_200;
+    // 2021-10-31 01:00 UTC，03:00 CEST 调回 02:00 CET
+    const AUTUMN: i64 = 1_635_642_000;
+
+    /// 使用 2021 年中欧夏令时的时区
+    #[derive(Debug, Clone, Copy)]
+    struct Cet;
+
+    impl Cet {
+        fn offset_at(t: i64) -> FixedOffset {
+            let hours = if (SPRING..AUTUMN).contains(&t) { 2 } else { 1 };
+            FixedOffset::east_opt(hours * 3600).unwrap()
+        }
+    }
+
+    impl TimeZone for Cet {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            Cet
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let local = local.and_utc().timestamp();
+            // 夏令时的偏移在前，重复的时间先返回较早的
+            let offsets: Vec<_> = [2, 1]
+                .iter()
+                .map(|h| FixedOffset::east_opt(h * 3600).unwrap())
+                .filter(|o| Self::offset_at(local - i64::from(o.local_minus_utc())) == *o)
+                .collect();
+            match offsets[..] {
+                [] => LocalResult::None,
+                [o] => LocalResult::Single(o),
+                [a, b, ..] => LocalResult::Ambiguous(a, b),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms_opt(0, 0, 0).unwrap())
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            Self::offset_at(utc.and_utc().timestamp())
+        }
+    }
+
+    fn next(delay: &str, itemid: i64, now: i64) -> Option<i64> {
+        Delay::parse(delay)
+            .unwrap()
+            .next_check_tz(itemid, now, &Utc)
+    }
+
+    #[test]
+    fn test_parse_delay() {
+        for (s, simple) in &[
+            ("30", 30),
+            ("30s", 30),
+            ("5m", 300),
+            ("2h", 7200),
+            ("1d", 86400),
+            ("0", 0),
+        ] {
+            assert_eq!(*simple, Delay::parse(s).unwrap().simple(), "{}", s);
+        }
+
+        // 原来的 trans() 支持的格式
+        for (s, simple) in &[
+            ("15", 15),
+            ("15s", 15),
+            ("5m", 300),
+            ("2h", 7200),
+            ("1d", 86400),
+        ] {
+            assert_eq!(Delay::from(*simple), Delay::parse(s).unwrap(), "{}", s);
+        }
+        // 与服务端相同，单位只能是小写，trans() 接受的 "15S" 不再有效
+        for s in &["15S", "5M", "2H", "1D"] {
+            assert!(Delay::parse(s).is_err(), "{}", s);
+        }
+
+        let delay =
+            Delay::parse("1m;50s/1-7,00:00-24:00;0/6,9:00-18:30;wd1-5h9-18/3;md1,15h0m0").unwrap();
+        assert_eq!(
+            vec![
+                FlexibleInterval {
+                    delay: 50,
+                    period: TimePeriod {
+                        start_day: 1,
+                        end_day: 7,
+                        start_time: 0,
+                        end_time: 86400
+                    }
+                },
+                FlexibleInterval {
+                    delay: 0,
+                    period: TimePeriod {
+                        start_day: 6,
+                        end_day: 6,
+                        start_time: 9 * 3600,
+                        end_time: 18 * 3600 + 1800
+                    }
+                },
+            ],
+            delay.flexible()
+        );
+        assert_eq!(2, delay.scheduling().len());
+        assert_eq!(
+            "60;50/1-7,00:00-24:00;0/6,09:00-18:30;wd1-5h9-18/3;md1,15h0m0",
+            delay.to_string()
+        );
+
+        let invalid = [
+            "",
+            "-1",
+            "30x",
+            "1w",
+            "2d",
+            "86401",
+            "1.5m",
+            "{$DELAY}",
+            "30s;",
+            "30s;50s",
+            "30s;50s/",
+            "30s;1w/1-7,00:00-24:00",
+            "30s;50s/8,00:00-24:00",
+            "30s;50s/0,00:00-24:00",
+            "30s;50s/5-1,00:00-24:00",
+            "30s;50s/1-7,00:00-24:01",
+            "30s;50s/1,10:00-09:00",
+            "30s;50s/1,9:0-10:00",
+            "30s;50s/1,09:00",
+            "30s;50s/1-7",
+            "30s;wd0",
+            "30s;wd1-8",
+            "30s;wd12",
+            "30s;h24",
+            "30s;h",
+            "30s;h001",
+            "30s;md0",
+            "30s;md32",
+            "30s;m5-1",
+            "30s;m0h9",
+            "30s;h9h10",
+            "30s;wd1md1",
+            "30s;s/0",
+            "30s;s/60",
+            "30s;m1,",
+            "30s;x1",
+            "30s;h9-",
+            "30s;h-9",
+            "30s;m/5/5",
+        ];
+        for s in invalid.iter() {
+            assert!(Delay::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_delay_at() {
+        let delay =
+            Delay::parse("10m;5m/1-7,00:00-24:00;2m/1-5,09:00-18:00;0/6-7,12:00-13:00").unwrap();
+        assert_eq!(120, delay.delay_at(MONDAY + 10 * HOUR, &Utc));
+        assert_eq!(300, delay.delay_at(MONDAY + 18 * HOUR, &Utc));
+        assert_eq!(300, delay.delay_at(SUNDAY - 2 * HOUR, &Utc));
+        assert_eq!(0, delay.delay_at(SUNDAY, &Utc));
+        assert_eq!(600, Delay::parse("10m").unwrap().delay_at(SUNDAY, &Utc));
+        // 时区影响时间段
+        let tz = FixedOffset::east_opt(8 * 3600).unwrap();
+        assert_eq!(120, delay.delay_at(MONDAY + 2 * HOUR, &tz));
+    }
+
+    #[test]
+    fn test_next_check_simple() {
+        assert_eq!(Some(1030), next("30s", 10, 1000));
+        assert_eq!(Some(1060), next("30s", 10, 1030));
+        assert_eq!(Some(1039), next("30s", 49, 1030));
+        // 偏移等于当前时间时推迟一个间隔
+        assert_eq!(Some(SUNDAY + 60), next("1m", 100, SUNDAY));
+        assert_eq!(Some(SUNDAY + 20), next("1m", 0, SUNDAY));
+        assert_eq!(Some(MONDAY + HOUR), next("1d", 3600, SUNDAY));
+        assert_eq!(None, next("0", 1, SUNDAY));
+    }
+
+    #[test]
+    fn test_next_check_flexible() {
+        let delay = "1h;1m/1-5,09:00-18:00";
+        // 周一 08:30，时间段开始后按新间隔检查
+        assert_eq!(
+            Some(MONDAY + 9 * HOUR),
+            next(delay, 0, MONDAY + 8 * HOUR + 1800)
+        );
+        assert_eq!(
+            Some(MONDAY + 9 * HOUR + 7),
+            next(delay, 7, MONDAY + 8 * HOUR + 1800)
+        );
+        // 时间段内
+        assert_eq!(
+            Some(MONDAY + 10 * HOUR + 7),
+            next(delay, 7, MONDAY + 10 * HOUR)
+        );
+        let end = MONDAY + 18 * HOUR;
+        assert_eq!(Some(end - 15), next(delay, 45, end - 30));
+        assert_eq!(Some(end - 1), next(delay, 59, end - 30));
+        // 超出时间段时按默认间隔
+        assert_eq!(Some(end + 45), next(delay, 45, end - 10));
+        assert_eq!(Some(end + HOUR + 45), next(delay, 45, end + 45));
+
+        // 周末不检查
+        let delay = "30s;0/6-7,00:00-24:00";
+        let saturday = MONDAY - 2 * 86400 + 10 * HOUR;
+        assert_eq!(Some(MONDAY), next(delay, 0, saturday));
+        assert_eq!(Some(MONDAY + 13), next(delay, 13, saturday));
+        assert_eq!(Some(MONDAY + 13), next(delay, 13, SUNDAY));
+        // 检查时间恰好是时间段开始时，按新的时间段计算
+        assert_eq!(Some(MONDAY), next(delay, 0, saturday - 10 * HOUR - 10));
+        assert_eq!(
+            Some(saturday - 10 * HOUR - 1),
+            next(delay, 29, saturday - 10 * HOUR - 10)
+        );
+
+        // 只在时间段内检查
+        let delay = "0;5m/1-5,09:00-18:00";
+        assert_eq!(Some(MONDAY + 9 * HOUR + 42), next(delay, 42, SUNDAY));
+        assert_eq!(
+            Some(MONDAY + 9 * HOUR + 342),
+            next(delay, 42, MONDAY + 9 * HOUR + 42)
+        );
+        assert_eq!(
+            Some(MONDAY + 33 * HOUR + 42),
+            next(delay, 42, MONDAY + 18 * HOUR - 1)
+        );
+
+        assert_eq!(None, next("0;0/1-7,00:00-24:00", 1, SUNDAY));
+    }
+
+    #[test]
+    fn test_next_check_scheduling() {
+        let midnight = SUNDAY - (12 * HOUR + 26 * 60 + 40);
+        let cases: &[(&str, i64)] = &[
+            ("0;s0-59", SUNDAY + 1),
+            ("0;s/10", SUNDAY + 10),
+            ("0;m/5s30", midnight + 12 * HOUR + 30 * 60 + 30),
+            ("0;m/20s15", midnight + 12 * HOUR + 40 * 60 + 15),
+            ("0;h9-17/4", midnight + 13 * HOUR),
+            ("0;h9;h21", midnight + 21 * HOUR),
+            ("0;h12m26s40", midnight + 36 * HOUR + 26 * 60 + 40),
+            ("0;h9m/30", MONDAY + 9 * HOUR),
+            ("0;wd1-5h9", MONDAY + 9 * HOUR),
+            ("0;wd6", MONDAY + 5 * 86400),
+            ("0;wd7", midnight + 7 * 86400),
+            // 2020-10-05，十月第一个周一
+            ("0;md1-7wd1", 1_601_856_000),
+            // 2020-10-31
+            ("0;md31", 1_604_102_400),
+            // 2021-05-31，第一个周一的 31 日
+            ("0;md31wd1", 1_622_419_200),
+            // 调度间隔早于默认间隔时使用调度间隔
+            ("1h;m/15", midnight + 12 * HOUR + 30 * 60),
+            ("1m;h13", SUNDAY + 20),
+            ("0;m0-5", midnight + 13 * HOUR),
+            ("0;m1,3,5", midnight + 13 * HOUR + 60),
+            ("0;m0-30/20", midnight + 13 * HOUR),
+            ("0;m/30s/15", midnight + 12 * HOUR + 30 * 60),
+            ("0;h/6", midnight + 18 * HOUR),
+            ("0;h1-10/3", midnight + 25 * HOUR),
+            ("0;wd1,3,5h9", MONDAY + 9 * HOUR),
+            // 2020-10-01 09:30
+            ("0;md1-5h9m30", 1_601_544_600),
+            // 多个调度间隔取最早的
+            ("0;wd1h9;wd7h13", midnight + 13 * HOUR),
+        ];
+        for (delay, expected) in cases {
+            assert_eq!(Some(*expected), next(delay, 0, SUNDAY), "{}", delay);
+        }
+
+        let s = SchedulingInterval::parse("wd1-5h9").unwrap();
+        assert_eq!(Some(MONDAY + 9 * HOUR), s.next_after(SUNDAY, &Utc));
+        assert_eq!(
+            Some(MONDAY + 33 * HOUR),
+            s.next_after(MONDAY + 9 * HOUR, &Utc)
+        );
+        let tz = FixedOffset::east_opt(8 * 3600).unwrap();
+        assert_eq!(Some(MONDAY + HOUR), s.next_after(SUNDAY, &tz));
+    }
+
+    #[test]
+    fn test_next_check_dst() {
+        let local = |t: &str| {
+            Cet.from_local_datetime(&NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S").unwrap())
+        };
+        assert_eq!(
+            LocalResult::None,
+            local("2021-03-28 02:30:00").map(|t| t.timestamp())
+        );
+        assert_eq!(
+            LocalResult::Ambiguous(AUTUMN - 1800, AUTUMN + 1800),
+            local("2021-10-31 02:30:00").map(|t| t.timestamp())
+        );
+
+        // 跳过的 02:30 不检查，到下一天
+        let s = SchedulingInterval::parse("h2m30").unwrap();
+        assert_eq!(
+            Some(SPRING + 86400 - 1800),
+            s.next_after(SPRING - 12 * HOUR, &Cet)
+        );
+        // 跳过的一小时之后继续查找同一天的时间
+        let s = SchedulingInterval::parse("s/10").unwrap();
+        assert_eq!(Some(SPRING), s.next_after(SPRING - 5, &Cet));
+        let s = SchedulingInterval::parse("h2-3").unwrap();
+        assert_eq!(Some(SPRING), s.next_after(SPRING - HOUR, &Cet));
+
+        // 重复的 02:30 只检查第一次
+        let s = SchedulingInterval::parse("h2m30").unwrap();
+        assert_eq!(Some(AUTUMN - 1800), s.next_after(AUTUMN - 12 * HOUR, &Cet));
+        assert_eq!(
+            Some(AUTUMN + 86400 + 1800),
+            s.next_after(AUTUMN - 1800, &Cet)
+        );
+
+        // 灵活间隔的时间段按切换后的偏移计算：周一 00:00 CEST 为 22:00 UTC
+        let delay = Delay::parse("0;5m/1,00:00-24:00").unwrap();
+        let monday = SPRING + 21 * HOUR;
+        assert_eq!(
+            Some(monday + 42),
+            delay.next_check_tz(42, SPRING + 9 * HOUR, &Cet)
+        );
+        assert_eq!(
+            Some(AUTUMN + 22 * HOUR + 42),
+            delay.next_check_tz(42, AUTUMN + 9 * HOUR, &Cet)
+        );
+        assert_eq!(0, delay.delay_at(SPRING - 1, &Cet));
+        assert_eq!(300, delay.delay_at(monday, &Cet));
+        assert_eq!(0, delay.delay_at(monday - 1, &Cet));
+    }
+
+    #[test]
+    fn test_delay_is_active() {
+        assert!(Delay::from(30).is_active());
+        assert!(!Delay::from(0).is_active());
+        assert_eq!(Delay::parse("30s").unwrap(), Delay::from(30));
+        for s in &["0;5m/1-5,09:00-18:00", "0;wd1-5h9", "1m"] {
+            assert!(Delay::parse(s).unwrap().is_active(), "{}", s);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! zabbix 通信协议错误类型
+//!
+//! 通过 `failure::Error::downcast_ref::<ProtocolError>()` 区分具体错误。
+
+use failure::Fail;
+use std::fmt;
+use std::io;
+
+/// 通信协议错误
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// 对端在数据包未接收完整时关闭了连接
+    UnexpectedEof,
+    /// 包头不是以 `ZBXD` 开始
+    InvalidMagic,
+    /// 未知或缺少协议标志位
+    InvalidFlags(u8),
+    /// 数据长度超过允许的最大值
+    PacketTooLarge(u64, u64),
+    /// 解压后的数据长度与包头不符
+    LengthMismatch(u64, u64),
+    /// 连接、读取或写入超时
+    Timeout,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedEof => {
+                write!(f, "connection closed before the packet was complete")
+            }
+            ProtocolError::InvalidMagic => write!(f, "packet header invalid"),
+            ProtocolError::InvalidFlags(flags) => write!(f, "packet flags invalid: {:#04x}", flags),
+            ProtocolError::PacketTooLarge(size, max) => {
+                write!(f, "packet too large: {} bytes, max {}", size, max)
+            }
+            ProtocolError::LengthMismatch(expected, got) => write!(
+                f,
+                "uncompressed length mismatch: expected {}, got {}",
+                expected, got
+            ),
+            ProtocolError::Timeout => write!(f, "operation timed out"),
+        }
+    }
+}
+
+impl Fail for ProtocolError {}
+
+///
+/// 将超时和连接提前关闭转换为对应的协议错误
+///
+pub(crate) fn from_io(e: io::Error) -> failure::Error {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => ProtocolError::UnexpectedEof.into(),
+        // 设置了读写超时的 socket 在 unix 上返回 WouldBlock
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ProtocolError::Timeout.into(),
+        _ => e.into(),
+    }
+}
@@ -0,0 +1,215 @@
+//! ZBXD 数据包的读写
+//!
+//! 循环读取直到收到完整的包头和数据段，写入时保证数据全部发出。
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::prelude::*;
+
+use super::error::{from_io, ProtocolError};
+use super::header::ZbxHeader;
+use super::Result;
+
+/// 按 zabbix 协议收发数据包的流
+#[derive(Debug)]
+pub struct ZbxStream<S> {
+    inner: S,
+    compress_threshold: Option<usize>,
+    max_packet_size: u64,
+}
+
+impl<S> ZbxStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            compress_threshold: None,
+            max_packet_size: ZbxHeader::DEFAULT_MAX_SIZE,
+        }
+    }
+
+    ///
+    /// 数据长度超过 threshold 字节时压缩发送
+    ///
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_threshold = Some(threshold);
+        self
+    }
+
+    ///
+    /// 设置接收数据包的最大长度
+    ///
+    pub fn with_max_packet_size(mut self, size: u64) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> ZbxStream<S> {
+    ///
+    /// 读取一个完整的数据包，返回（解压后的）数据段
+    ///
+    pub fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let header = self.read_header()?;
+        let mut data = Vec::new();
+        (&mut self.inner)
+            .take(header.data_length)
+            .read_to_end(&mut data)
+            .map_err(from_io)?;
+        if (data.len() as u64) < header.data_length {
+            return Err(ProtocolError::UnexpectedEof.into());
+        }
+
+        if header.is_compressed() {
+            return decompress(&data, header.reserved);
+        }
+        Ok(data)
+    }
+
+    ///
+    /// 读取并解析包头
+    ///
+    pub fn read_header(&mut self) -> Result<ZbxHeader> {
+        let mut buf = [0; ZbxHeader::LARGE_SIZE];
+        read_exact(&mut self.inner, &mut buf[..ZbxHeader::PREFIX_SIZE])?;
+        let flags = ZbxHeader::parse_prefix(&buf)?;
+
+        let size = ZbxHeader::header_size(flags);
+        read_exact(&mut self.inner, &mut buf[ZbxHeader::PREFIX_SIZE..size])?;
+        ZbxHeader::parse(&buf[..size], self.max_packet_size)
+    }
+}
+
+impl<S: Write> ZbxStream<S> {
+    ///
+    /// 写入一个完整的数据包
+    ///
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<()> {
+        let packet = encode_packet(data, self.compress_threshold)?;
+        self.inner.write_all(&packet).map_err(from_io)?;
+        self.inner.flush().map_err(from_io)?;
+        Ok(())
+    }
+}
+
+fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> Result<()> {
+    r.read_exact(buf).map_err(from_io)
+}
+
+///
+/// 生成数据包，数据长度超过 compress_threshold 时压缩
+///
+pub(crate) fn encode_packet(data: &[u8], compress_threshold: Option<usize>) -> Result<Vec<u8>> {
+    let (header, payload) = match compress_threshold {
+        Some(threshold) if data.len() > threshold => {
+            let payload = compress(data)?;
+            (
+                ZbxHeader::for_payload(payload.len(), Some(data.len())),
+                payload,
+            )
+        }
+        _ => (ZbxHeader::for_payload(data.len(), None), data.to_vec()),
+    };
+
+    let mut packet: Vec<u8> = Vec::with_capacity(header.size() + payload.len());
+    packet.extend(header.encode());
+    packet.extend(payload);
+    Ok(packet)
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+pub(crate) fn decompress(data: &[u8], size: u64) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(data.len());
+    // 最多读取 size + 1 字节，防止压缩炸弹
+    ZlibDecoder::new(data)
+        .take(size + 1)
+        .read_to_end(&mut result)?;
+    if result.len() as u64 != size {
+        return Err(ProtocolError::LengthMismatch(size, result.len() as u64).into());
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// 每次只返回一个字节的读取器，模拟慢速网络
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    fn kind(e: &failure::Error) -> &ProtocolError {
+        e.downcast_ref::<ProtocolError>().unwrap()
+    }
+
+    #[test]
+    fn test_read_write_frame() {
+        let data = "data".repeat(100);
+        for threshold in &[None, Some(16)] {
+            let mut stream = ZbxStream::new(Vec::new());
+            if let Some(t) = threshold {
+                stream = stream.with_compression(*t);
+            }
+            stream.write_frame(data.as_bytes()).unwrap();
+            let packet = stream.into_inner();
+
+            let mut stream = ZbxStream::new(Trickle(&packet));
+            assert_eq!(data.as_bytes(), &stream.read_frame().unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn test_read_frame_errors() {
+        let packet = encode_packet(b"data", None).unwrap();
+
+        let e = ZbxStream::new(&packet[..3]).read_frame().unwrap_err();
+        assert!(matches!(kind(&e), ProtocolError::UnexpectedEof));
+
+        let e = ZbxStream::new(&packet[..15]).read_frame().unwrap_err();
+        assert!(matches!(kind(&e), ProtocolError::UnexpectedEof));
+
+        let e = ZbxStream::new(&b"HTTP/1.1 400\r\n"[..])
+            .read_frame()
+            .unwrap_err();
+        assert!(matches!(kind(&e), ProtocolError::InvalidMagic));
+
+        let e = ZbxStream::new(&b"ZBXD\x08\x04\0\0\0\0\0\0\0data"[..])
+            .read_frame()
+            .unwrap_err();
+        assert!(matches!(kind(&e), ProtocolError::InvalidFlags(0x08)));
+
+        let e = ZbxStream::new(&packet[..])
+            .with_max_packet_size(3)
+            .read_frame()
+            .unwrap_err();
+        assert!(matches!(kind(&e), ProtocolError::PacketTooLarge(4, 3)));
+    }
+}
@@ -0,0 +1,188 @@
+//! 监控项处理
+//!
+//! `ItemRegistry` 按 key 名称保存处理函数，被动模式、主动模式和 proxy 本地采集共用。
+//! 处理函数接收解析后的 key，返回带类型的值，返回错误时监控项不支持，错误信息即为原因。
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::key::ItemKey;
+use super::Result;
+
+/// 日志监控项的值，lastlogsize 和 mtime 用于下次继续读取
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogValue {
+    pub value: String,
+    pub lastlogsize: u64,
+    pub mtime: i64,
+}
+
+/// 监控项的值
+#[derive(Debug, Clone, PartialEq)]
+pub enum ItemValue {
+    Unsigned(u64),
+    Float(f64),
+    Text(String),
+    Log(LogValue),
+}
+
+impl fmt::Display for ItemValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ItemValue::Unsigned(v) => write!(f, "{}", v),
+            // 与 zabbix agent 相同，浮点数保留 6 位小数
+            ItemValue::Float(v) => write!(f, "{:.6}", v),
+            ItemValue::Text(v) => write!(f, "{}", v),
+            ItemValue::Log(v) => write!(f, "{}", v.value),
+        }
+    }
+}
+
+impl From<u64> for ItemValue {
+    fn from(v: u64) -> Self {
+        ItemValue::Unsigned(v)
+    }
+}
+
+impl From<f64> for ItemValue {
+    fn from(v: f64) -> Self {
+        ItemValue::Float(v)
+    }
+}
+
+impl From<String> for ItemValue {
+    fn from(v: String) -> Self {
+        ItemValue::Text(v)
+    }
+}
+
+impl<'a> From<&'a str> for ItemValue {
+    fn from(v: &'a str) -> Self {
+        ItemValue::Text(String::from(v))
+    }
+}
+
+impl From<LogValue> for ItemValue {
+    fn from(v: LogValue) -> Self {
+        ItemValue::Log(v)
+    }
+}
+
+/// 监控项处理
+pub trait ItemHandler: Send + Sync {
+    fn get(&self, key: &ItemKey) -> Result<ItemValue>;
+}
+
+impl<F> ItemHandler for F
+where
+    F: Fn(&ItemKey) -> Result<ItemValue> + Send + Sync,
+{
+    fn get(&self, key: &ItemKey) -> Result<ItemValue> {
+        self(key)
+    }
+}
+
+/// 按 key 名称注册的监控项处理
+#[derive(Clone, Default)]
+pub struct ItemRegistry {
+    handlers: HashMap<String, Arc<dyn ItemHandler>>,
+}
+
+impl fmt::Debug for ItemRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = self.names();
+        names.sort_unstable();
+        f.debug_tuple("ItemRegistry").field(&names).finish()
+    }
+}
+
+impl ItemRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// 注册处理函数，name 为 key 的名称（不含参数），返回值可以转换为 `ItemValue`
+    ///
+    pub fn register<F, T>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&ItemKey) -> Result<T> + Send + Sync + 'static,
+        T: Into<ItemValue>,
+    {
+        self.register_handler(name, move |key: &ItemKey| f(key).map(Into::into));
+    }
+
+    ///
+    /// 注册实现了 `ItemHandler` 的处理，同名的处理会被替换
+    ///
+    pub fn register_handler<H>(&mut self, name: &str, handler: H)
+    where
+        H: ItemHandler + 'static,
+    {
+        self.handlers.insert(String::from(name), Arc::new(handler));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.handlers.keys().map(|k| k.as_str()).collect()
+    }
+
+    ///
+    /// 解析 key 并调用对应的处理
+    ///
+    pub fn get(&self, key: &str) -> Result<ItemValue> {
+        let key = ItemKey::parse(key).map_err(|_| format_err!("Invalid item key format."))?;
+        self.get_key(&key)
+    }
+
+    pub fn get_key(&self, key: &ItemKey) -> Result<ItemValue> {
+        match self.handlers.get(key.name()) {
+            Some(h) => h.get(key),
+            None => Err(format_err!("Unsupported item key.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(u64);
+
+    impl ItemHandler for Counter {
+        fn get(&self, key: &ItemKey) -> Result<ItemValue> {
+            let n: u64 = key.param(0).unwrap_or("0").parse()?;
+            Ok(ItemValue::Unsigned(self.0 + n))
+        }
+    }
+
+    #[test]
+    fn test_item_registry() {
+        let mut registry = ItemRegistry::new();
+        registry.register("app.version", |_| Ok("1.0"));
+        registry.register("app.load", |_| Ok(0.5));
+        registry.register("app.fail", |_| -> Result<u64> {
+            Err(format_err!("no data"))
+        });
+        registry.register_handler("app.count", Counter(10));
+
+        assert_eq!(ItemValue::from("1.0"), registry.get("app.version").unwrap());
+        assert_eq!("0.500000", registry.get("app.load").unwrap().to_string());
+        assert_eq!("15", registry.get("app.count[5]").unwrap().to_string());
+        assert!(registry.get("app.count[x]").is_err());
+        assert_eq!("no data", registry.get("app.fail").unwrap_err().to_string());
+        assert_eq!(
+            "Unsupported item key.",
+            registry.get("app.none").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Invalid item key format.",
+            registry.get("app.count[").unwrap_err().to_string()
+        );
+        assert!(registry.contains("app.count"));
+        assert_eq!(4, registry.names().len());
+    }
+}
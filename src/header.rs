@@ -0,0 +1,207 @@
+//! zabbix 通信协议数据包头
+//!
+//! `ZBXD` + 标志位(1 字节) + 数据长度 + 未压缩数据长度，
+//! 普通包两个长度字段各占 4 字节，大数据包 (0x04) 各占 8 字节。
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::error::ProtocolError;
+use super::Result;
+
+/// ZBXD 数据包头
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZbxHeader {
+    pub flags: u8,
+    /// 数据段长度（压缩时为压缩后的长度）
+    pub data_length: u64,
+    /// 未压缩数据长度，未压缩时为 0
+    pub reserved: u64,
+}
+
+impl ZbxHeader {
+    pub const MAGIC: &'static [u8; 4] = b"ZBXD";
+
+    /// 协议标志位
+    pub const FLAG_PROTOCOL: u8 = 0x01;
+    /// 压缩标志位，数据段为 zlib 压缩格式
+    pub const FLAG_COMPRESS: u8 = 0x02;
+    /// 大数据包标志位，长度字段为 64 位
+    pub const FLAG_LARGE: u8 = 0x04;
+
+    /// `ZBXD` 与标志位的长度
+    pub const PREFIX_SIZE: usize = 5;
+    pub const SIZE: usize = 13;
+    pub const LARGE_SIZE: usize = 21;
+
+    /// 默认允许的最大数据包长度
+    pub const DEFAULT_MAX_SIZE: u64 = 128 * 1024 * 1024;
+
+    pub fn new(flags: u8, data_length: u64, reserved: u64) -> Self {
+        Self {
+            flags,
+            data_length,
+            reserved,
+        }
+    }
+
+    ///
+    /// 根据数据长度生成包头，超过 u32 范围时自动使用大数据包格式
+    ///
+    pub fn for_payload(data_length: usize, uncompressed: Option<usize>) -> Self {
+        let mut flags = Self::FLAG_PROTOCOL;
+        let reserved = match uncompressed {
+            Some(size) => {
+                flags |= Self::FLAG_COMPRESS;
+                size as u64
+            }
+            None => 0,
+        };
+
+        let data_length = data_length as u64;
+        if data_length > u64::from(u32::MAX) || reserved > u64::from(u32::MAX) {
+            flags |= Self::FLAG_LARGE;
+        }
+        Self::new(flags, data_length, reserved)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & Self::FLAG_COMPRESS != 0
+    }
+
+    pub fn is_large(&self) -> bool {
+        self.flags & Self::FLAG_LARGE != 0
+    }
+
+    /// 包头长度
+    pub fn size(&self) -> usize {
+        Self::header_size(self.flags)
+    }
+
+    /// 根据标志位计算包头长度
+    pub fn header_size(flags: u8) -> usize {
+        if flags & Self::FLAG_LARGE != 0 {
+            Self::LARGE_SIZE
+        } else {
+            Self::SIZE
+        }
+    }
+
+    ///
+    /// 检查 `ZBXD` 和标志位，返回标志位
+    ///
+    pub fn parse_prefix(buf: &[u8]) -> Result<u8> {
+        if buf.len() < Self::PREFIX_SIZE {
+            return Err(ProtocolError::UnexpectedEof.into());
+        }
+        if Self::MAGIC != &buf[..4] {
+            return Err(ProtocolError::InvalidMagic.into());
+        }
+
+        let flags = buf[4];
+        let known = Self::FLAG_PROTOCOL | Self::FLAG_COMPRESS | Self::FLAG_LARGE;
+        if flags & Self::FLAG_PROTOCOL == 0 || flags & !known != 0 {
+            return Err(ProtocolError::InvalidFlags(flags).into());
+        }
+        Ok(flags)
+    }
+
+    ///
+    /// 解析完整包头，并检查长度不超过 max_size
+    ///
+    pub fn parse(buf: &[u8], max_size: u64) -> Result<Self> {
+        let flags = Self::parse_prefix(buf)?;
+        let size = Self::header_size(flags);
+        if buf.len() < size {
+            return Err(ProtocolError::UnexpectedEof.into());
+        }
+
+        let (data_length, reserved) = if flags & Self::FLAG_LARGE != 0 {
+            (
+                LittleEndian::read_u64(&buf[5..13]),
+                LittleEndian::read_u64(&buf[13..21]),
+            )
+        } else {
+            (
+                u64::from(LittleEndian::read_u32(&buf[5..9])),
+                u64::from(LittleEndian::read_u32(&buf[9..13])),
+            )
+        };
+
+        let header = Self::new(flags, data_length, reserved);
+        header.check_size(max_size)?;
+        Ok(header)
+    }
+
+    ///
+    /// 检查数据长度和未压缩数据长度
+    ///
+    pub fn check_size(&self, max_size: u64) -> Result<()> {
+        if self.data_length > max_size || (self.is_compressed() && self.reserved > max_size) {
+            let size = self.data_length.max(self.reserved);
+            return Err(ProtocolError::PacketTooLarge(size, max_size).into());
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; self.size()];
+        buf[..4].copy_from_slice(Self::MAGIC);
+        buf[4] = self.flags;
+        if self.is_large() {
+            LittleEndian::write_u64(&mut buf[5..13], self.data_length);
+            LittleEndian::write_u64(&mut buf[13..21], self.reserved);
+        } else {
+            LittleEndian::write_u32(&mut buf[5..9], self.data_length as u32);
+            LittleEndian::write_u32(&mut buf[9..13], self.reserved as u32);
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_flags() {
+        let max = ZbxHeader::DEFAULT_MAX_SIZE;
+        for flags in 0..=0xffu8 {
+            let mut buf = vec![0; ZbxHeader::LARGE_SIZE];
+            buf[..4].copy_from_slice(ZbxHeader::MAGIC);
+            buf[4] = flags;
+            buf[5] = 4;
+
+            let result = ZbxHeader::parse(&buf, max);
+            match flags {
+                0x01 | 0x03 | 0x05 | 0x07 => {
+                    let h = result.unwrap();
+                    assert_eq!(h.flags, flags);
+                    assert_eq!(h.data_length, 4);
+                    assert_eq!(h.size(), ZbxHeader::header_size(flags));
+                }
+                _ => assert!(result.is_err(), "flags {:#04x}", flags),
+            }
+        }
+
+        assert!(ZbxHeader::parse(b"ZBXE\x01\x04\0\0\0\0\0\0\0", max).is_err());
+        assert!(ZbxHeader::parse(b"ZBXD\x05\x04\0\0\0\0\0\0\0", max).is_err());
+    }
+
+    #[test]
+    fn test_header_encode() {
+        let h = ZbxHeader::for_payload(4, None);
+        assert_eq!(b"ZBXD\x01\x04\0\0\0\0\0\0\0", &h.encode()[..]);
+
+        let h = ZbxHeader::for_payload(10, Some(20));
+        assert_eq!(b"ZBXD\x03\x0a\0\0\0\x14\0\0\0", &h.encode()[..]);
+        assert_eq!(h, ZbxHeader::parse(&h.encode(), 100).unwrap());
+
+        let h = ZbxHeader::new(0x07, 10, 1 << 33);
+        assert_eq!(ZbxHeader::LARGE_SIZE, h.encode().len());
+        assert_eq!(h, ZbxHeader::parse(&h.encode(), u64::MAX).unwrap());
+        assert!(ZbxHeader::parse(&h.encode(), 1 << 32).is_err());
+
+        let h = ZbxHeader::new(0x01, 101, 0);
+        assert!(ZbxHeader::parse(&h.encode(), 100).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! `zabbix_sender -i` 输入文件格式
+//!
+//! 每行 `<hostname> <key> [<timestamp> [<ns>]] <value>`，字段之间以空格或制表符分隔，
+//! `-` 表示默认主机。字段可以用双引号括起来，引号内支持 `\"`、`\\` 和 `\n` 转义；
+//! 未加引号的值取到行尾。
+
+use failure::Fail;
+use std::fmt;
+
+use super::request::ZabbixMetric;
+use super::Result;
+
+/// 输入文件解析错误，包含行号
+#[derive(Debug)]
+pub struct LineError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.reason)
+    }
+}
+
+impl Fail for LineError {}
+
+/// 输入文件格式，对应 `zabbix_sender` 的 `-s`、`-T` 和 `-N` 参数
+#[derive(Debug, Clone, Default)]
+pub struct InputFormat {
+    host: Option<String>,
+    with_timestamps: bool,
+    with_ns: bool,
+}
+
+impl InputFormat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// 主机名为 `-` 时使用的默认主机
+    ///
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(String::from(host));
+        self
+    }
+
+    ///
+    /// 每行包含时间戳 (`-T`)
+    ///
+    pub fn with_timestamps(mut self) -> Self {
+        self.with_timestamps = true;
+        self
+    }
+
+    ///
+    /// 每行包含时间戳和纳秒 (`-T -N`)
+    ///
+    pub fn with_ns(mut self) -> Self {
+        self.with_timestamps = true;
+        self.with_ns = true;
+        self
+    }
+
+    ///
+    /// 解析一行，line 为行号，用于错误信息
+    ///
+    pub fn parse_line(&self, line: usize, input: &str) -> Result<ZabbixMetric> {
+        let error = |reason: &str| LineError {
+            line,
+            reason: String::from(reason),
+        };
+        let input = input.trim_end_matches(['\r', '\n']);
+
+        let (host, p) = get_string(input).ok_or_else(|| error("invalid 'Hostname' value"))?;
+        if host.is_empty() {
+            return Err(error("'Hostname' required").into());
+        }
+        let host = if host == "-" {
+            match &self.host {
+                Some(h) => h.clone(),
+                None => {
+                    return Err(error(
+                        "'-' encountered as 'Hostname', but no default hostname was specified",
+                    )
+                    .into())
+                }
+            }
+        } else {
+            host
+        };
+
+        let (key, mut p) = get_string(p).ok_or_else(|| error("invalid 'Key' value"))?;
+        if key.is_empty() {
+            return Err(error("'Key' required").into());
+        }
+
+        let mut clock = None;
+        let mut ns = None;
+        if self.with_timestamps {
+            let (ts, rest) = get_string(p).ok_or_else(|| error("'Timestamp' required"))?;
+            clock =
+                Some(parse_uint31(&ts).ok_or_else(|| error("invalid 'Timestamp' value detected"))?);
+            p = rest;
+        }
+        if self.with_ns {
+            let (value, rest) = get_string(p).ok_or_else(|| error("'Timestamp' required"))?;
+            ns = match value.parse::<i64>() {
+                Ok(v) if (0..=999_999_999).contains(&v) => Some(v),
+                _ => return Err(error("invalid 'Timestamp' value detected").into()),
+            };
+            p = rest;
+        }
+
+        let value = if !p.is_empty() && !p.starts_with('"') {
+            String::from(p)
+        } else {
+            match get_string(p) {
+                Some((v, rest)) if !v.is_empty() || p.starts_with('"') => {
+                    if !rest.is_empty() {
+                        return Err(error("too many parameters").into());
+                    }
+                    v
+                }
+                _ => return Err(error("'Key value' required").into()),
+            }
+        };
+
+        let metric = match clock {
+            Some(clock) => ZabbixMetric::with_clock(&host, &key, &value, clock, ns),
+            None => ZabbixMetric::new(&host, &key, &value),
+        };
+        Ok(metric)
+    }
+}
+
+fn parse_uint31(input: &str) -> Option<i64> {
+    match input.parse::<i64>() {
+        Ok(v) if (0..=i64::from(i32::MAX)).contains(&v) => Some(v),
+        _ => None,
+    }
+}
+
+///
+/// 读取一个字段，返回字段内容和跳过分隔符后的剩余部分，引号不匹配时返回 None
+///
+fn get_string(input: &str) -> Option<(String, &str)> {
+    let is_space = |c: char| c == ' ' || c == '\t';
+    let p = input.trim_start_matches(is_space);
+    let mut result = String::new();
+
+    if let Some(quoted) = p.strip_prefix('"') {
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    let rest = &quoted[i + 1..];
+                    if !rest.is_empty() && !rest.starts_with(is_space) {
+                        return None;
+                    }
+                    return Some((result, rest.trim_start_matches(is_space)));
+                }
+                '\\' => match chars.clone().next() {
+                    Some((_, e @ '"')) | Some((_, e @ '\\')) => {
+                        result.push(e);
+                        chars.next();
+                    }
+                    Some((_, 'n')) => {
+                        result.push('\n');
+                        chars.next();
+                    }
+                    _ => result.push(c),
+                },
+                _ => result.push(c),
+            }
+        }
+        // 缺少结束引号
+        return None;
+    }
+
+    match p.find(is_space) {
+        Some(i) => Some((String::from(&p[..i]), p[i..].trim_start_matches(is_space))),
+        None => Some((String::from(p), "")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line_error(e: failure::Error) -> LineError {
+        e.downcast::<LineError>().unwrap()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let format = InputFormat::new().with_host("default");
+
+        let m = format
+            .parse_line(1, "host key value with spaces\r\n")
+            .unwrap();
+        assert_eq!(
+            ("host", "key", "value with spaces"),
+            (&m.host[..], &m.key[..], &m.value[..])
+        );
+
+        let m = format
+            .parse_line(1, r#"- "key[a b,\"c\"]" "multi\nline \\ value""#)
+            .unwrap();
+        assert_eq!("default", m.host);
+        assert_eq!(r#"key[a b,"c"]"#, m.key);
+        assert_eq!("multi\nline \\ value", m.value);
+
+        let m = format.parse_line(1, "h\tk\t\"\"").unwrap();
+        assert_eq!("", m.value);
+
+        let e = line_error(format.parse_line(7, "host key").unwrap_err());
+        assert_eq!(7, e.line);
+        assert_eq!("[line 7] 'Key value' required", e.to_string());
+
+        let e = line_error(format.parse_line(2, r#"host key "a" b"#).unwrap_err());
+        assert_eq!("too many parameters", e.reason);
+
+        let e = line_error(format.parse_line(3, r#"host "key value"#).unwrap_err());
+        assert_eq!("invalid 'Key' value", e.reason);
+
+        let e = InputFormat::new().parse_line(4, "- key 1").unwrap_err();
+        assert!(e.to_string().contains("no default hostname"));
+    }
+
+    #[test]
+    fn test_parse_line_timestamps() {
+        let format = InputFormat::new().with_timestamps();
+        let m = format.parse_line(1, "host key 1429533600 5").unwrap();
+        assert_eq!(1429533600, m.clock());
+        assert_eq!(None, m.ns());
+        assert_eq!("5", m.value);
+
+        let e = line_error(format.parse_line(1, "host key -1 5").unwrap_err());
+        assert_eq!("invalid 'Timestamp' value detected", e.reason);
+
+        let format = InputFormat::new().with_ns();
+        let m = format
+            .parse_line(1, "host key 1429533600 123456789 5")
+            .unwrap();
+        assert_eq!(1429533600, m.clock());
+        assert_eq!(Some(123456789), m.ns());
+        assert!(format
+            .parse_line(1, "host key 1429533600 1000000000 5")
+            .is_err());
+    }
+}
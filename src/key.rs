@@ -0,0 +1,342 @@
+//! 监控项 key 的解析与生成
+//!
+//! key 由名称 (`[0-9a-zA-Z_.-]`) 和可选的参数列表组成，如 `key[a,[b,c],"d,e"]`。
+//! 参数可以是：
+//! - 未加引号的字符串，忽略开头的空格，到 `,` 或 `]` 结束；
+//! - 双引号括起的字符串，引号内 `\"` 表示双引号，可以包含 `,` 和 `]`；
+//! - 方括号括起的数组，数组不能嵌套。
+
+use failure::Fail;
+use std::fmt;
+use std::str::FromStr;
+
+use super::Result;
+
+/// key 解析错误，pos 为出错位置（字符序号）
+#[derive(Debug)]
+pub struct KeyError {
+    pub pos: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid item key at position {}: {}",
+            self.pos, self.reason
+        )
+    }
+}
+
+impl Fail for KeyError {}
+
+/// key 参数
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyParam {
+    Value(String),
+    Array(Vec<KeyParam>),
+}
+
+impl KeyParam {
+    /// 参数为字符串时返回其值
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            KeyParam::Value(v) => Some(v),
+            KeyParam::Array(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyParam {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyParam::Value(v) => write!(f, "{}", quote_param(v)),
+            KeyParam::Array(params) => write!(f, "[{}]", join_params(params)),
+        }
+    }
+}
+
+/// 监控项 key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ItemKey {
+    name: String,
+    params: Vec<KeyParam>,
+}
+
+impl ItemKey {
+    ///
+    /// 由名称和参数生成，`key[]` 对应一个空字符串参数
+    ///
+    pub fn new(name: &str, params: Vec<KeyParam>) -> Self {
+        let name = String::from(name);
+        Self { name, params }
+    }
+
+    pub fn parse(key: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: key.chars().collect(),
+            pos: 0,
+        };
+        Ok(parser.parse()?)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn params(&self) -> &[KeyParam] {
+        &self.params
+    }
+
+    ///
+    /// 第 i 个参数（从 0 开始），不存在或为数组时返回 None
+    ///
+    pub fn param(&self, i: usize) -> Option<&str> {
+        self.params.get(i).and_then(KeyParam::as_str)
+    }
+}
+
+impl FromStr for ItemKey {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for ItemKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.params.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}[{}]", self.name, join_params(&self.params))
+        }
+    }
+}
+
+fn join_params(params: &[KeyParam]) -> String {
+    params
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+///
+/// 必要时为参数加上引号，与 zabbix 的 quote_key_param 相同
+///
+fn quote_param(param: &str) -> String {
+    let quote = param.starts_with('"')
+        || param.starts_with(' ')
+        || param.starts_with('[')
+        || param.contains(',')
+        || param.contains(']');
+    if quote {
+        format!("\"{}\"", param.replace('"', "\\\""))
+    } else {
+        String::from(param)
+    }
+}
+
+fn is_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, pos: usize, reason: &str) -> std::result::Result<T, KeyError> {
+        Err(KeyError {
+            pos,
+            reason: String::from(reason),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn parse(&mut self) -> std::result::Result<ItemKey, KeyError> {
+        while self.peek().is_some_and(is_key_char) {
+            self.pos += 1;
+        }
+        if self.pos == 0 {
+            return self.error(0, "key name expected");
+        }
+        let name: String = self.chars[..self.pos].iter().collect();
+
+        let params = match self.peek() {
+            None => vec![],
+            Some('[') => {
+                self.pos += 1;
+                let params = self.parse_params(false)?;
+                if self.pos != self.chars.len() {
+                    return self.error(self.pos, "unexpected characters after ']'");
+                }
+                params
+            }
+            Some(c) => return self.error(self.pos, &format!("unexpected character '{}'", c)),
+        };
+
+        Ok(ItemKey { name, params })
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek() == Some(' ') {
+            self.pos += 1;
+        }
+    }
+
+    /// 解析到对应的 `]` 为止（包括 `]`）
+    fn parse_params(&mut self, nested: bool) -> std::result::Result<Vec<KeyParam>, KeyError> {
+        let mut params = vec![];
+        loop {
+            self.skip_spaces();
+            let param = match self.peek() {
+                Some('"') => {
+                    let param = self.parse_quoted()?;
+                    self.skip_spaces();
+                    param
+                }
+                Some('[') if nested => {
+                    return self.error(self.pos, "nested arrays are not allowed")
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    let param = KeyParam::Array(self.parse_params(true)?);
+                    self.skip_spaces();
+                    param
+                }
+                _ => self.parse_unquoted(),
+            };
+            params.push(param);
+
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(params);
+                }
+                None => return self.error(self.pos, "missing ']'"),
+                Some(c) => {
+                    return self.error(self.pos, &format!("unexpected character '{}'", c));
+                }
+            }
+        }
+    }
+
+    fn parse_quoted(&mut self) -> std::result::Result<KeyParam, KeyError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut value = String::new();
+        loop {
+            match self.peek() {
+                Some('\\') if self.chars.get(self.pos + 1) == Some(&'"') => {
+                    value.push('"');
+                    self.pos += 2;
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(KeyParam::Value(value));
+                }
+                Some(c) => {
+                    value.push(c);
+                    self.pos += 1;
+                }
+                None => return self.error(start, "unterminated quoted parameter"),
+            }
+        }
+    }
+
+    fn parse_unquoted(&mut self) -> KeyParam {
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            if c == ',' || c == ']' {
+                break;
+            }
+            value.push(c);
+            self.pos += 1;
+        }
+        KeyParam::Value(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(v: &str) -> KeyParam {
+        KeyParam::Value(v.to_string())
+    }
+
+    fn error(key: &str) -> KeyError {
+        ItemKey::parse(key)
+            .unwrap_err()
+            .downcast::<KeyError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_item_key_parse() {
+        let key = ItemKey::parse(r#"key[a,[b, c ],"d,e", "f\"g]\h",]"#).unwrap();
+        assert_eq!("key", key.name());
+        assert_eq!(
+            &[
+                value("a"),
+                KeyParam::Array(vec![value("b"), value("c ")]),
+                value("d,e"),
+                value(r#"f"g]\h"#),
+                value(""),
+            ],
+            key.params()
+        );
+        assert_eq!(Some("d,e"), key.param(2));
+        assert_eq!(None, key.param(1));
+        assert_eq!(None, key.param(5));
+
+        assert!(ItemKey::parse("agent.ping").unwrap().params().is_empty());
+        assert_eq!(&[value("")], ItemKey::parse("key[]").unwrap().params());
+        assert_eq!(
+            Some("{#FSNAME}"),
+            ItemKey::parse("vfs.fs.size[{#FSNAME},pfree]")
+                .unwrap()
+                .param(0)
+        );
+    }
+
+    #[test]
+    fn test_item_key_errors() {
+        assert_eq!(0, error("[a]").pos);
+        assert_eq!(3, error("key a").pos);
+        assert_eq!(6, error("key[a]b").pos);
+        assert_eq!(6, error(r#"key[a,"b]"#).pos);
+        assert_eq!(10, error(r#"key[a,"b" c]"#).pos);
+        assert_eq!(9, error("key[a,[b,[c]]]").pos);
+        assert_eq!(7, error("key[a,b").pos);
+        assert_eq!(
+            "invalid item key at position 7: missing ']'",
+            error("key[a,b").to_string()
+        );
+    }
+
+    #[test]
+    fn test_item_key_display() {
+        for key in &[
+            "agent.ping",
+            "key[]",
+            "key[a,,b]",
+            r#"key[a,[b,"c,d"],"e\"]",f"g]"#,
+            r#"key["[a]"," b"]"#,
+        ] {
+            assert_eq!(*key, ItemKey::parse(key).unwrap().to_string());
+        }
+
+        let key = ItemKey::new("key", vec![value("a]"), value("\"b")]);
+        assert_eq!(r#"key["a]","\"b"]"#, key.to_string());
+        assert_eq!(key, ItemKey::parse(&key.to_string()).unwrap());
+    }
+}
@@ -18,9 +18,40 @@ use failure::Error;
 
 type Result<T> = std::result::Result<T, Error>;
 
+mod error;
+pub use self::error::ProtocolError;
+
+mod header;
+pub use self::header::ZbxHeader;
+
+mod frame;
+pub use self::frame::ZbxStream;
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use self::tls::{TlsAcceptor, TlsConfig, TlsMode, TlsPsk, TlsStream};
+
 mod protocol;
 pub use self::protocol::ZabbixProtocol;
 
+mod key;
+pub use self::key::{ItemKey, KeyError, KeyParam};
+
+mod delay;
+pub use self::delay::{Delay, FlexibleInterval, SchedulingInterval, TimePeriod};
+
+mod handler;
+pub use self::handler::{ItemHandler, ItemRegistry, ItemValue, LogValue};
+
+#[cfg(feature = "linux")]
+mod system;
+#[cfg(feature = "linux")]
+pub use self::system::register_system_items;
+
+mod userparam;
+pub use self::userparam::UserParameter;
+
 mod request;
 pub use self::request::{ZabbixDiscovery, ZabbixHost, ZabbixMetric, ZabbixRequest};
 
@@ -28,10 +59,53 @@ mod response;
 pub use self::response::Response;
 
 mod proxy;
-pub use self::proxy::{ZabbixProxy, Host, HostItem, Item, ItemHost};
+pub use self::proxy::{ZabbixProxy, Host, HostItem, Item, ItemHost, ProxyMode};
+
+mod proxyconfig;
+pub use self::proxyconfig::{
+    ConfigDCheck, ConfigDRule, ConfigError, ConfigExpression, ConfigHost, ConfigHostTemplate,
+    ConfigHttpField, ConfigHttpItem, ConfigHttpStep, ConfigHttpTest, ConfigInterface, ConfigItem,
+    ConfigItemRtdata, ConfigMacro, ConfigPreproc, ConfigRegexp, ConfigSettings, ProxyConfig,
+};
+
+mod usermacro;
+pub use self::usermacro::{MacroContext, MacroResolver, UserMacro};
+
+mod configcache;
+pub use self::configcache::{ConfigCache, ConfigEvent};
+
+mod proxydata;
+pub use self::proxydata::{
+    AutoRegistration, DiscoveryValue, HistoryValue, HostAvailability, ProxyData, ProxyDataResponse,
+    ProxyTask,
+};
+
+mod active;
+pub use self::active::{ActiveCheck, AgentValue};
+
+mod logfile;
+
+mod peer;
+pub use self::peer::AllowedPeers;
 
 mod agent;
 pub use self::agent::ZabbixAgent;
 
+mod conf;
+pub use self::conf::{AgentConf, ConfError, ProxyConf, TlsOptions};
+
+mod input;
+pub use self::input::{InputFormat, LineError};
+
 mod sender;
-pub use self::sender::ZabbixSender;
+pub use self::sender::{SendInputError, ZabbixSender};
+
+#[cfg(feature = "async")]
+mod codec;
+#[cfg(feature = "async")]
+pub use self::codec::ZbxCodec;
+
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+pub use self::async_client::{AsyncZabbixProtocol, AsyncZabbixProxy, AsyncZabbixSender};
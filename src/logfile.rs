@@ -0,0 +1,451 @@
+//! 日志文件监控项 `log[]` 和 `logrt[]`，只用于主动模式
+//!
+//! `log[file,<regexp>,<encoding>,<maxlines>,<mode>,<output>,<maxdelay>]`
+//! `logrt[file_regexp,<regexp>,<encoding>,<maxlines>,<mode>,<output>,<maxdelay>,<options>]`
+//!
+//! 从 lastlogsize 处继续读取完整的行，匹配 regexp 的每一行作为一个值发送。
+//! logrt 按修改时间依次读取目录中文件名匹配的文件，修改时间不早于 mtime 的第一个文件
+//! 视为上次读取的文件，从 lastlogsize 处继续，之后的文件从头读取。
+//! 只支持 UTF-8 编码，maxdelay 和 options 参数被忽略。
+use regex::Regex;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::handler::LogValue;
+use super::key::ItemKey;
+use super::Result;
+
+/// 未指定 maxlines 时每秒最多发送的行数 (MaxLinesPerSecond)
+const MAX_LINES_PER_SECOND: usize = 20;
+
+/// 日志读取位置
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct LogState {
+    pub lastlogsize: u64,
+    pub mtime: i64,
+}
+
+/// 解析后的 log[] 或 logrt[] 监控项
+#[derive(Debug)]
+pub(crate) struct LogItem {
+    file: String,
+    /// logrt 的目录和文件名正则表达式
+    rotate: Option<(PathBuf, Regex)>,
+    regexp: Option<Regex>,
+    maxlines: usize,
+    skip: bool,
+    output: String,
+}
+
+impl LogItem {
+    pub fn is_log(key: &ItemKey) -> bool {
+        key.name() == "log" || key.name() == "logrt"
+    }
+
+    pub fn parse(key: &ItemKey) -> Result<Self> {
+        let rotate = key.name() == "logrt";
+        let max_params = if rotate { 8 } else { 7 };
+        if key.params().len() > max_params {
+            return Err(format_err!("Too many parameters."));
+        }
+        let param = |i: usize| -> Result<&str> {
+            match key.params().get(i) {
+                None => Ok(""),
+                Some(p) => p
+                    .as_str()
+                    .ok_or_else(|| format_err!("Invalid {} parameter.", ORDINALS[i])),
+            }
+        };
+        let invalid = |i: usize| format_err!("Invalid {} parameter.", ORDINALS[i]);
+
+        let file = param(0)?;
+        if file.is_empty() {
+            return Err(invalid(0));
+        }
+        let rotate = if rotate {
+            let (dir, name) = match file.rfind('/') {
+                Some(i) => (&file[..=i], &file[i + 1..]),
+                None => return Err(invalid(0)),
+            };
+            let re = Regex::new(name).map_err(|_| invalid(0))?;
+            Some((PathBuf::from(dir), re))
+        } else {
+            None
+        };
+
+        let regexp = match param(1)? {
+            "" => None,
+            re if re.starts_with('@') => {
+                return Err(format_err!("Global regular expressions are not supported."))
+            }
+            re => Some(Regex::new(re).map_err(|_| invalid(1))?),
+        };
+
+        match param(2)?.to_uppercase().as_str() {
+            "" | "UTF-8" | "UTF8" => {}
+            _ => return Err(format_err!("Unsupported encoding.")),
+        }
+
+        let maxlines = match param(3)? {
+            "" => MAX_LINES_PER_SECOND,
+            n => match n.parse::<usize>() {
+                Ok(n) if (1..=1000).contains(&n) => n,
+                _ => return Err(invalid(3)),
+            },
+        };
+
+        let skip = match param(4)? {
+            "" | "all" => false,
+            "skip" => true,
+            _ => return Err(invalid(4)),
+        };
+
+        Ok(Self {
+            file: String::from(file),
+            rotate,
+            regexp,
+            maxlines,
+            skip,
+            output: String::from(param(5)?),
+        })
+    }
+
+    ///
+    /// 发送给服务端的读取位置，log[] 的 mtime 为 0
+    ///
+    pub fn position(&self, state: &LogState) -> (u64, i64) {
+        let mtime = if self.rotate.is_some() {
+            state.mtime
+        } else {
+            0
+        };
+        (state.lastlogsize, mtime)
+    }
+
+    ///
+    /// 读取新增的行，更新读取位置。first 为新的监控项时，skip 模式跳过已有的内容。
+    /// 每次最多返回 maxlines * delay 个值
+    ///
+    pub fn read(&self, state: &mut LogState, first: bool, delay: u32) -> Result<Vec<LogValue>> {
+        let max = self.maxlines * delay.max(1) as usize;
+        let mut result = vec![];
+
+        let (dir, re) = match &self.rotate {
+            None => {
+                let (size, _) = file_info(Path::new(&self.file))?;
+                if first && self.skip {
+                    state.lastlogsize = size;
+                    return Ok(result);
+                }
+                // 文件被截断或替换时从头读取
+                if size < state.lastlogsize {
+                    state.lastlogsize = 0;
+                }
+                state.lastlogsize = self.read_file(
+                    Path::new(&self.file),
+                    state.lastlogsize,
+                    state.mtime,
+                    max,
+                    &mut result,
+                )?;
+                return Ok(result);
+            }
+            Some(rotate) => rotate,
+        };
+
+        let mut files = vec![];
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format_err!("Cannot open directory \"{}\": {}", dir.display(), e))?;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if !name.to_str().is_some_and(|n| re.is_match(n)) {
+                continue;
+            }
+            if let Ok((size, mtime)) = file_info(&entry.path()) {
+                if entry.path().is_file() {
+                    files.push((mtime, entry.path(), size));
+                }
+            }
+        }
+        files.sort();
+
+        if first && self.skip {
+            if let Some((mtime, _, size)) = files.last() {
+                state.lastlogsize = *size;
+                state.mtime = *mtime;
+            }
+            return Ok(result);
+        }
+
+        let mut resume = true;
+        let since = state.mtime;
+        for (mtime, path, size) in files.iter().filter(|(m, _, _)| *m >= since) {
+            let start = if resume && *size >= state.lastlogsize {
+                state.lastlogsize
+            } else {
+                0
+            };
+            resume = false;
+            state.lastlogsize = self.read_file(path, start, *mtime, max, &mut result)?;
+            state.mtime = *mtime;
+            if result.len() >= max {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    ///
+    /// 从 start 处读取完整的行，返回读取后的位置，未以换行结束的最后一行留到下次读取
+    ///
+    fn read_file(
+        &self,
+        path: &Path,
+        start: u64,
+        mtime: i64,
+        max: usize,
+        result: &mut Vec<LogValue>,
+    ) -> Result<u64> {
+        let mut file = File::open(path)
+            .map_err(|e| format_err!("Cannot open file \"{}\": {}", path.display(), e))?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut reader = BufReader::new(file);
+        let mut pos = start;
+        let mut buf = Vec::new();
+
+        while result.len() < max {
+            buf.clear();
+            let n = reader.read_until(b'\n', &mut buf)?;
+            if n == 0 || buf.last() != Some(&b'\n') {
+                break;
+            }
+            pos += n as u64;
+            let line = String::from_utf8_lossy(&buf);
+            if let Some(value) = self.process_line(line.trim_end_matches(['\r', '\n'])) {
+                result.push(LogValue {
+                    value,
+                    lastlogsize: pos,
+                    mtime: if self.rotate.is_some() { mtime } else { 0 },
+                });
+            }
+        }
+        Ok(pos)
+    }
+
+    ///
+    /// 匹配一行，output 中的 `\0..\9` 替换为对应的捕获组，output 为空时返回整行
+    ///
+    fn process_line(&self, line: &str) -> Option<String> {
+        let re = match &self.regexp {
+            None => return Some(String::from(line)),
+            Some(re) => re,
+        };
+        let caps = re.captures(line)?;
+        if self.output.is_empty() {
+            return Some(String::from(line));
+        }
+
+        let mut result = String::new();
+        let mut chars = self.output.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek().and_then(|d| d.to_digit(10))) {
+                ('\\', Some(n)) => {
+                    chars.next();
+                    result.push_str(caps.get(n as usize).map_or("", |m| m.as_str()));
+                }
+                _ => result.push(c),
+            }
+        }
+        Some(result)
+    }
+}
+
+const ORDINALS: [&str; 8] = [
+    "first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth",
+];
+
+///
+/// 文件大小和修改时间（秒）
+///
+fn file_info(path: &Path) -> Result<(u64, i64)> {
+    let meta = fs::metadata(path).map_err(|e| {
+        format_err!(
+            "Cannot obtain information for file \"{}\": {}",
+            path.display(),
+            e
+        )
+    })?;
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64);
+    Ok((meta.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::process;
+    use std::time::{Duration, SystemTime};
+
+    fn item(key: &str) -> LogItem {
+        LogItem::parse(&ItemKey::parse(key).unwrap()).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zabbix-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn append(path: &Path, data: &str) {
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        f.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn set_mtime(path: &Path, secs_ago: u64) {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let t = SystemTime::now() - Duration::from_secs(secs_ago);
+        let time = libc::timespec {
+            tv_sec: t.duration_since(UNIX_EPOCH).unwrap().as_secs() as libc::time_t,
+            tv_nsec: 0,
+        };
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let rc =
+            unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), [time, time].as_ptr(), 0) };
+        assert_eq!(0, rc);
+    }
+
+    fn values(v: &[LogValue]) -> Vec<&str> {
+        v.iter().map(|v| v.value.as_str()).collect()
+    }
+
+    #[test]
+    fn test_log_item_parse() {
+        let parse = |key: &str| LogItem::parse(&ItemKey::parse(key).unwrap());
+        assert!(parse("log[/var/log/app.log,error,,100,skip,\\1]").is_ok());
+        assert!(parse("logrt[\"/var/log/app.log.*\"]").is_ok());
+        assert_eq!(
+            "Invalid first parameter.",
+            parse("log[]").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Invalid fifth parameter.",
+            parse("log[/a,,,,none]").unwrap_err().to_string()
+        );
+        assert!(parse("log[/a,,,0]").is_err());
+        assert!(parse("log[/a,\"(\"]").is_err());
+        assert!(parse("log[/a,,CP1251]").is_err());
+        assert!(parse("logrt[app.log]").is_err());
+    }
+
+    #[test]
+    fn test_log_output() {
+        let item = item(r#"log[/a,"user=(\w+) code=(\d+)",,,,"\2 by \1 \3"]"#);
+        assert_eq!(
+            Some("500 by bob ".to_string()),
+            item.process_line("x user=bob code=500")
+        );
+        assert_eq!(None, item.process_line("x user=bob"));
+        assert_eq!(
+            Some("line".to_string()),
+            self::item("log[/a]").process_line("line")
+        );
+    }
+
+    #[test]
+    fn test_log_read() {
+        let dir = temp_dir("log");
+        let file = dir.join("app.log");
+        append(&file, "old error\nold info\n");
+        let key = format!("log[{},error]", file.display());
+
+        // all 模式从 lastlogsize 处读取
+        let mut state = LogState::default();
+        let result = item(&key).read(&mut state, true, 1).unwrap();
+        assert_eq!(vec!["old error"], values(&result));
+        assert_eq!(10, result[0].lastlogsize);
+        assert_eq!(state.lastlogsize, 9 + 10);
+
+        // 不完整的行留到下次读取
+        append(&file, "new error\npartial error");
+        let result = item(&key).read(&mut state, false, 1).unwrap();
+        assert_eq!(vec!["new error"], values(&result));
+        append(&file, "\n");
+        let result = item(&key).read(&mut state, false, 1).unwrap();
+        assert_eq!(vec!["partial error"], values(&result));
+
+        // skip 模式跳过已有内容
+        let skip = format!("log[{},error,,,skip]", file.display());
+        let mut state = LogState::default();
+        assert!(item(&skip).read(&mut state, true, 1).unwrap().is_empty());
+        append(&file, "error 1\nerror 2\nerror 3\n");
+        let result = item(&skip).read(&mut state, false, 1).unwrap();
+        assert_eq!(vec!["error 1", "error 2", "error 3"], values(&result));
+
+        // maxlines 限制每次发送的行数
+        let limited = format!("log[{},,,2]", file.display());
+        let mut state = LogState::default();
+        assert_eq!(2, item(&limited).read(&mut state, true, 1).unwrap().len());
+        assert_eq!(4, item(&limited).read(&mut state, false, 2).unwrap().len());
+
+        // 文件被截断后从头读取
+        fs::write(&file, "error after truncate\n").unwrap();
+        let mut state = LogState {
+            lastlogsize: 1000,
+            mtime: 0,
+        };
+        let result = item(&key).read(&mut state, false, 1).unwrap();
+        assert_eq!(vec!["error after truncate"], values(&result));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(item(&key).read(&mut state, false, 1).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_logrt_read() {
+        let dir = temp_dir("logrt");
+        let current = dir.join("app.log");
+        append(&current, "line 1\nline 2\n");
+        set_mtime(&current, 100);
+        let key = format!("logrt[\"{}/app\\.log.*\"]", dir.display());
+
+        let mut state = LogState::default();
+        let result = item(&key).read(&mut state, true, 1).unwrap();
+        assert_eq!(vec!["line 1", "line 2"], values(&result));
+        assert!(state.mtime > 0);
+
+        // 轮转：app.log -> app.log.1，新的 app.log
+        append(&current, "line 3\n");
+        set_mtime(&current, 100);
+        fs::rename(&current, dir.join("app.log.1")).unwrap();
+        append(&current, "line 4\n");
+        append(&dir.join("other.log"), "other\n");
+
+        let result = item(&key).read(&mut state, false, 1).unwrap();
+        assert_eq!(vec!["line 3", "line 4"], values(&result));
+        assert_eq!(7, state.lastlogsize);
+        assert!(item(&key).read(&mut state, false, 1).unwrap().is_empty());
+
+        let skip = format!("logrt[\"{}/app\\.log.*\",,,,skip]", dir.display());
+        let mut state = LogState::default();
+        assert!(item(&skip).read(&mut state, true, 1).unwrap().is_empty());
+        assert_eq!(7, state.lastlogsize);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! 允许连接的对端地址
+//!
+//! 与配置文件中 `Server` 参数的格式相同：以逗号分隔的 IP 地址、CIDR 网段或主机名，
+//! 主机名在每次检查时解析。
+use std::fmt;
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+
+use super::Result;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Peer {
+    Net(IpAddr, u8),
+    Host(String),
+}
+
+/// 允许连接的对端地址列表
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllowedPeers {
+    peers: Vec<Peer>,
+    source: String,
+}
+
+impl AllowedPeers {
+    pub fn parse(list: &str) -> Result<Self> {
+        let mut peers = vec![];
+        for entry in list.split(',').map(str::trim) {
+            if entry.is_empty() {
+                return Err(format_err!("empty entry in allowed peers \"{}\"", list));
+            }
+            peers.push(parse_peer(entry)?);
+        }
+        let source = String::from(list);
+        Ok(Self { peers, source })
+    }
+
+    ///
+    /// 是否允许 addr 连接，IPv4 映射的 IPv6 地址按 IPv4 比较
+    ///
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        let addr = canonical(addr);
+        self.peers.iter().any(|peer| match peer {
+            Peer::Net(net, prefix) => in_net(addr, *net, *prefix),
+            Peer::Host(host) => match (host.as_str(), 0).to_socket_addrs() {
+                Ok(addrs) => addrs.map(|a| canonical(a.ip())).any(|a| a == addr),
+                Err(e) => {
+                    debug!("cannot resolve \"{}\": {}", host, e);
+                    false
+                }
+            },
+        })
+    }
+
+    ///
+    /// 检查连接的对端地址，不允许时记录日志
+    ///
+    pub(crate) fn accepts(&self, stream: &TcpStream) -> bool {
+        match stream.peer_addr() {
+            Ok(addr) if self.allows(addr.ip()) => true,
+            Ok(addr) => {
+                warn!(
+                    "connection from \"{}\" rejected, allowed hosts: \"{}\"",
+                    addr.ip(),
+                    self
+                );
+                false
+            }
+            Err(e) => {
+                warn!("failed to get peer address: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl FromStr for AllowedPeers {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for AllowedPeers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn parse_peer(entry: &str) -> Result<Peer> {
+    let (addr, prefix) = match entry.find('/') {
+        Some(i) => (&entry[..i], Some(&entry[i + 1..])),
+        None => (entry, None),
+    };
+    let ip = match addr.parse::<IpAddr>() {
+        Ok(ip) => canonical(ip),
+        Err(_) if prefix.is_none() => return Ok(Peer::Host(String::from(entry))),
+        Err(_) => return Err(format_err!("invalid address \"{}\"", entry)),
+    };
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        None => max,
+        Some(p) => match p.parse::<u8>() {
+            Ok(p) if p <= max => p,
+            _ => return Err(format_err!("invalid network prefix \"{}\"", entry)),
+        },
+    };
+    Ok(Peer::Net(ip, prefix))
+}
+
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
+
+fn in_net(addr: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (addr, net) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(a) & mask == u32::from(n) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(n)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(a) & mask == u128::from(n) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allowed_peers() {
+        let peers =
+            AllowedPeers::parse("127.0.0.1, 192.168.1.0/24,::1,fe80::/64,localhost").unwrap();
+        assert!(peers.allows(ip("127.0.0.1")));
+        assert!(peers.allows(ip("::ffff:127.0.0.1")));
+        assert!(peers.allows(ip("192.168.1.200")));
+        assert!(!peers.allows(ip("192.168.2.1")));
+        assert!(peers.allows(ip("fe80::1")));
+        assert!(!peers.allows(ip("fe81::1")));
+        assert!(peers.allows(ip("::1")));
+
+        assert!(AllowedPeers::parse("0.0.0.0/0")
+            .unwrap()
+            .allows(ip("10.1.2.3")));
+        assert!(!AllowedPeers::parse("10.0.0.1")
+            .unwrap()
+            .allows(ip("10.0.0.2")));
+        assert!(AllowedPeers::parse("10.0.0.0/33").is_err());
+        assert!(AllowedPeers::parse("host/24").is_err());
+        assert!(AllowedPeers::parse("a,,b").is_err());
+        assert_eq!(
+            "127.0.0.1",
+            AllowedPeers::parse("127.0.0.1").unwrap().to_string()
+        );
+    }
+}
@@ -1,82 +1,301 @@
 //! 采用 rust 实现的 zabbix 协议库
 
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use std::net::TcpStream;
-
-use std::io;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
 
+use super::error::{from_io, ProtocolError};
+use super::frame::{encode_packet, ZbxStream};
+use super::header::ZbxHeader;
+#[cfg(feature = "tls")]
+use super::tls::TlsConfig;
 use super::Result;
 
 /// 定义了 zabbix server 的地址和端口
 #[derive(Debug, Clone)]
 pub struct ZabbixProtocol {
-    server: String,
-    port: u16,
+    pub(crate) server: String,
+    pub(crate) port: u16,
+    pub(crate) compress_threshold: Option<usize>,
+    pub(crate) max_packet_size: u64,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) timeout: Option<Duration>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<TlsConfig>,
 }
 
 impl ZabbixProtocol {
     pub const ZBX_HDR: &'static [u8; 5] = b"ZBXD\x01";
-    pub const ZBX_HDR_SIZE: usize = 13;
+    pub const ZBX_HDR_SIZE: usize = ZbxHeader::SIZE;
 
     pub fn new(server: &str, port: u16) -> Self {
         let server = String::from(server);
-        Self { server, port }
+        Self {
+            server,
+            port,
+            compress_threshold: None,
+            max_packet_size: ZbxHeader::DEFAULT_MAX_SIZE,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            timeout: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    ///
+    /// 数据长度超过 threshold 字节时压缩发送
+    ///
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_threshold = Some(threshold);
+        self
+    }
+
+    ///
+    /// 设置接收数据包的最大长度，超过时返回错误
+    ///
+    pub fn with_max_packet_size(mut self, size: u64) -> Self {
+        self.max_packet_size = size;
+        self
     }
 
-    fn create_packet(&self, data: &str) -> (Vec<u8>, usize) {
-        let data = data.as_bytes();
+    ///
+    /// 同时设置连接、读取和写入超时，并限制单次请求的总耗时，对应 zabbix 配置中的 Timeout 参数
+    ///
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_connect_timeout(timeout)
+            .with_read_timeout(timeout)
+            .with_write_timeout(timeout)
+            .with_request_timeout(timeout)
+    }
+
+    ///
+    /// 单次请求（连接、握手、发送和接收）的总超时，
+    /// 避免对端逐字节发送时每次读取都不超时而无限等待
+    ///
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        let mut buf = [0; 8];
-        let length = data.len();
-        LittleEndian::write_u64(&mut buf, length as u64);
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
 
-        let mut packet: Vec<u8> = Vec::with_capacity(Self::ZBX_HDR_SIZE + length);
-        packet.extend(Self::ZBX_HDR);
-        packet.extend(&buf);
-        packet.extend(data);
-        (packet, Self::ZBX_HDR_SIZE + length)
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    ///
+    /// 使用 TLS 证书加密连接
+    ///
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    ///
+    /// 使用相同的参数连接另一个地址
+    ///
+    pub(crate) fn with_address(&self, server: &str, port: u16) -> Self {
+        Self {
+            server: String::from(server),
+            port,
+            ..self.clone()
+        }
+    }
+
+    ///
+    /// 生成 zabbix 协议数据包，返回数据包和长度
+    ///
+    pub fn create_packet(&self, data: &str) -> Result<(Vec<u8>, usize)> {
+        let packet = encode_packet(data.as_bytes(), self.compress_threshold)?;
+        let size = packet.len();
+        Ok((packet, size))
     }
 
     pub fn send(&self, data: &str) -> Result<Vec<u8>> {
-        let addr = format!("{0}:{1}", self.server, self.port);
-        let mut s = TcpStream::connect(addr)?;
-        let (pkt, _) = self.create_packet(data);
-        let _ = s.write(&pkt)?;
-
-        let mut zbx_hdr = [0; Self::ZBX_HDR_SIZE];
-        let _ = s.read(&mut zbx_hdr)?;
-        if Self::ZBX_HDR != &zbx_hdr[..5] {
-            return Err(format_err!("packet header invalid"));
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let s = self.connect_before(deadline)?;
+        trace!("send {} bytes to {}:{}", data.len(), self.server, self.port);
+
+        let deadline = match deadline {
+            Some(at) => Deadline {
+                sock: s.try_clone()?,
+                at,
+                read_timeout: self.read_timeout,
+                write_timeout: self.write_timeout,
+            },
+            None => return self.send_over(s, data),
+        };
+        deadline.apply().map_err(from_io)?;
+
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.tls {
+                let s = tls.connect(s)?;
+                return self.exchange(deadline.wrap(s), data);
+            }
         }
+        self.exchange(deadline.wrap(s), data)
+    }
 
-        // let mut rdr = io::Cursor::new(zbx_hdr);
-        // rdr.set_position(5);
-        let mut rdr = io::Cursor::new(&zbx_hdr[5..]);
-        let data_length = rdr.read_u64::<LittleEndian>()?;
-        if data_length == 0 {
-            return Err(format_err!("packet data length = 0"));
+    fn send_over(&self, s: TcpStream, data: &str) -> Result<Vec<u8>> {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls) = &self.tls {
+                return self.exchange(tls.connect(s)?, data);
+            }
         }
+        self.exchange(s, data)
+    }
 
-        let mut read_data = vec![];
-        s.take(data_length).read_to_end(&mut read_data)?;
+    fn exchange<S: Read + Write>(&self, s: S, data: &str) -> Result<Vec<u8>> {
+        let mut stream = self.stream(s);
+        stream.write_frame(data.as_bytes())?;
+        let read_data = stream.read_frame()?;
+        if read_data.is_empty() {
+            return Err(format_err!("packet data length = 0"));
+        }
         Ok(read_data)
     }
+
+    ///
+    /// 连接服务端并设置读写超时，超时返回 `ProtocolError::Timeout`
+    ///
+    pub fn connect(&self) -> Result<TcpStream> {
+        self.connect_before(None)
+    }
+
+    fn connect_before(&self, deadline: Option<Instant>) -> Result<TcpStream> {
+        let addr = format!("{0}:{1}", self.server, self.port);
+        let timeout = match deadline {
+            Some(at) => Some(min_timeout(
+                self.connect_timeout,
+                remaining(at).map_err(from_io)?,
+            )),
+            None => self.connect_timeout,
+        };
+        let s = match timeout {
+            Some(timeout) => connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        s.set_read_timeout(self.read_timeout)?;
+        s.set_write_timeout(self.write_timeout)?;
+        Ok(s)
+    }
+
+    pub(crate) fn stream<S>(&self, s: S) -> ZbxStream<S> {
+        let stream = ZbxStream::new(s).with_max_packet_size(self.max_packet_size);
+        match self.compress_threshold {
+            Some(threshold) => stream.with_compression(threshold),
+            None => stream,
+        }
+    }
+}
+
+///
+/// 请求的截止时间，每次读写前按剩余时间重新设置 socket 超时
+///
+struct Deadline {
+    sock: TcpStream,
+    at: Instant,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl Deadline {
+    fn apply(&self) -> io::Result<()> {
+        let left = remaining(self.at)?;
+        self.sock
+            .set_read_timeout(Some(min_timeout(self.read_timeout, left)))?;
+        self.sock
+            .set_write_timeout(Some(min_timeout(self.write_timeout, left)))
+    }
+
+    fn wrap<S>(self, inner: S) -> DeadlineStream<S> {
+        DeadlineStream {
+            inner,
+            deadline: self,
+        }
+    }
+}
+
+struct DeadlineStream<S> {
+    inner: S,
+    deadline: Deadline,
+}
+
+impl<S: Read> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.deadline.apply()?;
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.deadline.apply()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.deadline.apply()?;
+        self.inner.flush()
+    }
+}
+
+fn remaining(at: Instant) -> io::Result<Duration> {
+    match at.checked_duration_since(Instant::now()) {
+        Some(left) if !left.is_zero() => Ok(left),
+        _ => Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")),
+    }
+}
+
+fn min_timeout(timeout: Option<Duration>, left: Duration) -> Duration {
+    timeout.map_or(left, |t| t.min(left))
+}
+
+fn connect_timeout(addr: &str, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = None;
+    for a in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => return Ok(s),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    match last_err {
+        Some(e) if e.kind() == std::io::ErrorKind::TimedOut => Err(ProtocolError::Timeout.into()),
+        Some(e) => Err(from_io(e)),
+        None => Err(format_err!("could not resolve address: {}", addr)),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::TcpListener;
 
     #[test]
     fn test_zabbix_protocol_create_packet() {
         let data = "data";
         let zbx = ZabbixProtocol::new("127.0.0.1", 10051);
-        let (pkt, size) = zbx.create_packet(data);
+        let (pkt, size) = zbx.create_packet(data).unwrap();
         // [90, 66, 88, 68, 1, 4, 0, 0, 0, 0, 0, 0, 0, 100, 97, 116, 97]
 
-        let mut rdr = io::Cursor::new(&pkt[5..ZabbixProtocol::ZBX_HDR_SIZE]);
-        let length = rdr.read_u64::<LittleEndian>().unwrap();
+        let header = ZbxHeader::parse(&pkt, ZbxHeader::DEFAULT_MAX_SIZE).unwrap();
 
         assert_eq!(
             ZabbixProtocol::ZBX_HDR,
@@ -85,7 +304,127 @@ mod tests {
             ZabbixProtocol::ZBX_HDR
         );
         assert_eq!(data.len() + ZabbixProtocol::ZBX_HDR_SIZE, size);
-        assert_eq!(length, data.len() as u64);
+        assert_eq!(header.data_length, data.len() as u64);
+        assert_eq!(header.reserved, 0);
         assert_eq!(data.as_bytes(), &pkt[ZabbixProtocol::ZBX_HDR_SIZE..]);
     }
+
+    #[test]
+    fn test_zabbix_protocol_compress_packet() {
+        let data = "data".repeat(100);
+        let zbx = ZabbixProtocol::new("127.0.0.1", 10051).with_compression(128);
+        let (pkt, size) = zbx.create_packet(&data).unwrap();
+
+        assert_eq!(b"ZBXD\x03", &pkt[..5]);
+        assert_eq!(pkt.len(), size);
+
+        let header = ZbxHeader::parse(&pkt, ZbxHeader::DEFAULT_MAX_SIZE).unwrap();
+        assert!(header.is_compressed());
+        assert_eq!(
+            header.data_length as usize,
+            size - ZabbixProtocol::ZBX_HDR_SIZE
+        );
+        assert_eq!(header.reserved as usize, data.len());
+
+        let body = zbx.stream(&pkt[..]).read_frame().unwrap();
+        assert_eq!(data.as_bytes(), &body[..]);
+
+        // 未超过阈值时不压缩
+        let (pkt, _) = zbx.create_packet("data").unwrap();
+        assert_eq!(ZabbixProtocol::ZBX_HDR, &pkt[..5]);
+    }
+
+    #[test]
+    fn test_zabbix_protocol_read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_timeout(Duration::from_millis(100));
+        let e = zbx.send("data").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::Timeout)
+        ));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_zabbix_protocol_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            // 每 50ms 发送一个字节，单次读取不会超时
+            let (mut s, _) = listener.accept().unwrap();
+            for b in ZabbixProtocol::ZBX_HDR.iter().cycle().take(40) {
+                if s.write_all(&[*b]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let zbx = ZabbixProtocol::new("127.0.0.1", port)
+            .with_read_timeout(Duration::from_millis(200))
+            .with_request_timeout(Duration::from_millis(300));
+        let start = Instant::now();
+        let e = zbx.send("data").unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_millis(1000));
+        server.join().unwrap();
+    }
+
+    ///
+    /// backlog 已满的本地端口，新的连接请求被丢弃，连接会一直挂起
+    ///
+    #[cfg(target_os = "linux")]
+    fn full_backlog() -> (TcpListener, Vec<TcpStream>) {
+        use std::os::unix::io::FromRawFd;
+
+        let listener = unsafe {
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+            assert!(fd >= 0);
+            let mut addr: libc::sockaddr_in = std::mem::zeroed();
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_addr.s_addr = u32::from(std::net::Ipv4Addr::LOCALHOST).to_be();
+            let len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            assert_eq!(
+                0,
+                libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len)
+            );
+            assert_eq!(0, libc::listen(fd, 0));
+            TcpListener::from_raw_fd(fd)
+        };
+        let addr = listener.local_addr().unwrap();
+        let mut clients = vec![];
+        while clients.len() < 16 {
+            match TcpStream::connect_timeout(&addr, Duration::from_millis(200)) {
+                Ok(s) => clients.push(s),
+                Err(_) => return (listener, clients),
+            }
+        }
+        panic!("backlog of {} is not full", addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_zabbix_protocol_connect_timeout() {
+        let (listener, _clients) = full_backlog();
+        let port = listener.local_addr().unwrap().port();
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_timeout(Duration::from_millis(200));
+        let start = Instant::now();
+        let e = zbx.send("data").unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(200), "{}", e);
+        assert!(start.elapsed() < Duration::from_millis(1000), "{}", e);
+        assert!(
+            matches!(
+                e.downcast_ref::<ProtocolError>(),
+                Some(ProtocolError::Timeout)
+            ),
+            "{}",
+            e
+        );
+    }
 }
@@ -1,18 +1,56 @@
 //! 基于 rust 实现的 zabbix proxy，实现了基本的代理功能。
 //!
 use super::Result;
+use super::active::session_token;
+use super::configcache::{ConfigCache, ConfigEvent, ProxyConfigRequest};
+use super::delay::Delay;
+use super::handler::ItemRegistry;
+use super::key::ItemKey;
+use super::peer::AllowedPeers;
 use super::protocol::ZabbixProtocol;
+use super::proxyconfig::{ConfigHost, ConfigItem, FromRow, ProxyConfig, RowReader};
+use super::proxydata::{HistoryValue, ProxyData, ProxyDataResponse, ProxyTask};
 use super::request::{ZabbixHost, ZabbixMetric, ZabbixRequest};
 use super::response::Response;
+use super::usermacro::contains_macro;
+#[cfg(feature = "tls")]
+use super::tls::TlsConfig;
+use chrono::prelude::*;
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-enum ProxyResponse {
+#[allow(clippy::upper_case_acronyms)]
+pub(crate) enum ProxyResponse {
     RESPONSE(Response),
     CONFIG(Value),
 }
 
+/// proxy 运行模式 (ProxyMode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyMode {
+    /// 主动连接服务端
+    Active,
+    /// 等待服务端连接
+    Passive,
+}
+
+/// proxy 本地状态，被动模式下由服务端的请求读取和更新
+#[derive(Debug, Default)]
+struct ProxyState {
+    config: ConfigCache,
+    events: Vec<ConfigEvent>,
+    history: Vec<HistoryValue>,
+    last_id: u64,
+    tasks: Vec<ProxyTask>,
+    task_results: Vec<ProxyTask>,
+}
+
 /// zabbix proxy
 /// 实现了 proxy 的基本功能
 ///
@@ -20,6 +58,20 @@ enum ProxyResponse {
 pub struct ZabbixProxy {
     name: String,
     proto: ZabbixProtocol,
+    registry: ItemRegistry,
+    mode: ProxyMode,
+    listen_port: u16,
+    timeout: Duration,
+    config_frequency: Duration,
+    data_sender_frequency: Duration,
+    heartbeat_frequency: Duration,
+    offline_buffer: Duration,
+    allowed_peers: Option<AllowedPeers>,
+    version: String,
+    session: String,
+    state: Arc<Mutex<ProxyState>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
 }
 
 impl ZabbixProxy {
@@ -27,22 +79,147 @@ impl ZabbixProxy {
     pub const HISTORY_DATA: &'static str = "history data";
     pub const PROXY_HEARTBEAT: &'static str = "proxy heartbeat";
     pub const AUTO_REGISTRATION: &'static str = "auto registration";
+    pub const PROXY_DATA: &'static str = "proxy data";
+    pub const PROXY_TASKS: &'static str = "proxy tasks";
+
+    pub const LISTEN_PORT: u16 = 10051;
 
     pub fn new(name: &str, server: &str, port: u16) -> Self {
+        Self::with_protocol(name, ZabbixProtocol::new(server, port))
+    }
+
+    pub fn with_protocol(name: &str, proto: ZabbixProtocol) -> Self {
         let name = String::from(name);
-        let proto = ZabbixProtocol::new(server, port);
-        Self { name, proto }
+        let registry = ItemRegistry::new();
+        Self {
+            name,
+            proto,
+            registry,
+            mode: ProxyMode::Active,
+            listen_port: Self::LISTEN_PORT,
+            timeout: Duration::from_secs(3),
+            config_frequency: Duration::from_secs(3600),
+            data_sender_frequency: Duration::from_secs(1),
+            heartbeat_frequency: Duration::from_secs(60),
+            offline_buffer: Duration::from_secs(3600),
+            allowed_peers: None,
+            version: String::from(ProxyData::VERSION),
+            session: session_token(),
+            state: Arc::new(Mutex::new(ProxyState::default())),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    ///
+    /// 运行模式 (ProxyMode)
+    ///
+    pub fn with_mode(mut self, mode: ProxyMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    ///
+    /// 被动模式监听端口 (ListenPort)
+    ///
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = port;
+        self
+    }
+
+    ///
+    /// 读写超时 (Timeout)
+    ///
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.proto = self.proto.with_timeout(timeout);
+        self
+    }
+
+    ///
+    /// 主动模式从服务端获取配置的间隔 (ConfigFrequency)
+    ///
+    pub fn with_config_frequency(mut self, frequency: Duration) -> Self {
+        self.config_frequency = frequency;
+        self
+    }
+
+    ///
+    /// 主动模式向服务端发送数据的间隔 (DataSenderFrequency)
+    ///
+    pub fn with_data_sender_frequency(mut self, frequency: Duration) -> Self {
+        self.data_sender_frequency = frequency;
+        self
+    }
+
+    ///
+    /// 主动模式发送心跳的间隔，为 0 时不发送 (HeartbeatFrequency)
+    ///
+    pub fn with_heartbeat_frequency(mut self, frequency: Duration) -> Self {
+        self.heartbeat_frequency = frequency;
+        self
+    }
+
+    ///
+    /// 无法连接服务端时数据保留的时间 (ProxyOfflineBuffer)
+    ///
+    pub fn with_offline_buffer(mut self, buffer: Duration) -> Self {
+        self.offline_buffer = buffer;
+        self
+    }
+
+    ///
+    /// 被动模式只接受这些地址的连接 (Server)
+    ///
+    pub fn with_allowed_peers(mut self, peers: AllowedPeers) -> Self {
+        self.allowed_peers = Some(peers);
+        self
+    }
+
+    ///
+    /// 连接加密：主动模式使用 TLSConnect，被动模式使用 TLSAccept
+    ///
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.proto = self.proto.with_tls(tls.clone());
+        self.tls = Some(tls);
+        self
+    }
+
+    ///
+    /// 报告给服务端的 proxy 版本，默认为 `ProxyData::VERSION`，连接旧版本的服务端时可设置为对应的版本
+    ///
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = String::from(version);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mode(&self) -> ProxyMode {
+        self.mode
+    }
+
+    ///
+    /// proxy data 的会话标识，每个 proxy 实例不同
+    ///
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    ///
+    /// 本地采集使用的监控项处理，可与 agent 共用
+    ///
+    pub fn with_registry(mut self, registry: ItemRegistry) -> Self {
+        self.registry = registry;
+        self
     }
 
     fn send_request(&self, req: &ZabbixRequest, is_config: bool) -> Result<ProxyResponse> {
         let read_data = self.proto.send(&req.str())?;
-        let response = if is_config {
-            ProxyResponse::CONFIG(serde_json::from_slice(&read_data)?)
-        } else {
-            ProxyResponse::RESPONSE(serde_json::from_slice(&read_data)?)
-        };
-
-        Ok(response)
+        parse_response(&read_data, is_config)
     }
 
     ///
@@ -50,24 +227,38 @@ impl ZabbixProxy {
     ///
     pub fn get_config(&self) -> Option<Value> {
         let req = ZabbixRequest::new(Self::PROXY_CONFIG, &self.name, Value::Null);
-        if let Ok(r) = self.send_request(&req, true) {
-            if let ProxyResponse::CONFIG(c) = r {
-                return Some(c);
-            }
+        if let Ok(ProxyResponse::CONFIG(c)) = self.send_request(&req, true) {
+            return Some(c);
         }
         None
     }
 
+    ///
+    /// 按配置版本增量同步配置 (Zabbix 6.4 以后)，返回主机和监控项的变化。
+    /// 旧版本的服务端返回完整配置，同样可以使用
+    ///
+    pub fn sync_config(&self) -> Result<Vec<ConfigEvent>> {
+        let revision = self.lock().config.revision();
+        let req = ProxyConfigRequest {
+            request: Self::PROXY_CONFIG,
+            host: &self.name,
+            version: &self.version,
+            session: &self.session,
+            config_revision: revision,
+        };
+        let read_data = self.proto.send(&serde_json::to_string(&req)?)?;
+        let config: Value = serde_json::from_slice(&read_data)?;
+        self.lock().config.apply(&config)
+    }
+
     ///
     /// 自动注册主机
     ///
     pub fn auto_register(&self, hosts: Vec<ZabbixHost>) -> Result<bool> {
         let hosts = serde_json::to_value(hosts)?;
         let req = ZabbixRequest::new(Self::AUTO_REGISTRATION, &self.name, hosts);
-        if let Ok(r) = self.send_request(&req, false) {
-            if let ProxyResponse::RESPONSE(c) = r {
-                return Ok(c.success());
-            }
+        if let Ok(ProxyResponse::RESPONSE(c)) = self.send_request(&req, false) {
+            return Ok(c.success());
         }
         Ok(false)
     }
@@ -77,43 +268,79 @@ impl ZabbixProxy {
     ///
     pub fn heart_beat(&self) -> Result<bool> {
         let req = ZabbixRequest::new(Self::PROXY_HEARTBEAT, &self.name, Value::Null);
-        if let Ok(r) = self.send_request(&req, false) {
-            if let ProxyResponse::RESPONSE(c) = r {
-                return Ok(c.success());
-            }
+        if let Ok(ProxyResponse::RESPONSE(c)) = self.send_request(&req, false) {
+            return Ok(c.success());
         }
         Ok(false)
     }
 
     ///
-    /// 向服务端发送历史数据
+    /// 向服务端发送 proxy data (Zabbix 4.0 以后)，应答中包含需要 proxy 执行的任务
+    ///
+    pub fn send_proxy_data(&self, data: &ProxyData) -> Result<ProxyDataResponse> {
+        let read_data = self.proto.send(&serde_json::to_string(data)?)?;
+        Ok(serde_json::from_slice(&read_data)?)
+    }
+
+    ///
+    /// 向服务端发送历史数据，使用 Zabbix 4.0 以前的 history data 请求，
+    /// 新版本的服务端使用 `send_proxy_data`
     ///
     pub fn send_data(&self, data: &[ZabbixMetric]) -> Result<bool> {
         let data = serde_json::to_value(data)?;
         let req = ZabbixRequest::new(Self::HISTORY_DATA, &self.name, data);
-        if let Ok(r) = self.send_request(&req, false) {
-            if let ProxyResponse::RESPONSE(c) = r {
-                //trace!("{:?}", c);
-                return Ok(c.success() && c.ok());
-            }
+        if let Ok(ProxyResponse::RESPONSE(c)) = self.send_request(&req, false) {
+            //trace!("{:?}", c);
+            return Ok(c.success() && c.ok());
         }
         Ok(false)
     }
 }
 
+///
+/// Zabbix 6.4 以后的服务端只发送请求，不包含配置数据，旧版本的服务端在请求中直接包含各个表
+///
+fn is_config_handshake(req: &Value) -> bool {
+    req.as_object()
+        .is_some_and(|o| o.keys().all(|k| k == "request" || k == "version"))
+}
+
+pub(crate) fn parse_response(read_data: &[u8], is_config: bool) -> Result<ProxyResponse> {
+    let response = if is_config {
+        ProxyResponse::CONFIG(serde_json::from_slice(read_data)?)
+    } else {
+        ProxyResponse::RESPONSE(serde_json::from_slice(read_data)?)
+    };
+
+    Ok(response)
+}
+
 /// 扩展代理功能
 impl ZabbixProxy {
-    pub fn get_proxy_config(&self, compress: &[&str]) -> Option<(HashSet<Host>, HashSet<Item>)> {
-        if let Some(v) = self.get_config() {
-            let h = Host::from(get_item(&v["hosts"]["fields"], &v["hosts"]["data"]));
-            let i = Item::from(
-                get_item(&v["items"]["fields"], &v["items"]["data"]),
-                compress,
-            );
-
-            return Some((h, i));
+    ///
+    /// 使用 registry 在本地采集监控项，不支持的监控项记录日志后跳过
+    ///
+    pub fn poll_items(&self, items: &[ItemHost]) -> Vec<ZabbixMetric> {
+        let mut result = Vec::with_capacity(items.len());
+        for ih in items {
+            match self.registry.get(&ih.item.key_) {
+                Ok(value) => result.push(ZabbixMetric::new(
+                    &ih.host.host,
+                    &ih.item.key_,
+                    &value.to_string(),
+                )),
+                Err(e) => warn!(
+                    "item [{}:{}] became not supported: {}",
+                    ih.host.host, ih.item.key_, e
+                ),
+            }
         }
-        None
+        result
+    }
+
+    pub fn get_proxy_config(&self, compress: &[&str]) -> Option<(HashSet<Host>, HashSet<Item>)> {
+        let config = ProxyConfig::from_value(&self.get_config()?).ok()?;
+        Some((config.monitored_hosts(), config.monitored_items(compress)))
     }
 
     pub fn get_proxy_config_item(&self, compress: &[&str]) -> Option<Vec<ItemHost>> {
@@ -165,44 +392,6 @@ impl ZabbixProxy {
     }
 }
 
-fn get_item(field: &Value, data: &Value) -> Vec<HashMap<String, Value>> {
-    let mut result = Vec::new();
-    if let Some(field) = field.as_array() {
-        let field = field.iter().map(|x| x.as_str());
-        if let Some(data) = data.as_array() {
-            for x in data.iter() {
-                let mut hm: HashMap<String, Value> = HashMap::new();
-                let y = field.clone().zip(x.as_array().unwrap().iter());
-                for z in y {
-                    hm.insert(z.0.unwrap().to_string(), z.1.clone());
-                }
-                result.push(hm);
-            }
-        }
-    }
-    result
-}
-
-/*
-fn get_item(field: &Value, data: &Value) -> Vec<HashMap<String, Value>> {
-    let mut result = Vec::new();
-
-    if let Some(field) = field.as_array() {
-        let field = field.iter().map(|x| x.as_str());
-        let data = data.as_array().unwrap();
-        for x in data.iter() {
-            let mut hm: HashMap<String, Value> = HashMap::new();
-            let y = field.clone().zip(x.as_array().unwrap().iter());
-            for z in y {
-                hm.insert(z.0.unwrap().to_string(), z.1.clone());
-            }
-            result.push(hm);
-        }
-    }
-    result
-}
-*/
-
 #[derive(Hash, Eq, PartialEq, Clone, Debug)]
 pub struct HostItem {
     pub host: Host,
@@ -227,13 +416,18 @@ impl Host {
         Self { hostid, host }
     }
 
+    ///
+    /// 已启用的主机，格式错误的行记录日志后跳过
+    ///
     pub fn from(data: Vec<HashMap<String, Value>>) -> HashSet<Self> {
         let mut result = HashSet::new();
-        for d in data {
-            if let Some(0) = d["status"].as_i64() {
-                let hostid = d["hostid"].as_i64().unwrap();
-                let host = d["host"].as_str().unwrap();
-                result.insert(Self::new(hostid, host.to_string()));
+        for (i, row) in data.iter().enumerate() {
+            match ConfigHost::from_row(&RowReader::new("hosts", i, row)) {
+                Ok(h) if h.is_monitored() => {
+                    result.insert(Self::new(h.hostid, h.host));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("invalid proxy configuration: {}", e),
             }
         }
         result
@@ -245,52 +439,374 @@ pub struct Item {
     pub itemid: i64,
     pub hostid: i64,
     pub key_: String,
-    pub delay: u32,
+    pub delay: Delay,
 }
 
 impl Item {
-    pub fn new(itemid: i64, hostid: i64, key_: String, delay: u32) -> Self {
+    pub fn new<D: Into<Delay>>(itemid: i64, hostid: i64, key_: String, delay: D) -> Self {
         Self {
             itemid,
             hostid,
             key_,
-            delay,
+            delay: delay.into(),
         }
     }
 
+    ///
+    /// 晚于 now 的下次检查时间，见 `Delay::next_check`
+    ///
+    pub fn next_check(&self, now: i64) -> Option<i64> {
+        self.delay.next_check(self.itemid, now)
+    }
+
+    ///
+    /// 已启用且需要检查的监控项，格式错误的行记录日志后跳过。
+    /// 这里没有宏定义，间隔使用用户宏的监控项被跳过，key 中的宏不会替换
+    ///
+    #[deprecated(
+        since = "0.4.0",
+        note = "does not resolve user macros, use `ProxyConfig::monitored_items` or `ConfigCache::items`"
+    )]
     pub fn from(data: Vec<HashMap<String, Value>>, compress: &[&str]) -> HashSet<Self> {
         let mut result = HashSet::new();
-        for d in data {
-            if let Some(0) = d["status"].as_i64() {
-                let delay = trans(d["delay"].as_str().expect("delay"));
-                if delay == 0 {
+        for (i, row) in data.iter().enumerate() {
+            match ConfigItem::from_row(&RowReader::new("items", i, row)) {
+                Ok(item) if item.is_monitored() => match item.delay() {
+                    Ok(delay) if delay.is_active() => {
+                        let key_ = compress_key(&item.key_, compress);
+                        result.insert(Self::new(item.itemid, item.hostid, key_, delay));
+                    }
+                    _ if contains_macro(&item.delay) => warn!(
+                        "item {} skipped, update interval \"{}\" contains user macros",
+                        item.itemid, item.delay
+                    ),
+                    _ => {}
+                },
+                Ok(_) => {}
+                Err(e) => warn!("invalid proxy configuration: {}", e),
+            }
+        }
+        result
+    }
+}
+
+///
+/// 合并监控项 key：compress 包含 `[` 时去掉参数，其余分隔符只截断 key 名称，
+/// 名称被截断时同样去掉参数。无法解析的 key 保持原样
+///
+pub(crate) fn compress_key(key: &str, compress: &[&str]) -> String {
+    let item_key = match ItemKey::parse(key) {
+        Ok(k) => k,
+        Err(_) => return String::from(key),
+    };
+
+    let mut name = item_key.name();
+    for s in compress.iter().filter(|s| **s != "[") {
+        name = name.split(s).next().unwrap_or(name);
+    }
+
+    if compress.contains(&"[") || name != item_key.name() {
+        String::from(name)
+    } else {
+        String::from(key)
+    }
+}
+
+/// 被动模式：服务端连接 proxy 下发配置、获取数据和任务
+impl ZabbixProxy {
+    ///
+    /// 保存本地采集的数据，等待服务端获取，超过 ProxyOfflineBuffer 的数据被丢弃
+    ///
+    pub fn push_data(&self, data: Vec<HistoryValue>) {
+        let mut state = self.lock();
+        for mut value in data {
+            state.last_id += 1;
+            value.id = state.last_id;
+            state.history.push(value);
+        }
+
+        let oldest = Local::now().timestamp() - self.offline_buffer.as_secs() as i64;
+        let count = state.history.len();
+        state.history.retain(|v| v.clock >= oldest);
+        if state.history.len() < count {
+            warn!(
+                "{} values older than the offline buffer were discarded",
+                count - state.history.len()
+            );
+        }
+    }
+
+    ///
+    /// 等待服务端获取的数据条数
+    ///
+    pub fn pending_data(&self) -> usize {
+        self.lock().history.len()
+    }
+
+    ///
+    /// 当前配置缓存的副本
+    ///
+    pub fn config(&self) -> ConfigCache {
+        self.lock().config.clone()
+    }
+
+    ///
+    /// 被动模式：取出服务端下发配置后产生的变化事件
+    ///
+    pub fn take_config_events(&self) -> Vec<ConfigEvent> {
+        std::mem::take(&mut self.lock().events)
+    }
+
+    ///
+    /// 取出服务端下发的任务（远程命令等），由调用方执行
+    ///
+    pub fn take_tasks(&self) -> Vec<ProxyTask> {
+        std::mem::take(&mut self.lock().tasks)
+    }
+
+    ///
+    /// 保存任务的执行结果，随下一次 proxy data 或 proxy tasks 返回给服务端
+    ///
+    pub fn push_task_result(&self, result: ProxyTask) {
+        self.lock().task_results.push(result);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ProxyState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    ///
+    /// 按 ProxyMode 运行主动或被动模式，不会返回
+    ///
+    pub fn run(&self) -> Result<()> {
+        match self.mode {
+            ProxyMode::Active => self.run_active(),
+            ProxyMode::Passive => self.run_passive(),
+        }
+    }
+
+    ///
+    /// 运行主动模式，不会返回：按 ConfigFrequency 同步配置，按 DataSenderFrequency 发送数据，
+    /// HeartbeatFrequency 不为 0 时发送心跳。配置的变化通过 `take_config_events` 获取，
+    /// 服务端下发的任务通过 `take_tasks` 获取
+    ///
+    pub fn run_active(&self) -> Result<()> {
+        let now = Instant::now();
+        let mut next_config = now;
+        let mut next_send = now + self.data_sender_frequency;
+        let mut next_heartbeat = now;
+
+        loop {
+            let now = Instant::now();
+            if now >= next_config {
+                match self.sync_config() {
+                    Ok(events) => self.lock().events.extend(events),
+                    Err(e) => warn!("failed to get configuration from server: {}", e),
+                }
+                next_config = now + self.config_frequency;
+            }
+
+            if now >= next_send {
+                if let Err(e) = self.flush_data() {
+                    warn!("failed to send proxy data: {}", e);
+                }
+                next_send = now + self.data_sender_frequency;
+            }
+
+            let mut wake = next_config.min(next_send);
+            if !self.heartbeat_frequency.is_zero() {
+                if now >= next_heartbeat {
+                    match self.heart_beat() {
+                        Ok(true) => {}
+                        Ok(false) => warn!("server did not accept heartbeat"),
+                        Err(e) => warn!("failed to send heartbeat: {}", e),
+                    }
+                    next_heartbeat = now + self.heartbeat_frequency;
+                }
+                wake = wake.min(next_heartbeat);
+            }
+
+            let wait = wake.saturating_duration_since(Instant::now());
+            thread::sleep(wait.min(Duration::from_secs(1)));
+        }
+    }
+
+    ///
+    /// 在 ListenPort 上运行被动模式，不会返回
+    ///
+    pub fn run_passive(&self) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.listen_port))?;
+        self.serve(listener)
+    }
+
+    ///
+    /// 在已绑定的端口上处理服务端的请求，每个连接使用一个线程
+    ///
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        #[cfg(feature = "tls")]
+        let acceptor = match &self.tls {
+            Some(tls) => Some(Arc::new(tls.acceptor()?)),
+            None => None,
+        };
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("failed to accept connection: {}", e);
                     continue;
                 }
-                let itemid = d["itemid"].as_i64().unwrap();
-                let hostid = d["hostid"].as_i64().unwrap();
-                let mut key_ = d["key_"].as_str().expect("key_");
+            };
+            if let Some(peers) = &self.allowed_peers {
+                if !peers.accepts(&stream) {
+                    continue;
+                }
+            }
+            let timeouts = stream
+                .set_read_timeout(Some(self.timeout))
+                .and_then(|_| stream.set_write_timeout(Some(self.timeout)));
+            if let Err(e) = timeouts {
+                warn!("failed to set connection timeout: {}", e);
+                continue;
+            }
+
+            let proxy = self.clone();
+            #[cfg(feature = "tls")]
+            let acceptor = acceptor.clone();
+            thread::spawn(move || {
+                let peer = stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
 
-                for s in compress {
-                    key_ = key_.split(s).next().unwrap();
+                #[cfg(feature = "tls")]
+                let result = match acceptor {
+                    Some(acceptor) => acceptor.accept(stream).and_then(|s| proxy.handle(s)),
+                    None => proxy.handle(stream),
+                };
+                #[cfg(not(feature = "tls"))]
+                let result = proxy.handle(stream);
+
+                if let Err(e) = result {
+                    warn!("failed to process request from server {}: {}", peer, e);
                 }
+            });
+        }
+        Ok(())
+    }
+
+    ///
+    /// 处理服务端的一个连接。proxy config 同时支持 Zabbix 6.4 以后先交换配置版本的增量同步
+    /// 和旧版本在请求中直接发送完整配置的方式
+    ///
+    pub fn handle<S: Read + Write>(&self, s: S) -> Result<()> {
+        let mut stream = self.proto.stream(s);
+        let req: Value = serde_json::from_slice(&stream.read_frame()?)?;
+        let request = req["request"].as_str().unwrap_or_default();
+        trace!("passive proxy request: {}", request);
 
-                result.insert(Self::new(itemid, hostid, key_.to_string(), delay));
+        match request {
+            Self::PROXY_CONFIG if is_config_handshake(&req) => {
+                // Zabbix 6.4 以后：先返回本地的配置版本，服务端再发送增量配置
+                let revision = self.lock().config.revision();
+                let reply = serde_json::json!({
+                    "version": self.version,
+                    "session": self.session,
+                    "config_revision": revision,
+                });
+                stream.write_frame(reply.to_string().as_bytes())?;
+
+                let config: Value = serde_json::from_slice(&stream.read_frame()?)?;
+                let response = match self.update_config(config) {
+                    Ok(()) => serde_json::json!({"response": "success", "version": self.version}),
+                    Err(e) => serde_json::json!({
+                        "response": "failed",
+                        "info": e.to_string(),
+                        "version": self.version,
+                    }),
+                };
+                stream.write_frame(response.to_string().as_bytes())
+            }
+            Self::PROXY_CONFIG => {
+                let response = match self.update_config(req) {
+                    Ok(()) => Response::new("success", None),
+                    Err(e) => Response::new("failed", Some(e.to_string())),
+                };
+                stream.write_frame(serde_json::to_string(&response)?.as_bytes())
+            }
+            Self::PROXY_DATA | Self::PROXY_TASKS => {
+                let (data, last_id, results) = self.buffered_data(request == Self::PROXY_DATA);
+                stream.write_frame(serde_json::to_string(&data)?.as_bytes())?;
+
+                let response: ProxyDataResponse = serde_json::from_slice(&stream.read_frame()?)?;
+                self.acknowledge(&response, last_id, results);
+                if !response.success() {
+                    return Err(format_err!(
+                        "server did not accept {}: {}",
+                        request,
+                        response.info.unwrap_or_default()
+                    ));
+                }
+                Ok(())
+            }
+            _ => {
+                let info = format!("unsupported request \"{}\"", request);
+                let response = Response::new("failed", Some(info.clone()));
+                stream.write_frame(serde_json::to_string(&response)?.as_bytes())?;
+                Err(format_err!("{}", info))
             }
         }
-        result
     }
-}
 
-fn trans(input: &str) -> u32 {
-    if let Ok(result) = input.parse() {
-        return result;
+    fn update_config(&self, config: Value) -> Result<()> {
+        let mut state = self.lock();
+        let events = state.config.apply(&config)?;
+        debug!(
+            "received configuration revision {}, {} changes",
+            state.config.revision(),
+            events.len()
+        );
+        state.events.extend(events);
+        Ok(())
     }
 
-    if let Ok(x) = input.to_lowercase().parse::<humantime::Duration>() {
-        return x.as_secs() as u32;
+    ///
+    /// 主动模式：将本地保存的数据和任务结果发送给服务端，服务端下发的任务通过 `take_tasks` 获取
+    ///
+    pub fn flush_data(&self) -> Result<ProxyDataResponse> {
+        let (data, last_id, results) = self.buffered_data(true);
+        let response = self.send_proxy_data(&data)?;
+        self.acknowledge(&response, last_id, results);
+        Ok(response)
+    }
+
+    ///
+    /// 生成本地保存的数据，同时返回其中最大的历史数据 id 和任务结果数量，服务端确认后删除
+    ///
+    fn buffered_data(&self, history: bool) -> (ProxyData, u64, usize) {
+        let state = self.lock();
+        let mut data = ProxyData::new(&self.name, &self.session)
+            .with_version(&self.version)
+            .with_tasks(state.task_results.clone());
+        let mut last_id = 0;
+        if history {
+            last_id = state.history.last().map_or(0, |v| v.id);
+            data = data.with_history(state.history.clone());
+        }
+        (data, last_id, state.task_results.len())
     }
 
-    0
+    ///
+    /// 处理服务端的应答：接收成功时删除已发送的数据，保存下发的任务
+    ///
+    fn acknowledge(&self, response: &ProxyDataResponse, last_id: u64, results: usize) {
+        let mut state = self.lock();
+        if response.upload_enabled() {
+            state.history.retain(|v| v.id > last_id);
+            state.task_results.drain(..results);
+        }
+        state.tasks.extend(response.tasks.iter().cloned());
+    }
 }
 
 #[cfg(test)]
@@ -298,6 +814,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(deprecated)]
     fn test_item_from() {
         let mut data: Vec<HashMap<String, Value>> = vec![];
 
@@ -319,15 +836,247 @@ mod tests {
 
         let items = Item::from(data.clone(), &[]);
         assert_eq!(5, items.len());
+
+        // 格式错误的行跳过，不会 panic
+        data[0].remove("delay");
+        data[1].insert("hostid".to_string(), json!("3011"));
+        data[2].insert("itemid".to_string(), json!([]));
+        let items = Item::from(data, &[]);
+        assert_eq!(3, items.len());
+        assert!(items.contains(&Item::new(1, 3011, "df[1]".to_string(), 30)));
+
+        let mut host = HashMap::new();
+        host.insert("hostid".to_string(), json!("10084"));
+        assert!(Host::from(vec![host.clone()]).is_empty());
+        host.insert("host".to_string(), json!("h"));
+        assert_eq!(1, Host::from(vec![host]).len());
+    }
+
+    #[test]
+    fn test_poll_items() {
+        let mut registry = ItemRegistry::new();
+        registry.register("app.ping", |_| Ok(1u64));
+        let proxy = ZabbixProxy::new("proxy", "127.0.0.1", 10051).with_registry(registry);
+
+        let host = Host::new(10084, "host".to_string());
+        let items: Vec<ItemHost> = ["app.ping", "app.none"]
+            .iter()
+            .map(|key| ItemHost {
+                item: Item::new(1, 10084, key.to_string(), 30),
+                host: host.clone(),
+            })
+            .collect();
+        let metrics = proxy.poll_items(&items);
+        assert_eq!(1, metrics.len());
+        assert_eq!("host", metrics[0].host);
+        assert_eq!("app.ping", metrics[0].key);
+        assert_eq!("1", metrics[0].value);
+    }
+
+    #[test]
+    fn test_proxy_passive() {
+        let proxy = ZabbixProxy::new("proxy", "127.0.0.1", 10051)
+            .with_mode(ProxyMode::Passive)
+            .with_allowed_peers(AllowedPeers::parse("127.0.0.1").unwrap());
+        proxy.push_data(vec![
+            HistoryValue::new(10, "1"),
+            HistoryValue::new(11, "1").with_clock(0, 0),
+        ]);
+        assert_eq!(1, proxy.pending_data());
+        proxy.push_task_result(ProxyTask::command_result(1, 0, "ok"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = proxy.clone();
+        thread::spawn(move || server.serve(listener));
+
+        let zbx = ZabbixProtocol::new("127.0.0.1", port);
+        let send = |req: Value| -> Value {
+            serde_json::from_slice(&zbx.send(&req.to_string()).unwrap()).unwrap()
+        };
+        let hosts = json!({"fields": ["hostid", "host", "status"], "data": [[1, "h1", 0]]});
+        let config =
+            json!({"request": "proxy config", "config_revision": 3, "data": {"hosts": hosts}});
+        assert_eq!(json!({"response": "success", "info": null}), send(config));
+        assert_eq!(3, proxy.config().revision());
+        assert_eq!(
+            vec![ConfigEvent::HostAdded(Host::new(1, "h1".to_string()))],
+            proxy.take_config_events()
+        );
+        assert_eq!("failed", send(json!([]))["response"]);
+        assert_eq!("failed", send(json!({"request": "none"}))["response"]);
+
+        // proxy data 需要确认，使用同一个连接
+        let exchange = |request: &str, ack: Value| -> Value {
+            let s = zbx.connect().unwrap();
+            let mut stream = zbx.stream(s);
+            stream
+                .write_frame(json!({ "request": request }).to_string().as_bytes())
+                .unwrap();
+            let data = serde_json::from_slice(&stream.read_frame().unwrap()).unwrap();
+            stream.write_frame(ack.to_string().as_bytes()).unwrap();
+            // 等待 proxy 处理确认后关闭连接
+            let _ = stream.read_frame();
+            data
+        };
+        let data = exchange("proxy tasks", json!({"response": "success"}));
+        assert!(data.get("history data").is_none());
+        assert_eq!(1, data["tasks"][0]["parent_taskid"]);
+        assert_eq!(ProxyData::VERSION, data["version"]);
+
+        let failed = json!({"response": "failed", "info": "error"});
+        let data = exchange("proxy data", failed);
+        assert_eq!(10, data["history data"][0]["itemid"]);
+        assert_eq!(1, data["history data"][0]["id"]);
+        assert_eq!(1, proxy.pending_data());
+
+        let ack = json!({"response": "success", "tasks": [{"type": 2, "command": "uptime"}]});
+        exchange("proxy data", ack);
+        assert_eq!(0, proxy.pending_data());
+        assert_eq!(Some("uptime".to_string()), proxy.take_tasks()[0].command);
+        assert!(proxy.take_tasks().is_empty());
+
+        // Zabbix 6.4：proxy 先返回配置版本，再接收增量配置
+        let hosts = json!({"fields": ["hostid", "host", "status"], "data": [[2, "h2", 0]]});
+        let delta = json!({"config_revision": 4, "data": {"hosts": hosts}});
+        let reply = exchange("proxy config", delta);
+        assert_eq!(json!(3), reply["config_revision"]);
+        assert_eq!(ProxyData::VERSION, reply["version"]);
+        assert_eq!(proxy.session(), reply["session"]);
+        assert_eq!(4, proxy.config().revision());
+        assert_eq!(
+            vec![ConfigEvent::HostAdded(Host::new(2, "h2".to_string()))],
+            proxy.take_config_events()
+        );
+    }
+
+    #[test]
+    fn test_proxy_flush_data() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for (upload, stream) in ["disabled", "enabled"].iter().zip(listener.incoming()) {
+                let zbx = ZabbixProtocol::new("127.0.0.1", port);
+                let mut stream = zbx.stream(stream.unwrap());
+                let req: Value = serde_json::from_slice(&stream.read_frame().unwrap()).unwrap();
+                requests.push(req);
+                let response = json!({"response": "success", "upload": upload,
+                                      "tasks": [{"type": 2, "command": "uptime"}]});
+                stream.write_frame(response.to_string().as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let proxy = ZabbixProxy::new("proxy", "127.0.0.1", port);
+        proxy.push_data(vec![HistoryValue::new(10, "1")]);
+        assert!(!proxy.flush_data().unwrap().upload_enabled());
+        assert_eq!(1, proxy.pending_data());
+        assert!(proxy.flush_data().unwrap().upload_enabled());
+        assert_eq!(0, proxy.pending_data());
+        assert_eq!(2, proxy.take_tasks().len());
+
+        let requests = server.join().unwrap();
+        assert_eq!("proxy data", requests[1]["request"]);
+        assert_eq!("proxy", requests[1]["host"]);
+        assert_eq!(proxy.session(), requests[1]["session"]);
+        assert_eq!(10, requests[1]["history data"][0]["itemid"]);
+    }
+
+    #[test]
+    fn test_proxy_run_active() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests: Vec<String> = vec![];
+            for stream in listener.incoming() {
+                let zbx = ZabbixProtocol::new("127.0.0.1", port);
+                let mut stream = zbx.stream(stream.unwrap());
+                let req: Value = serde_json::from_slice(&stream.read_frame().unwrap()).unwrap();
+                let request = req["request"].as_str().unwrap().to_string();
+                let response = match request.as_str() {
+                    ZabbixProxy::PROXY_CONFIG => json!({"config_revision": 1, "full_sync": 1,
+                        "data": {"items": {"fields": ["itemid", "hostid", "key_", "delay", "status"],
+                                           "data": [[10, 1, "a", "30s", 0]]}}}),
+                    _ => json!({"response": "success"}),
+                };
+                stream.write_frame(response.to_string().as_bytes()).unwrap();
+                requests.push(request);
+
+                let count = |r: &str| requests.iter().filter(|x| *x == r).count();
+                if count(ZabbixProxy::PROXY_DATA) >= 2 && count(ZabbixProxy::PROXY_HEARTBEAT) >= 2 {
+                    return requests;
+                }
+            }
+            requests
+        });
+
+        let proxy = ZabbixProxy::new("proxy", "127.0.0.1", port)
+            .with_config_frequency(Duration::from_secs(3600))
+            .with_data_sender_frequency(Duration::from_millis(100))
+            .with_heartbeat_frequency(Duration::from_millis(150));
+        proxy.push_data(vec![HistoryValue::new(10, "1")]);
+        let runner = proxy.clone();
+        thread::spawn(move || runner.run());
+
+        let requests = server.join().unwrap();
+        assert_eq!(ZabbixProxy::PROXY_CONFIG, requests[0]);
+        assert_eq!(
+            1,
+            requests
+                .iter()
+                .filter(|r| *r == ZabbixProxy::PROXY_CONFIG)
+                .count()
+        );
+        assert_eq!(0, proxy.pending_data());
+        assert_eq!(1, proxy.take_config_events().len());
+    }
+
+    #[test]
+    fn test_proxy_sync_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let items = json!({"fields": ["itemid", "hostid", "key_", "delay", "status"],
+                               "data": [[10, 1, "a", "30s", 0]]});
+            let responses = [
+                json!({"config_revision": 7, "full_sync": 1, "data": {"items": items}}),
+                json!({"config_revision": 8, "data": {"items": {"del": [10]}}}),
+            ];
+            let mut revisions = vec![];
+            for (response, stream) in responses.iter().zip(listener.incoming()) {
+                let zbx = ZabbixProtocol::new("127.0.0.1", port);
+                let mut stream = zbx.stream(stream.unwrap());
+                let req: Value = serde_json::from_slice(&stream.read_frame().unwrap()).unwrap();
+                revisions.push(req["config_revision"].clone());
+                stream.write_frame(response.to_string().as_bytes()).unwrap();
+            }
+            revisions
+        });
+
+        let proxy = ZabbixProxy::new("proxy", "127.0.0.1", port);
+        assert_eq!(
+            vec![ConfigEvent::ItemAdded(Item::new(
+                10,
+                1,
+                "a".to_string(),
+                30
+            ))],
+            proxy.sync_config().unwrap()
+        );
+        assert_eq!(
+            vec![ConfigEvent::ItemRemoved(10)],
+            proxy.sync_config().unwrap()
+        );
+        assert_eq!(8, proxy.config().revision());
+        assert_eq!(vec![json!(0), json!(7)], server.join().unwrap());
     }
 
     #[test]
-    fn test_trans() {
-        assert_eq!(trans("15"), 15);
-        assert_eq!(trans("15s"), 15);
-        assert_eq!(trans("15S"), 15);
-        assert_eq!(trans("5m"), 300);
-        assert_eq!(trans("2h"), 7200);
-        assert_eq!(trans("1d"), 86400);
+    fn test_compress_key() {
+        assert_eq!("df", compress_key(r#"df["a[b]",c]"#, &["["]));
+        assert_eq!("vfs", compress_key("vfs_fs[a_b]", &["_"]));
+        assert_eq!("df[a_b]", compress_key("df[a_b]", &["_"]));
+        assert_eq!("df[a", compress_key("df[a", &["_"]));
     }
 }
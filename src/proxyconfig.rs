@@ -0,0 +1,1231 @@
+//! proxy 配置数据模型
+//!
+//! 服务端下发的配置按表组织：`{"<表名>": {"fields": [...], "data": [[...], ...]}}`。
+//! 每行按字段名解析为对应的类型，编号既可以是数字也可以是字符串 (5.4 以后)。
+//! 格式错误的行记录为包含表、行和字段的 `ConfigError` 后跳过，不影响其它行。
+//! 解析后的子表（接口、宏、预处理、Web 场景等）可以按所属的主机或监控项查找。
+use failure::Fail;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::configcache::ConfigCache;
+use super::delay::Delay;
+use super::proxy::{Host, Item};
+use super::usermacro::MacroResolver;
+use super::Result;
+
+/// 一行配置数据，字段名到值
+pub(crate) type Row = HashMap<String, Value>;
+
+/// 配置数据错误，包含表名、行号（从 0 开始）和字段
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub table: String,
+    pub row: Option<usize>,
+    pub field: Option<String>,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "table \"{}\"", self.table)?;
+        if let Some(row) = self.row {
+            write!(f, ", row {}", row)?;
+        }
+        if let Some(field) = &self.field {
+            write!(f, ", field \"{}\"", field)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+impl Fail for ConfigError {}
+
+///
+/// 按字段名读取一行数据，出错时返回带有位置信息的 `ConfigError`
+///
+pub(crate) struct RowReader<'a> {
+    table: &'a str,
+    index: usize,
+    row: &'a Row,
+}
+
+impl<'a> RowReader<'a> {
+    pub fn new(table: &'a str, index: usize, row: &'a Row) -> Self {
+        Self { table, index, row }
+    }
+
+    pub fn error(&self, field: &str, reason: &str) -> ConfigError {
+        ConfigError {
+            table: String::from(self.table),
+            row: Some(self.index),
+            field: Some(String::from(field)),
+            reason: String::from(reason),
+        }
+    }
+
+    fn get(&self, field: &str) -> Option<&'a Value> {
+        self.row.get(field).filter(|v| !v.is_null())
+    }
+
+    fn required(&self, field: &str) -> std::result::Result<&'a Value, ConfigError> {
+        self.get(field)
+            .ok_or_else(|| self.error(field, "missing field"))
+    }
+
+    fn to_int<T: TryFrom<i64>>(
+        &self,
+        field: &str,
+        value: &Value,
+    ) -> std::result::Result<T, ConfigError> {
+        let n = match value {
+            Value::Number(n) => n.as_i64(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        n.and_then(|n| T::try_from(n).ok())
+            .ok_or_else(|| self.error(field, &format!("invalid integer {}", value)))
+    }
+
+    fn to_str(&self, field: &str, value: &Value) -> std::result::Result<String, ConfigError> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Number(n) => Ok(n.to_string()),
+            _ => Err(self.error(field, &format!("invalid string {}", value))),
+        }
+    }
+
+    ///
+    /// 必需的整数字段，编号等可以是字符串
+    ///
+    pub fn int<T: TryFrom<i64>>(&self, field: &str) -> std::result::Result<T, ConfigError> {
+        self.to_int(field, self.required(field)?)
+    }
+
+    ///
+    /// 可选的整数字段，缺少或为 null 时使用 default
+    ///
+    pub fn int_or<T: TryFrom<i64>>(
+        &self,
+        field: &str,
+        default: T,
+    ) -> std::result::Result<T, ConfigError> {
+        match self.get(field) {
+            Some(v) => self.to_int(field, v),
+            None => Ok(default),
+        }
+    }
+
+    ///
+    /// 可选的编号字段，缺少、null 或 0 时为 None
+    ///
+    pub fn id(&self, field: &str) -> std::result::Result<Option<i64>, ConfigError> {
+        self.int_or(field, 0i64)
+            .map(|id| Some(id).filter(|id| *id != 0))
+    }
+
+    pub fn str(&self, field: &str) -> std::result::Result<String, ConfigError> {
+        self.to_str(field, self.required(field)?)
+    }
+
+    pub fn str_or(&self, field: &str, default: &str) -> std::result::Result<String, ConfigError> {
+        match self.get(field) {
+            Some(v) => self.to_str(field, v),
+            None => Ok(String::from(default)),
+        }
+    }
+}
+
+/// 可以从一行配置数据解析的类型
+pub(crate) trait FromRow: Sized {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError>;
+}
+
+///
+/// 将 `{"fields": [...], "data": [...]}` 转换为按字段名索引的行，
+/// 表格式错误时返回错误，与字段数不符的行记录错误后跳过
+///
+pub(crate) fn table_rows(
+    name: &str,
+    table: &Value,
+    errors: &mut Vec<ConfigError>,
+) -> std::result::Result<Vec<(usize, Row)>, ConfigError> {
+    let error = |row: Option<usize>, reason: &str| ConfigError {
+        table: String::from(name),
+        row,
+        field: None,
+        reason: String::from(reason),
+    };
+
+    let fields: Vec<&str> = match table.get("fields") {
+        Some(Value::Array(fields)) => fields
+            .iter()
+            .map(|f| f.as_str().ok_or_else(|| error(None, "invalid field name")))
+            .collect::<std::result::Result<_, _>>()?,
+        _ => return Err(error(None, "fields is not an array")),
+    };
+    let data = match table.get("data") {
+        Some(Value::Array(data)) => data.as_slice(),
+        None => &[],
+        Some(_) => return Err(error(None, "data is not an array")),
+    };
+
+    let mut rows = Vec::with_capacity(data.len());
+    for (i, row) in data.iter().enumerate() {
+        match row.as_array() {
+            Some(values) if values.len() == fields.len() => {
+                let row = fields
+                    .iter()
+                    .map(|f| f.to_string())
+                    .zip(values.iter().cloned())
+                    .collect();
+                rows.push((i, row));
+            }
+            _ => errors.push(error(
+                Some(i),
+                &format!("row does not match {} fields", fields.len()),
+            )),
+        }
+    }
+    Ok(rows)
+}
+
+fn parse_rows<'a, T, I, F>(table: &str, rows: I, errors: &mut Vec<ConfigError>, parse: F) -> Vec<T>
+where
+    I: IntoIterator<Item = (usize, &'a Row)>,
+    F: Fn(&RowReader) -> std::result::Result<T, ConfigError>,
+{
+    let mut result = vec![];
+    for (i, row) in rows {
+        match parse(&RowReader::new(table, i, row)) {
+            Ok(v) => result.push(v),
+            Err(e) => errors.push(e),
+        }
+    }
+    result
+}
+
+fn keyed<T, F: Fn(&T) -> i64>(values: Vec<T>, key: F) -> BTreeMap<i64, T> {
+    values.into_iter().map(|v| (key(&v), v)).collect()
+}
+
+/// 主机 (hosts)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigHost {
+    pub hostid: i64,
+    pub host: String,
+    pub name: String,
+    /// 0 为启用，3 为模板
+    pub status: u8,
+    pub proxy_hostid: Option<i64>,
+    /// 连接主机的加密方式：1 不加密，2 PSK，4 证书
+    pub tls_connect: u8,
+    /// 接受主机连接的加密方式，可以是多种的组合
+    pub tls_accept: u8,
+    pub tls_issuer: String,
+    pub tls_subject: String,
+    pub tls_psk_identity: String,
+    pub tls_psk: String,
+}
+
+impl FromRow for ConfigHost {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        let host = row.str("host")?;
+        Ok(Self {
+            hostid: row.int("hostid")?,
+            name: row.str_or("name", &host)?,
+            host,
+            status: row.int_or("status", 0)?,
+            proxy_hostid: row.id("proxy_hostid")?,
+            tls_connect: row.int_or("tls_connect", 1)?,
+            tls_accept: row.int_or("tls_accept", 1)?,
+            tls_issuer: row.str_or("tls_issuer", "")?,
+            tls_subject: row.str_or("tls_subject", "")?,
+            tls_psk_identity: row.str_or("tls_psk_identity", "")?,
+            tls_psk: row.str_or("tls_psk", "")?,
+        })
+    }
+}
+
+impl ConfigHost {
+    pub fn is_monitored(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// 监控项 (items)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigItem {
+    pub itemid: i64,
+    pub hostid: i64,
+    pub key_: String,
+    /// 原始的间隔，可能包含灵活间隔和宏
+    pub delay: String,
+    /// 0 为启用
+    pub status: u8,
+    /// 监控项类型 (type)，0 为 zabbix agent
+    pub item_type: u8,
+    pub value_type: u8,
+    pub interfaceid: Option<i64>,
+    /// 依赖监控项的主监控项
+    pub master_itemid: Option<i64>,
+    pub params: String,
+    pub timeout: String,
+    pub snmp_oid: String,
+    pub url: String,
+    pub trapper_hosts: String,
+}
+
+impl FromRow for ConfigItem {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            itemid: row.int("itemid")?,
+            hostid: row.int("hostid")?,
+            key_: row.str("key_")?,
+            delay: row.str_or("delay", "0")?,
+            status: row.int_or("status", 0)?,
+            item_type: row.int_or("type", 0)?,
+            value_type: row.int_or("value_type", 0)?,
+            interfaceid: row.id("interfaceid")?,
+            master_itemid: row.id("master_itemid")?,
+            params: row.str_or("params", "")?,
+            timeout: row.str_or("timeout", "")?,
+            snmp_oid: row.str_or("snmp_oid", "")?,
+            url: row.str_or("url", "")?,
+            trapper_hosts: row.str_or("trapper_hosts", "")?,
+        })
+    }
+}
+
+impl ConfigItem {
+    pub fn is_monitored(&self) -> bool {
+        self.status == 0
+    }
+
+    ///
+    /// 默认间隔（秒），间隔无效时为 0
+    ///
+    pub fn delay_secs(&self) -> u32 {
+        self.delay().map(|d| d.simple()).unwrap_or(0)
+    }
+
+    ///
+    /// 解析间隔，包含用户宏时需要先用 `MacroResolver` 替换
+    ///
+    pub fn delay(&self) -> Result<Delay> {
+        Delay::parse(&self.delay)
+    }
+}
+
+/// 主机接口 (interface)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigInterface {
+    pub interfaceid: i64,
+    pub hostid: i64,
+    /// 1 为默认接口
+    pub main: u8,
+    /// 1 agent，2 SNMP，3 IPMI，4 JMX
+    pub interface_type: u8,
+    /// 1 使用 ip，0 使用 dns
+    pub useip: u8,
+    pub ip: String,
+    pub dns: String,
+    pub port: String,
+}
+
+impl FromRow for ConfigInterface {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            interfaceid: row.int("interfaceid")?,
+            hostid: row.int("hostid")?,
+            main: row.int_or("main", 0)?,
+            interface_type: row.int_or("type", 1)?,
+            useip: row.int_or("useip", 1)?,
+            ip: row.str_or("ip", "")?,
+            dns: row.str_or("dns", "")?,
+            port: row.str_or("port", "")?,
+        })
+    }
+}
+
+impl ConfigInterface {
+    ///
+    /// 连接的地址，useip 为 1 时使用 ip，否则使用 dns
+    ///
+    pub fn address(&self) -> &str {
+        if self.useip == 1 {
+            &self.ip
+        } else {
+            &self.dns
+        }
+    }
+}
+
+/// 主机关联的模板 (hosts_templates)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigHostTemplate {
+    pub hosttemplateid: i64,
+    pub hostid: i64,
+    pub templateid: i64,
+}
+
+impl FromRow for ConfigHostTemplate {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            hosttemplateid: row.int("hosttemplateid")?,
+            hostid: row.int("hostid")?,
+            templateid: row.int("templateid")?,
+        })
+    }
+}
+
+/// 用户宏 (globalmacro / hostmacro)，全局宏的 hostid 为 None
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigMacro {
+    pub macroid: i64,
+    pub hostid: Option<i64>,
+    /// 包含上下文，如 `{$PORT:"http"}`
+    pub macro_: String,
+    pub value: String,
+    /// 0 文本，1 密文，2 vault
+    pub macro_type: u8,
+}
+
+impl ConfigMacro {
+    fn global(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Self::from_row(row, "globalmacroid", None)
+    }
+
+    fn host(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Self::from_row(row, "hostmacroid", Some(row.int("hostid")?))
+    }
+
+    fn from_row(
+        row: &RowReader,
+        id: &str,
+        hostid: Option<i64>,
+    ) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            macroid: row.int(id)?,
+            hostid,
+            macro_: row.str("macro")?,
+            value: row.str_or("value", "")?,
+            macro_type: row.int_or("type", 0)?,
+        })
+    }
+
+    pub fn is_secret(&self) -> bool {
+        self.macro_type == 1
+    }
+}
+
+/// 监控项预处理步骤 (item_preproc)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigPreproc {
+    pub item_preprocid: i64,
+    pub itemid: i64,
+    pub step: u32,
+    pub preproc_type: u8,
+    pub params: String,
+    pub error_handler: u8,
+    pub error_handler_params: String,
+}
+
+impl FromRow for ConfigPreproc {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            item_preprocid: row.int("item_preprocid")?,
+            itemid: row.int("itemid")?,
+            step: row.int("step")?,
+            preproc_type: row.int("type")?,
+            params: row.str_or("params", "")?,
+            error_handler: row.int_or("error_handler", 0)?,
+            error_handler_params: row.str_or("error_handler_params", "")?,
+        })
+    }
+}
+
+/// 监控项运行数据 (item_rtdata)，日志监控项的读取位置
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigItemRtdata {
+    pub itemid: i64,
+    pub lastlogsize: u64,
+    pub mtime: i64,
+}
+
+impl FromRow for ConfigItemRtdata {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            itemid: row.int("itemid")?,
+            lastlogsize: row.int_or("lastlogsize", 0)?,
+            mtime: row.int_or("mtime", 0)?,
+        })
+    }
+}
+
+/// 网络发现规则 (drules)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigDRule {
+    pub druleid: i64,
+    pub name: String,
+    pub iprange: String,
+    pub delay: String,
+    pub status: u8,
+}
+
+impl FromRow for ConfigDRule {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            druleid: row.int("druleid")?,
+            name: row.str_or("name", "")?,
+            iprange: row.str("iprange")?,
+            delay: row.str_or("delay", "1h")?,
+            status: row.int_or("status", 0)?,
+        })
+    }
+}
+
+/// 网络发现检查 (dchecks)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigDCheck {
+    pub dcheckid: i64,
+    pub druleid: i64,
+    pub check_type: u8,
+    pub key_: String,
+    pub snmp_community: String,
+    pub ports: String,
+    pub uniq: u8,
+}
+
+impl FromRow for ConfigDCheck {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            dcheckid: row.int("dcheckid")?,
+            druleid: row.int("druleid")?,
+            check_type: row.int("type")?,
+            key_: row.str_or("key_", "")?,
+            snmp_community: row.str_or("snmp_community", "")?,
+            ports: row.str_or("ports", "")?,
+            uniq: row.int_or("uniq", 0)?,
+        })
+    }
+}
+
+/// 全局正则表达式 (regexps)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigRegexp {
+    pub regexpid: i64,
+    pub name: String,
+}
+
+impl FromRow for ConfigRegexp {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            regexpid: row.int("regexpid")?,
+            name: row.str("name")?,
+        })
+    }
+}
+
+/// 全局正则表达式的条件 (expressions)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigExpression {
+    pub expressionid: i64,
+    pub regexpid: i64,
+    pub expression: String,
+    pub expression_type: u8,
+    pub exp_delimiter: String,
+    pub case_sensitive: u8,
+}
+
+impl FromRow for ConfigExpression {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            expressionid: row.int("expressionid")?,
+            regexpid: row.int("regexpid")?,
+            expression: row.str("expression")?,
+            expression_type: row.int_or("expression_type", 0)?,
+            exp_delimiter: row.str_or("exp_delimiter", "")?,
+            case_sensitive: row.int_or("case_sensitive", 0)?,
+        })
+    }
+}
+
+/// Web 场景 (httptest)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigHttpTest {
+    pub httptestid: i64,
+    pub hostid: i64,
+    pub name: String,
+    pub delay: String,
+    pub agent: String,
+    pub http_proxy: String,
+    pub retries: u32,
+    pub status: u8,
+}
+
+impl FromRow for ConfigHttpTest {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            httptestid: row.int("httptestid")?,
+            hostid: row.int("hostid")?,
+            name: row.str_or("name", "")?,
+            delay: row.str_or("delay", "1m")?,
+            agent: row.str_or("agent", "")?,
+            http_proxy: row.str_or("http_proxy", "")?,
+            retries: row.int_or("retries", 1)?,
+            status: row.int_or("status", 0)?,
+        })
+    }
+}
+
+/// Web 场景的步骤 (httpstep)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigHttpStep {
+    pub httpstepid: i64,
+    pub httptestid: i64,
+    pub name: String,
+    pub no: u32,
+    pub url: String,
+    pub timeout: String,
+    pub posts: String,
+    pub required: String,
+    pub status_codes: String,
+    pub follow_redirects: u8,
+    pub retrieve_mode: u8,
+}
+
+impl FromRow for ConfigHttpStep {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            httpstepid: row.int("httpstepid")?,
+            httptestid: row.int("httptestid")?,
+            name: row.str_or("name", "")?,
+            no: row.int_or("no", 0)?,
+            url: row.str("url")?,
+            timeout: row.str_or("timeout", "15s")?,
+            posts: row.str_or("posts", "")?,
+            required: row.str_or("required", "")?,
+            status_codes: row.str_or("status_codes", "")?,
+            follow_redirects: row.int_or("follow_redirects", 1)?,
+            retrieve_mode: row.int_or("retrieve_mode", 0)?,
+        })
+    }
+}
+
+/// Web 场景或步骤对应的监控项 (httptestitem / httpstepitem)，parentid 为场景或步骤
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigHttpItem {
+    pub id: i64,
+    pub parentid: i64,
+    pub itemid: i64,
+    pub item_type: u8,
+}
+
+impl ConfigHttpItem {
+    fn from_row(row: &RowReader, id: &str, parent: &str) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            id: row.int(id)?,
+            parentid: row.int(parent)?,
+            itemid: row.int("itemid")?,
+            item_type: row.int_or("type", 0)?,
+        })
+    }
+}
+
+/// Web 场景或步骤的变量、请求头等 (httptest_field / httpstep_field)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigHttpField {
+    pub id: i64,
+    pub parentid: i64,
+    /// 0 请求头，1 变量，2 POST 字段，3 查询字段
+    pub field_type: u8,
+    pub name: String,
+    pub value: String,
+}
+
+impl ConfigHttpField {
+    fn from_row(row: &RowReader, id: &str, parent: &str) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            id: row.int(id)?,
+            parentid: row.int(parent)?,
+            field_type: row.int_or("type", 0)?,
+            name: row.str_or("name", "")?,
+            value: row.str_or("value", "")?,
+        })
+    }
+}
+
+/// 全局设置 (config)，字段随服务端版本变化，保留所有字段
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigSettings {
+    pub configid: i64,
+    pub fields: HashMap<String, Value>,
+}
+
+impl FromRow for ConfigSettings {
+    fn from_row(row: &RowReader) -> std::result::Result<Self, ConfigError> {
+        Ok(Self {
+            configid: row.int("configid")?,
+            fields: row.row.clone(),
+        })
+    }
+}
+
+impl ConfigSettings {
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.fields.get(field)
+    }
+}
+
+/// 子表到父表的关联
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Link {
+    HostInterfaces,
+    HostTemplates,
+    HostMacros,
+    HostItems,
+    ItemPreproc,
+    DRuleChecks,
+    RegexpExpressions,
+    HostHttpTests,
+    HttpTestSteps,
+    HttpTestItems,
+    HttpStepItems,
+    HttpTestFields,
+    HttpStepFields,
+}
+
+/// proxy 配置
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxyConfig {
+    /// 主机和模板
+    pub hosts: BTreeMap<i64, ConfigHost>,
+    pub items: BTreeMap<i64, ConfigItem>,
+    pub interfaces: BTreeMap<i64, ConfigInterface>,
+    pub hosts_templates: BTreeMap<i64, ConfigHostTemplate>,
+    pub global_macros: BTreeMap<i64, ConfigMacro>,
+    pub host_macros: BTreeMap<i64, ConfigMacro>,
+    pub item_preproc: BTreeMap<i64, ConfigPreproc>,
+    /// 以 itemid 为键
+    pub item_rtdata: BTreeMap<i64, ConfigItemRtdata>,
+    pub drules: BTreeMap<i64, ConfigDRule>,
+    pub dchecks: BTreeMap<i64, ConfigDCheck>,
+    pub regexps: BTreeMap<i64, ConfigRegexp>,
+    pub expressions: BTreeMap<i64, ConfigExpression>,
+    pub httptests: BTreeMap<i64, ConfigHttpTest>,
+    pub httpsteps: BTreeMap<i64, ConfigHttpStep>,
+    pub httptest_items: BTreeMap<i64, ConfigHttpItem>,
+    pub httpstep_items: BTreeMap<i64, ConfigHttpItem>,
+    pub httptest_fields: BTreeMap<i64, ConfigHttpField>,
+    pub httpstep_fields: BTreeMap<i64, ConfigHttpField>,
+    /// 全局设置 (config 表的第一行)
+    pub settings: Option<ConfigSettings>,
+    /// 跳过的表或行
+    pub errors: Vec<ConfigError>,
+    /// (关联, 父编号) 到子编号
+    links: HashMap<(Link, i64), Vec<i64>>,
+}
+
+impl ProxyConfig {
+    ///
+    /// 解析服务端返回的配置，表可以在顶层或 `data` 中
+    ///
+    pub fn from_value(config: &Value) -> Result<Self> {
+        let object = config
+            .as_object()
+            .ok_or_else(|| format_err!("invalid configuration data"))?;
+        let tables = match object.get("data") {
+            Some(Value::Object(data)) => data,
+            _ => object,
+        };
+
+        let mut errors = vec![];
+        let mut rows = HashMap::new();
+        for name in Self::TABLES {
+            if let Some(table) = tables.get(*name) {
+                match table_rows(name, table, &mut errors) {
+                    Ok(r) => {
+                        rows.insert(*name, r);
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+        }
+        Ok(Self::from_rows(&rows, errors))
+    }
+
+    ///
+    /// 从配置缓存生成，行号为按主键排序后的位置
+    ///
+    pub fn from_cache(cache: &ConfigCache) -> Self {
+        Self::from_cache_tables(cache, Self::TABLES)
+    }
+
+    ///
+    /// 只解析缓存中指定的表
+    ///
+    pub(crate) fn from_cache_tables(cache: &ConfigCache, tables: &[&'static str]) -> Self {
+        let rows = tables
+            .iter()
+            .map(|name| (*name, cache.rows(name).into_iter().enumerate().collect()))
+            .collect();
+        Self::from_rows(&rows, vec![])
+    }
+
+    /// 解析的表
+    const TABLES: &'static [&'static str] = &[
+        "hosts",
+        "items",
+        "interface",
+        "hosts_templates",
+        "globalmacro",
+        "hostmacro",
+        "item_preproc",
+        "item_rtdata",
+        "drules",
+        "dchecks",
+        "regexps",
+        "expressions",
+        "httptest",
+        "httpstep",
+        "httptestitem",
+        "httpstepitem",
+        "httptest_field",
+        "httpstep_field",
+        "config",
+    ];
+
+    fn from_rows(rows: &HashMap<&str, Vec<(usize, Row)>>, mut errors: Vec<ConfigError>) -> Self {
+        let table = |name: &str| -> Vec<(usize, &Row)> {
+            rows.get(name)
+                .map(|r| r.iter().map(|(i, row)| (*i, row)).collect())
+                .unwrap_or_default()
+        };
+        let e = &mut errors;
+
+        let hosts = parse_rows("hosts", table("hosts"), e, ConfigHost::from_row);
+        let items = parse_rows("items", table("items"), e, ConfigItem::from_row);
+        let interfaces = parse_rows(
+            "interface",
+            table("interface"),
+            e,
+            ConfigInterface::from_row,
+        );
+        let hosts_templates = parse_rows(
+            "hosts_templates",
+            table("hosts_templates"),
+            e,
+            ConfigHostTemplate::from_row,
+        );
+        let global_macros = parse_rows("globalmacro", table("globalmacro"), e, ConfigMacro::global);
+        let host_macros = parse_rows("hostmacro", table("hostmacro"), e, ConfigMacro::host);
+        let item_preproc = parse_rows(
+            "item_preproc",
+            table("item_preproc"),
+            e,
+            ConfigPreproc::from_row,
+        );
+        let item_rtdata = parse_rows(
+            "item_rtdata",
+            table("item_rtdata"),
+            e,
+            ConfigItemRtdata::from_row,
+        );
+        let drules = parse_rows("drules", table("drules"), e, ConfigDRule::from_row);
+        let dchecks = parse_rows("dchecks", table("dchecks"), e, ConfigDCheck::from_row);
+        let regexps = parse_rows("regexps", table("regexps"), e, ConfigRegexp::from_row);
+        let expressions = parse_rows(
+            "expressions",
+            table("expressions"),
+            e,
+            ConfigExpression::from_row,
+        );
+        let httptests = parse_rows("httptest", table("httptest"), e, ConfigHttpTest::from_row);
+        let httpsteps = parse_rows("httpstep", table("httpstep"), e, ConfigHttpStep::from_row);
+        let httptest_items = parse_rows("httptestitem", table("httptestitem"), e, |row| {
+            ConfigHttpItem::from_row(row, "httptestitemid", "httptestid")
+        });
+        let httpstep_items = parse_rows("httpstepitem", table("httpstepitem"), e, |row| {
+            ConfigHttpItem::from_row(row, "httpstepitemid", "httpstepid")
+        });
+        let httptest_fields = parse_rows("httptest_field", table("httptest_field"), e, |row| {
+            ConfigHttpField::from_row(row, "httptest_fieldid", "httptestid")
+        });
+        let httpstep_fields = parse_rows("httpstep_field", table("httpstep_field"), e, |row| {
+            ConfigHttpField::from_row(row, "httpstep_fieldid", "httpstepid")
+        });
+        let settings = parse_rows("config", table("config"), e, ConfigSettings::from_row);
+
+        for e in &errors {
+            warn!("invalid proxy configuration: {}", e);
+        }
+        let mut config = Self {
+            hosts: keyed(hosts, |h| h.hostid),
+            items: keyed(items, |i| i.itemid),
+            interfaces: keyed(interfaces, |i| i.interfaceid),
+            hosts_templates: keyed(hosts_templates, |t| t.hosttemplateid),
+            global_macros: keyed(global_macros, |m| m.macroid),
+            host_macros: keyed(host_macros, |m| m.macroid),
+            item_preproc: keyed(item_preproc, |p| p.item_preprocid),
+            item_rtdata: keyed(item_rtdata, |r| r.itemid),
+            drules: keyed(drules, |r| r.druleid),
+            dchecks: keyed(dchecks, |c| c.dcheckid),
+            regexps: keyed(regexps, |r| r.regexpid),
+            expressions: keyed(expressions, |e| e.expressionid),
+            httptests: keyed(httptests, |t| t.httptestid),
+            httpsteps: keyed(httpsteps, |s| s.httpstepid),
+            httptest_items: keyed(httptest_items, |i| i.id),
+            httpstep_items: keyed(httpstep_items, |i| i.id),
+            httptest_fields: keyed(httptest_fields, |f| f.id),
+            httpstep_fields: keyed(httpstep_fields, |f| f.id),
+            settings: settings.into_iter().next(),
+            errors,
+            links: HashMap::new(),
+        };
+        config.build_links();
+        config
+    }
+
+    fn build_links(&mut self) {
+        let mut links: HashMap<(Link, i64), Vec<i64>> = HashMap::new();
+        let mut add = |link, parent, child| links.entry((link, parent)).or_default().push(child);
+
+        for i in self.interfaces.values() {
+            add(Link::HostInterfaces, i.hostid, i.interfaceid);
+        }
+        for t in self.hosts_templates.values() {
+            add(Link::HostTemplates, t.hostid, t.templateid);
+        }
+        for m in self.host_macros.values() {
+            add(Link::HostMacros, m.hostid.unwrap_or(0), m.macroid);
+        }
+        for i in self.items.values() {
+            add(Link::HostItems, i.hostid, i.itemid);
+        }
+        let mut preproc: Vec<&ConfigPreproc> = self.item_preproc.values().collect();
+        preproc.sort_by_key(|p| (p.itemid, p.step));
+        for p in preproc {
+            add(Link::ItemPreproc, p.itemid, p.item_preprocid);
+        }
+        for c in self.dchecks.values() {
+            add(Link::DRuleChecks, c.druleid, c.dcheckid);
+        }
+        for e in self.expressions.values() {
+            add(Link::RegexpExpressions, e.regexpid, e.expressionid);
+        }
+        for t in self.httptests.values() {
+            add(Link::HostHttpTests, t.hostid, t.httptestid);
+        }
+        let mut steps: Vec<&ConfigHttpStep> = self.httpsteps.values().collect();
+        steps.sort_by_key(|s| (s.httptestid, s.no));
+        for s in steps {
+            add(Link::HttpTestSteps, s.httptestid, s.httpstepid);
+        }
+        for i in self.httptest_items.values() {
+            add(Link::HttpTestItems, i.parentid, i.itemid);
+        }
+        for i in self.httpstep_items.values() {
+            add(Link::HttpStepItems, i.parentid, i.itemid);
+        }
+        for f in self.httptest_fields.values() {
+            add(Link::HttpTestFields, f.parentid, f.id);
+        }
+        for f in self.httpstep_fields.values() {
+            add(Link::HttpStepFields, f.parentid, f.id);
+        }
+        self.links = links;
+    }
+
+    fn linked<'a, T>(&self, link: Link, id: i64, map: &'a BTreeMap<i64, T>) -> Vec<&'a T> {
+        self.links
+            .get(&(link, id))
+            .map(|ids| ids.iter().filter_map(|id| map.get(id)).collect())
+            .unwrap_or_default()
+    }
+
+    ///
+    /// 已启用的主机
+    ///
+    pub fn monitored_hosts(&self) -> HashSet<Host> {
+        self.hosts
+            .values()
+            .filter(|h| h.is_monitored())
+            .map(|h| Host::new(h.hostid, h.host.clone()))
+            .collect()
+    }
+
+    ///
+    /// 已启用且需要检查的监控项，key 和间隔中的用户宏按主机解析，
+    /// compress 的含义与 `ZabbixProxy::get_proxy_config` 相同
+    ///
+    pub fn monitored_items(&self, compress: &[&str]) -> HashSet<Item> {
+        let macros = self.macros();
+        self.items
+            .values()
+            .filter_map(|i| macros.item(i, compress))
+            .collect()
+    }
+
+    pub fn macros(&self) -> MacroResolver {
+        MacroResolver::new(self)
+    }
+
+    pub fn host_items(&self, hostid: i64) -> Vec<&ConfigItem> {
+        self.linked(Link::HostItems, hostid, &self.items)
+    }
+
+    pub fn item_host(&self, itemid: i64) -> Option<&ConfigHost> {
+        self.items
+            .get(&itemid)
+            .and_then(|i| self.hosts.get(&i.hostid))
+    }
+
+    pub fn host_interfaces(&self, hostid: i64) -> Vec<&ConfigInterface> {
+        self.linked(Link::HostInterfaces, hostid, &self.interfaces)
+    }
+
+    ///
+    /// 监控项使用的接口，没有指定时使用主机同类型的默认接口
+    ///
+    pub fn item_interface(&self, itemid: i64) -> Option<&ConfigInterface> {
+        let item = self.items.get(&itemid)?;
+        match item.interfaceid {
+            Some(id) => self.interfaces.get(&id),
+            None => self
+                .host_interfaces(item.hostid)
+                .into_iter()
+                .find(|i| i.main == 1 && i.interface_type == Self::interface_type(item.item_type)),
+        }
+    }
+
+    /// 监控项类型对应的接口类型
+    fn interface_type(item_type: u8) -> u8 {
+        match item_type {
+            20 => 2, // SNMP
+            12 => 3, // IPMI
+            16 => 4, // JMX
+            _ => 1,
+        }
+    }
+
+    ///
+    /// 主机直接关联的模板编号
+    ///
+    pub fn host_templates(&self, hostid: i64) -> Vec<i64> {
+        self.links
+            .get(&(Link::HostTemplates, hostid))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn host_macros(&self, hostid: i64) -> Vec<&ConfigMacro> {
+        self.linked(Link::HostMacros, hostid, &self.host_macros)
+    }
+
+    ///
+    /// 监控项的预处理步骤，按 step 排序
+    ///
+    pub fn item_preprocessing(&self, itemid: i64) -> Vec<&ConfigPreproc> {
+        self.linked(Link::ItemPreproc, itemid, &self.item_preproc)
+    }
+
+    pub fn drule_checks(&self, druleid: i64) -> Vec<&ConfigDCheck> {
+        self.linked(Link::DRuleChecks, druleid, &self.dchecks)
+    }
+
+    pub fn regexp_expressions(&self, regexpid: i64) -> Vec<&ConfigExpression> {
+        self.linked(Link::RegexpExpressions, regexpid, &self.expressions)
+    }
+
+    pub fn host_httptests(&self, hostid: i64) -> Vec<&ConfigHttpTest> {
+        self.linked(Link::HostHttpTests, hostid, &self.httptests)
+    }
+
+    ///
+    /// Web 场景的步骤，按 no 排序
+    ///
+    pub fn httptest_steps(&self, httptestid: i64) -> Vec<&ConfigHttpStep> {
+        self.linked(Link::HttpTestSteps, httptestid, &self.httpsteps)
+    }
+
+    pub fn httptest_items(&self, httptestid: i64) -> Vec<&ConfigItem> {
+        self.linked(Link::HttpTestItems, httptestid, &self.items)
+    }
+
+    pub fn httpstep_items(&self, httpstepid: i64) -> Vec<&ConfigItem> {
+        self.linked(Link::HttpStepItems, httpstepid, &self.items)
+    }
+
+    pub fn httptest_fields(&self, httptestid: i64) -> Vec<&ConfigHttpField> {
+        self.linked(Link::HttpTestFields, httptestid, &self.httptest_fields)
+    }
+
+    pub fn httpstep_fields(&self, httpstepid: i64) -> Vec<&ConfigHttpField> {
+        self.linked(Link::HttpStepFields, httpstepid, &self.httpstep_fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_config() {
+        let config = ProxyConfig::from_value(&json!({
+            "hosts": {
+                "fields": ["hostid", "host", "status"],
+                "data": [[1, "h1", 0], ["2", "h2", "0"], [3, "h3", 1], [4, "h4"], [null, "h5", 0]],
+            },
+            "items": {
+                "fields": ["itemid", "hostid", "key_", "delay", "status", "type"],
+                "data": [
+                    [10, 1, "a", "30s", 0, 0],
+                    ["11", "2", "b", "1m;50s/1-7,00:00-24:00", 0, 7],
+                    [12, 1, "c", "0", 0, 2],
+                    [13, 1, "d", null, 0, 0],
+                    [14, 1, "e", "30", 0, "x"],
+                    [15, 1, "f", "0;wd1-5h9", 0, 0],
+                ],
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            vec![1, 2, 3],
+            config.hosts.keys().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!("h2", config.hosts[&2].name);
+        assert_eq!(2, config.monitored_hosts().len());
+        assert_eq!(5, config.items.len());
+        assert_eq!(7, config.items[&11].item_type);
+        assert_eq!(60, config.items[&11].delay_secs());
+        let items = config.monitored_items(&[]);
+        assert_eq!(3, items.len());
+        let delay = Delay::parse("1m;50s/1-7,00:00-24:00").unwrap();
+        assert!(items.contains(&Item::new(11, 2, "b".to_string(), delay)));
+        // 默认间隔为 0，只在调度时间检查
+        let delay = Delay::parse("0;wd1-5h9").unwrap();
+        assert!(items.contains(&Item::new(15, 1, "f".to_string(), delay)));
+
+        let errors: Vec<String> = config.errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "table \"hosts\", row 3: row does not match 3 fields",
+                "table \"hosts\", row 4, field \"hostid\": missing field",
+                "table \"items\", row 4, field \"type\": invalid integer \"x\"",
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_proxy_config_invalid_table() {
+        let config = ProxyConfig::from_value(&json!({
+            "hosts": {"fields": "hostid", "data": []},
+            "items": {"fields": ["itemid", "hostid", "key_", "delay"], "data": [[10, 1, "a", "30s"]]},
+        }))
+        .unwrap();
+        assert!(config.hosts.is_empty());
+        assert_eq!(1, config.items.len());
+        assert_eq!(
+            ConfigError {
+                table: "hosts".to_string(),
+                row: None,
+                field: None,
+                reason: "fields is not an array".to_string(),
+            },
+            config.errors[0]
+        );
+        assert!(ProxyConfig::from_value(&json!([])).is_err());
+    }
+
+    #[test]
+    fn test_proxy_config_links() {
+        let config = ProxyConfig::from_value(&json!({
+            "data": {
+                "hosts": {
+                    "fields": ["hostid", "host", "status", "tls_connect", "tls_accept", "tls_psk_identity", "tls_psk"],
+                    "data": [[1, "h1", 0, 2, 3, "psk1", "0123abcd"], [100, "Template OS", 3, 1, 1, "", ""]],
+                },
+                "hosts_templates": {"fields": ["hosttemplateid", "hostid", "templateid"], "data": [[5, 1, 100]]},
+                "interface": {
+                    "fields": ["interfaceid", "hostid", "main", "type", "useip", "ip", "dns", "port"],
+                    "data": [[7, 1, 1, 1, 1, "10.0.0.1", "h1.local", "10050"], [8, 1, 1, 2, 0, "", "h1.local", "161"]],
+                },
+                "items": {
+                    "fields": ["itemid", "hostid", "key_", "delay", "type", "interfaceid"],
+                    "data": [[10, 1, "a", "30s", 0, 7], [11, 1, "b", "1m", 20, null], [12, 1, "web.test.in[x,,bps]", "0", 9, null]],
+                },
+                "item_preproc": {
+                    "fields": ["item_preprocid", "itemid", "step", "type", "params"],
+                    "data": [[31, 10, 2, 1, "2"], [30, 10, 1, 5, "^(\\d+)\n\\1"]],
+                },
+                "item_rtdata": {"fields": ["itemid", "lastlogsize", "mtime"], "data": [[10, 1024, 0]]},
+                "globalmacro": {"fields": ["globalmacroid", "macro", "value", "type"], "data": [[1, "{$A}", "1", 0]]},
+                "hostmacro": {"fields": ["hostmacroid", "hostid", "macro", "value", "type"], "data": [[2, 1, "{$PASS}", "x", 1]]},
+                "drules": {"fields": ["druleid", "name", "iprange", "delay"], "data": [[40, "lan", "10.0.0.1-254", "1h"]]},
+                "dchecks": {"fields": ["dcheckid", "druleid", "type", "ports"], "data": [[41, 40, 9, "10050"]]},
+                "regexps": {"fields": ["regexpid", "name"], "data": [[50, "File systems"]]},
+                "expressions": {"fields": ["regexpid", "expressionid", "expression"], "data": [[50, 51, "^ext"]]},
+                "httptest": {"fields": ["httptestid", "hostid", "name", "delay"], "data": [[60, 1, "web", "1m"]]},
+                "httpstep": {
+                    "fields": ["httpstepid", "httptestid", "name", "no", "url"],
+                    "data": [[62, 60, "second", 2, "http://x/2"], [61, 60, "first", 1, "http://x/1"]],
+                },
+                "httptestitem": {"fields": ["httptestitemid", "httptestid", "itemid", "type"], "data": [[70, 60, 12, 2]]},
+                "httpstep_field": {"fields": ["httpstep_fieldid", "httpstepid", "type", "name", "value"], "data": [[80, 61, 0, "Accept", "*/*"]]},
+                "config": {"fields": ["configid", "refresh_unsupported"], "data": [[1, "10m"]]},
+            },
+        }))
+        .unwrap();
+        assert!(config.errors.is_empty(), "{:?}", config.errors);
+
+        let host = &config.hosts[&1];
+        assert_eq!((2, 3), (host.tls_connect, host.tls_accept));
+        assert_eq!("psk1", host.tls_psk_identity);
+        assert_eq!(1, config.monitored_hosts().len());
+        assert_eq!(vec![100], config.host_templates(1));
+        assert_eq!(3, config.host_items(1).len());
+        assert_eq!(Some("h1"), config.item_host(10).map(|h| h.host.as_str()));
+
+        assert_eq!(2, config.host_interfaces(1).len());
+        assert_eq!(Some(7), config.item_interface(10).map(|i| i.interfaceid));
+        let snmp = config.item_interface(11).unwrap();
+        assert_eq!((8, "h1.local"), (snmp.interfaceid, snmp.address()));
+
+        let steps: Vec<u32> = config
+            .item_preprocessing(10)
+            .iter()
+            .map(|p| p.step)
+            .collect();
+        assert_eq!(vec![1, 2], steps);
+        assert_eq!(1024, config.item_rtdata[&10].lastlogsize);
+
+        assert_eq!("{$A}", config.global_macros[&1].macro_);
+        assert_eq!(None, config.global_macros[&1].hostid);
+        let macros = config.host_macros(1);
+        assert_eq!(1, macros.len());
+        assert!(macros[0].is_secret());
+
+        assert_eq!(9, config.drule_checks(40)[0].check_type);
+        assert_eq!("^ext", config.regexp_expressions(50)[0].expression);
+
+        assert_eq!("web", config.host_httptests(1)[0].name);
+        let names: Vec<&str> = config
+            .httptest_steps(60)
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect();
+        assert_eq!(vec!["first", "second"], names);
+        assert_eq!(12, config.httptest_items(60)[0].itemid);
+        assert_eq!("Accept", config.httpstep_fields(61)[0].name);
+        assert!(config.httpstep_fields(62).is_empty());
+
+        let settings = config.settings.as_ref().unwrap();
+        assert_eq!(Some(&json!("10m")), settings.get("refresh_unsupported"));
+        assert!(config.host_items(2).is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+//! `proxy data` 请求
+//!
+//! Zabbix 4.0 以后 proxy 通过一个 `proxy data` 请求同时发送历史数据、网络发现、
+//! 自动注册、主机可用性和任务执行结果。服务端的应答中包含需要 proxy 执行的任务
+//! （远程命令等）以及是否允许上传数据。
+use chrono::prelude::*;
+
+/// 历史数据
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryValue {
+    pub itemid: u64,
+    pub clock: i64,
+    pub ns: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// 为 1 时表示不支持，value 为原因
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<u8>,
+    /// 日志监控项读取到的位置
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lastlogsize: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    /// 发送前由 proxy 分配，服务端据此识别重复发送的数据
+    #[serde(default)]
+    pub id: u64,
+}
+
+impl HistoryValue {
+    pub fn new(itemid: u64, value: &str) -> Self {
+        let now = Local::now();
+        Self {
+            itemid,
+            clock: now.timestamp(),
+            ns: i64::from(now.timestamp_subsec_nanos()),
+            value: Some(String::from(value)),
+            state: None,
+            lastlogsize: None,
+            mtime: None,
+            id: 0,
+        }
+    }
+
+    ///
+    /// 不支持的监控项，value 为原因
+    ///
+    pub fn not_supported(itemid: u64, error: &str) -> Self {
+        let mut value = Self::new(itemid, error);
+        value.state = Some(1);
+        value
+    }
+
+    ///
+    /// 指定时间戳（秒和纳秒）
+    ///
+    pub fn with_clock(mut self, clock: i64, ns: i64) -> Self {
+        self.clock = clock;
+        self.ns = ns;
+        self
+    }
+}
+
+/// 网络发现数据
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiscoveryValue {
+    pub clock: i64,
+    pub druleid: u64,
+    pub dcheckid: u64,
+    pub ip: String,
+    pub dns: String,
+    pub port: u16,
+    pub value: String,
+    /// 0 为 up，1 为 down
+    pub status: u8,
+}
+
+/// 自动注册数据
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AutoRegistration {
+    pub clock: i64,
+    pub host: String,
+    pub ip: String,
+    pub dns: String,
+    pub port: u16,
+    pub host_metadata: String,
+}
+
+impl AutoRegistration {
+    pub fn new(host: &str, ip: &str, port: u16, host_metadata: &str) -> Self {
+        Self {
+            clock: Local::now().timestamp(),
+            host: String::from(host),
+            ip: String::from(ip),
+            dns: String::new(),
+            port,
+            host_metadata: String::from(host_metadata),
+        }
+    }
+}
+
+/// 主机可用性
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HostAvailability {
+    pub hostid: u64,
+    /// 0 为未知，1 为可用，2 为不可用
+    pub available: u8,
+    pub error: String,
+}
+
+/// proxy 与服务端之间的任务
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ProxyTask {
+    #[serde(rename = "type")]
+    pub task_type: u8,
+    #[serde(default)]
+    pub clock: i64,
+    #[serde(default)]
+    pub ttl: i64,
+    /// 远程命令：0 自定义脚本，1 IPMI，2 SSH，3 Telnet，4 全局脚本
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commandtype: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// 远程命令：0 在 agent 上执行，1 在服务端（proxy）上执行
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub execute_on: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub authtype: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub publickey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privatekey: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostid: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_taskid: Option<u64>,
+    /// 任务结果：0 成功，-1 失败
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<String>,
+}
+
+impl ProxyTask {
+    pub const REMOTE_COMMAND: u8 = 2;
+    pub const REMOTE_COMMAND_RESULT: u8 = 3;
+
+    ///
+    /// 远程命令的执行结果，status 为 0 表示成功，info 为输出或错误原因
+    ///
+    pub fn command_result(parent_taskid: u64, status: i32, info: &str) -> Self {
+        Self {
+            task_type: Self::REMOTE_COMMAND_RESULT,
+            clock: Local::now().timestamp(),
+            parent_taskid: Some(parent_taskid),
+            status: Some(status),
+            info: Some(String::from(info)),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_remote_command(&self) -> bool {
+        self.task_type == Self::REMOTE_COMMAND
+    }
+}
+
+/// `proxy data` 请求
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxyData {
+    request: String,
+    pub host: String,
+    pub session: String,
+    #[serde(
+        rename = "host availability",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub host_availability: Vec<HostAvailability>,
+    #[serde(
+        rename = "history data",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub history: Vec<HistoryValue>,
+    #[serde(
+        rename = "discovery data",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub discovery: Vec<DiscoveryValue>,
+    #[serde(
+        rename = "auto registration",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub auto_registration: Vec<AutoRegistration>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<ProxyTask>,
+    /// 还有未发送的数据时为 1，服务端会立即再次请求
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub more: Option<u8>,
+    pub version: String,
+    pub clock: i64,
+    pub ns: i64,
+}
+
+impl ProxyData {
+    /// 发送给服务端的协议版本，与按 config_revision 增量同步配置的 Zabbix 6.4 一致
+    pub const VERSION: &'static str = "6.4.0";
+
+    pub fn new(host: &str, session: &str) -> Self {
+        let now = Local::now();
+        Self {
+            request: String::from("proxy data"),
+            host: String::from(host),
+            session: String::from(session),
+            host_availability: vec![],
+            history: vec![],
+            discovery: vec![],
+            auto_registration: vec![],
+            tasks: vec![],
+            more: None,
+            version: String::from(Self::VERSION),
+            clock: now.timestamp(),
+            ns: i64::from(now.timestamp_subsec_nanos()),
+        }
+    }
+
+    ///
+    /// 报告给服务端的 proxy 版本
+    ///
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = String::from(version);
+        self
+    }
+
+    pub fn with_history(mut self, history: Vec<HistoryValue>) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn with_discovery(mut self, discovery: Vec<DiscoveryValue>) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    pub fn with_auto_registration(mut self, hosts: Vec<AutoRegistration>) -> Self {
+        self.auto_registration = hosts;
+        self
+    }
+
+    pub fn with_host_availability(mut self, availability: Vec<HostAvailability>) -> Self {
+        self.host_availability = availability;
+        self
+    }
+
+    pub fn with_tasks(mut self, tasks: Vec<ProxyTask>) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    ///
+    /// 标记还有数据未发送
+    ///
+    pub fn with_more(mut self, more: bool) -> Self {
+        self.more = if more { Some(1) } else { None };
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+            && self.discovery.is_empty()
+            && self.auto_registration.is_empty()
+            && self.host_availability.is_empty()
+            && self.tasks.is_empty()
+    }
+}
+
+/// 服务端对 `proxy data` 的应答
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProxyDataResponse {
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<String>,
+    /// `disabled` 时服务端暂不接收数据，proxy 应保留数据稍后再发送
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<ProxyTask>,
+}
+
+impl ProxyDataResponse {
+    pub fn success(&self) -> bool {
+        self.response == "success"
+    }
+
+    ///
+    /// 服务端是否接收了数据，`upload` 缺省时视为允许
+    ///
+    pub fn upload_enabled(&self) -> bool {
+        self.success() && self.upload.as_deref() != Some("disabled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_data() {
+        let data = ProxyData::new("proxy", "s1")
+            .with_history(vec![
+                HistoryValue::new(10, "1").with_clock(100, 5),
+                HistoryValue::not_supported(11, "Unsupported item key."),
+            ])
+            .with_auto_registration(vec![AutoRegistration::new(
+                "h1", "10.0.0.1", 10050, "Linux",
+            )])
+            .with_tasks(vec![ProxyTask::command_result(7, 0, "ok")])
+            .with_more(true);
+        let v = serde_json::to_value(&data).unwrap();
+        assert_eq!("proxy data", v["request"]);
+        assert_eq!(
+            json!({"itemid": 10, "clock": 100, "ns": 5, "value": "1", "id": 0}),
+            v["history data"][0]
+        );
+        assert_eq!(1, v["history data"][1]["state"]);
+        assert_eq!("Linux", v["auto registration"][0]["host_metadata"]);
+        assert_eq!(3, v["tasks"][0]["type"]);
+        assert_eq!(7, v["tasks"][0]["parent_taskid"]);
+        assert_eq!(1, v["more"]);
+        assert_eq!(ProxyData::VERSION, v["version"]);
+        assert!(v.get("discovery data").is_none());
+        assert!(v.get("host availability").is_none());
+        assert!(!data.is_empty());
+        assert!(ProxyData::new("proxy", "s1").is_empty());
+    }
+
+    #[test]
+    fn test_proxy_data_response() {
+        let r: ProxyDataResponse = serde_json::from_value(json!({
+            "response": "success",
+            "upload": "enabled",
+            "tasks": [{"type": 2, "clock": 1, "ttl": 3600, "commandtype": 0,
+                       "command": "uptime", "execute_on": 0, "hostid": 10084}],
+        }))
+        .unwrap();
+        assert!(r.upload_enabled());
+        assert!(r.tasks[0].is_remote_command());
+        assert_eq!(Some("uptime".to_string()), r.tasks[0].command);
+        assert_eq!(Some(10084), r.tasks[0].hostid);
+
+        let r: ProxyDataResponse =
+            serde_json::from_value(json!({"response": "success", "upload": "disabled"})).unwrap();
+        assert!(r.success());
+        assert!(!r.upload_enabled());
+        assert!(r.tasks.is_empty());
+        let r: ProxyDataResponse = serde_json::from_value(json!({"response": "failed"})).unwrap();
+        assert!(!r.upload_enabled());
+    }
+}
@@ -1,10 +1,9 @@
-//!
+//! zabbix 请求数据结构
 use chrono::prelude::*;
 use serde_json::Value;
 use std::collections::HashMap;
 
-///
-///
+/// zabbix 通用请求
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ZabbixRequest {
     request: &'static str,
@@ -34,34 +33,49 @@ impl ZabbixRequest {
     }
 }
 
-///
-///
+/// 监控项数据
 #[derive(Serialize, Deserialize, Debug, Clone)] //, PartialEq)]
 pub struct ZabbixMetric {
     pub host: String,
     pub key: String,
     pub value: String,
     clock: i64,
-    //ns: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ns: Option<i64>,
 }
 
 impl ZabbixMetric {
     pub fn new(host: &str, key: &str, value: &str) -> Self {
+        let clock = Local::now().timestamp();
+        Self::with_clock(host, key, value, clock, None)
+    }
+
+    ///
+    /// 指定时间戳（秒和纳秒）
+    ///
+    pub fn with_clock(host: &str, key: &str, value: &str, clock: i64, ns: Option<i64>) -> Self {
         let host = String::from(host);
         let key = String::from(key);
         let value = String::from(value);
-        let clock = Local::now().timestamp();
         Self {
             host,
             key,
             value,
             clock,
+            ns,
         }
     }
+
+    pub fn clock(&self) -> i64 {
+        self.clock
+    }
+
+    pub fn ns(&self) -> Option<i64> {
+        self.ns
+    }
 }
 
-///
-///
+/// 低级自动发现数据
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ZabbixDiscovery {
     data: Vec<HashMap<String, String>>,
@@ -90,8 +104,7 @@ impl ZabbixDiscovery {
     }
 }
 
-///
-///
+/// 自动注册主机
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ZabbixHost {
     host: String,
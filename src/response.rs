@@ -5,6 +5,34 @@ pub struct Response {
 }
 
 impl Response {
+    pub fn new(response: &str, info: Option<String>) -> Self {
+        let response = String::from(response);
+        Self { response, info }
+    }
+
+    pub fn info(&self) -> Option<&str> {
+        self.info.as_deref()
+    }
+
+    ///
+    /// 合并两次发送的结果，累加处理数量和耗时
+    ///
+    pub fn merge(&self, other: &Response) -> Response {
+        let response = if self.success() && other.success() {
+            "success"
+        } else {
+            "failed"
+        };
+        let info = format!(
+            "processed: {}; failed: {}; total: {}; seconds spent: {:.6}",
+            self.processed_cnt().max(0) + other.processed_cnt().max(0),
+            self.failed_cnt().max(0) + other.failed_cnt().max(0),
+            self.total_cnt().max(0) + other.total_cnt().max(0),
+            self.seconds_spent().max(0.0) + other.seconds_spent().max(0.0),
+        );
+        Response::new(response, Some(info))
+    }
+
     pub fn success(&self) -> bool {
         self.response == "success"
     }
@@ -51,7 +79,7 @@ impl Response {
 
     fn get_value_from_info(&self, name: &str) -> Option<String> {
         //{ response: "success", info: Some("processed: 6; failed: 0; total: 6; seconds spent: 0.000172") }
-        let reg = regex::Regex::new(r"processed: (?P<processed>\d+); failed: (?P<failed>\d+); total: (?P<total>\d+); seconds spent: (?P<seconds_spent>\d.\d+)").unwrap();
+        let reg = regex::Regex::new(r"processed: (?P<processed>\d+); failed: (?P<failed>\d+); total: (?P<total>\d+); seconds spent: (?P<seconds_spent>\d+\.\d+)").unwrap();
 
         if let Some(v) = &self.info {
             if let Some(x) = reg.captures(v) {
@@ -92,5 +120,29 @@ mod tests {
             info: Some("processed: 10; failed: 4; total: 14; seconds spent: 0.000172".to_string()),
         };
         assert!(!resp2.ok());
+
+        let resp3 = resp1.merge(&resp2);
+        assert!(resp3.success());
+        assert_eq!(28, resp3.total_cnt());
+        assert_eq!(4, resp3.failed_cnt());
+        assert_eq!(24, resp3.processed_cnt());
+        assert!((resp3.seconds_spent() - 0.000344).abs() < 1e-6);
+        assert!(!resp3.merge(&Response::new("failed", None)).success());
+
+        // 合并后耗时超过 10 秒
+        let slow = Response::new(
+            "success",
+            Some("processed: 250; failed: 0; total: 250; seconds spent: 6.500000".to_string()),
+        );
+        let merged = slow.merge(&slow);
+        assert_eq!(
+            Some("processed: 500; failed: 0; total: 500; seconds spent: 13.000000"),
+            merged.info()
+        );
+        assert_eq!(500, merged.total_cnt());
+        assert_eq!(500, merged.processed_cnt());
+        assert_eq!(0, merged.failed_cnt());
+        assert!((merged.seconds_spent() - 13.0).abs() < 1e-6);
+        assert!(merged.ok());
     }
 }
@@ -1,8 +1,47 @@
 //! zabbix sender
 //!
+//! 与 `zabbix_sender` 相同，每次请求最多发送 250 个值。
+use failure::Fail;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use super::input::{InputFormat, LineError};
 use super::protocol::ZabbixProtocol;
+use super::request::{ZabbixMetric, ZabbixRequest};
+use super::response::Response;
+use super::Result;
+
+/// `send_input` 中途失败，出错前读取的值已经分批发送，sent 为这些批次合并后的结果
+#[derive(Debug)]
+pub struct SendInputError {
+    pub sent: Response,
+    pub error: failure::Error,
+}
+
+impl SendInputError {
+    ///
+    /// 解析失败时的行号和原因
+    ///
+    pub fn line_error(&self) -> Option<&LineError> {
+        self.error.downcast_ref()
+    }
+}
+
+impl fmt::Display for SendInputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl Fail for SendInputError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        Some(self.error.as_fail())
+    }
+}
 
-///
+/// zabbix sender
 #[derive(Debug, Clone)]
 pub struct ZabbixSender {
     name: String,
@@ -10,9 +49,174 @@ pub struct ZabbixSender {
 }
 
 impl ZabbixSender {
+    pub const SENDER_DATA: &'static str = "sender data";
+    /// 每次请求最多发送的值的数量
+    pub const MAX_VALUES: usize = 250;
+
     pub fn new(name: &str, server: &str, port: u16) -> Self {
+        Self::with_protocol(name, ZabbixProtocol::new(server, port))
+    }
+
+    pub fn with_protocol(name: &str, proto: ZabbixProtocol) -> Self {
         let name = String::from(name);
-        let proto = ZabbixProtocol::new(server, port);
         Self { name, proto }
     }
+
+    ///
+    /// 发送监控数据，按 250 个值分批发送，返回合并后的结果
+    ///
+    pub fn send(&self, data: &[ZabbixMetric]) -> Result<Response> {
+        let mut result = empty_response();
+        for chunk in data.chunks(Self::MAX_VALUES) {
+            let req = sender_request(&self.name, chunk)?;
+            let read_data = self.proto.send(&req.str())?;
+            let resp: Response = serde_json::from_slice(&read_data)?;
+            trace!("{:?}", resp);
+            result = result.merge(&resp);
+        }
+        Ok(result)
+    }
+
+    ///
+    /// 按 `zabbix_sender -i` 格式逐行读取并分批发送，与 `zabbix_sender` 相同，不预先检查整个输入。
+    /// 读取、解析或发送失败时停止，返回 `SendInputError`，包含出错前已发送的结果，
+    /// 解析失败的行号和原因可以通过 `SendInputError::line_error` 获取
+    ///
+    pub fn send_input<R: BufRead>(&self, reader: R, format: &InputFormat) -> Result<Response> {
+        let mut result = empty_response();
+        let mut batch = Vec::with_capacity(Self::MAX_VALUES);
+        let partial = |sent: &Response, error: failure::Error| SendInputError {
+            sent: sent.clone(),
+            error,
+        };
+        for (i, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| partial(&result, e.into()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let metric = format
+                .parse_line(i + 1, &line)
+                .map_err(|e| partial(&result, e))?;
+            batch.push(metric);
+            if batch.len() == Self::MAX_VALUES {
+                let resp = self.send(&batch).map_err(|e| partial(&result, e))?;
+                result = result.merge(&resp);
+                batch.clear();
+            }
+        }
+        if !batch.is_empty() {
+            let resp = self.send(&batch).map_err(|e| partial(&result, e))?;
+            result = result.merge(&resp);
+        }
+        Ok(result)
+    }
+
+    ///
+    /// 发送输入文件，文件名为 `-` 时读取标准输入
+    ///
+    pub fn send_file<P: AsRef<Path>>(&self, path: P, format: &InputFormat) -> Result<Response> {
+        if path.as_ref() == Path::new("-") {
+            let stdin = io::stdin();
+            return self.send_input(stdin.lock(), format);
+        }
+        let file = File::open(path.as_ref())
+            .map_err(|e| format_err!("cannot open {:?}: {}", path.as_ref(), e))?;
+        self.send_input(BufReader::new(file), format)
+    }
+}
+
+pub(crate) fn sender_request(name: &str, data: &[ZabbixMetric]) -> Result<ZabbixRequest> {
+    let data = serde_json::to_value(data)?;
+    Ok(ZabbixRequest::new(ZabbixSender::SENDER_DATA, name, data))
+}
+
+pub(crate) fn empty_response() -> Response {
+    Response::new(
+        "success",
+        Some("processed: 0; failed: 0; total: 0; seconds spent: 0.000000".to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ZbxStream;
+    use serde_json::Value;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn server(count: usize) -> (u16, thread::JoinHandle<Vec<usize>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut sizes = vec![];
+            for _ in 0..count {
+                let (s, _) = listener.accept().unwrap();
+                let mut stream = ZbxStream::new(s);
+                let req: Value = serde_json::from_slice(&stream.read_frame().unwrap()).unwrap();
+                assert_eq!("sender data", req["request"]);
+
+                let n = req["data"].as_array().unwrap().len();
+                let resp = json!({
+                    "response": "success",
+                    "info": format!(
+                        "processed: {}; failed: 1; total: {}; seconds spent: 0.000100",
+                        n - 1,
+                        n
+                    ),
+                });
+                stream.write_frame(resp.to_string().as_bytes()).unwrap();
+                sizes.push(n);
+            }
+            sizes
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_sender_send() {
+        let (port, handle) = server(3);
+        let data: Vec<_> = (0..600)
+            .map(|i| ZabbixMetric::new("host", "key", &i.to_string()))
+            .collect();
+        let sender = ZabbixSender::new("sender", "127.0.0.1", port);
+        let resp = sender.send(&data).unwrap();
+
+        assert_eq!(vec![250, 250, 100], handle.join().unwrap());
+        assert!(resp.success());
+        assert_eq!(600, resp.total_cnt());
+        assert_eq!(597, resp.processed_cnt());
+        assert_eq!(3, resp.failed_cnt());
+
+        let resp = sender.send(&[]).unwrap();
+        assert!(resp.ok());
+        assert_eq!(0, resp.total_cnt());
+    }
+
+    #[test]
+    fn test_sender_send_input() {
+        let (port, handle) = server(3);
+        let mut input: String = (0..300).map(|i| format!("- key {}\n", i)).collect();
+        input.push_str("\nhost \"key\n");
+
+        let sender = ZabbixSender::new("sender", "127.0.0.1", port);
+        let format = InputFormat::new().with_host("host");
+        let e = sender
+            .send_input(input.as_bytes(), &format)
+            .unwrap_err()
+            .downcast::<SendInputError>()
+            .unwrap();
+        assert_eq!(302, e.line_error().unwrap().line);
+        assert_eq!("[line 302] invalid 'Key' value", e.to_string());
+        // 出错前已经发送的 250 个值
+        assert_eq!(250, e.sent.total_cnt());
+        assert_eq!(249, e.sent.processed_cnt());
+
+        input.truncate(input.len() - 10);
+        let resp = sender.send_input(input.as_bytes(), &format).unwrap();
+        assert_eq!(300, resp.total_cnt());
+
+        // 第一次发送在解析错误前已经发出了 250 个值
+        assert_eq!(vec![250, 250, 50], handle.join().unwrap());
+    }
 }
@@ -0,0 +1,602 @@
+//! 内置的 Linux 系统监控项
+//!
+//! 读取 `/proc` 下的系统文件，key 和返回值与 C 语言版本的 agent 相同，可以直接使用标准模板。
+//! `system.cpu.util` 由后台线程每秒采样 `/proc/stat`，首次注册时启动。
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::agent::ZabbixAgent;
+use super::handler::{ItemRegistry, ItemValue};
+use super::key::ItemKey;
+use super::Result;
+
+///
+/// 注册所有内置监控项，hostname 为 `agent.hostname` 返回的主机名
+///
+pub fn register_system_items(registry: &mut ItemRegistry, hostname: &str) {
+    let hostname = String::from(hostname);
+    registry.register("agent.ping", |key: &ItemKey| {
+        check_params(key, 0)?;
+        Ok(1u64)
+    });
+    registry.register("agent.version", |key: &ItemKey| {
+        check_params(key, 0)?;
+        Ok(ZabbixAgent::VERSION)
+    });
+    registry.register("agent.hostname", move |key: &ItemKey| {
+        check_params(key, 0)?;
+        Ok(hostname.clone())
+    });
+    registry.register("system.uptime", system_uptime);
+    registry.register("system.cpu.load", system_cpu_load);
+    registry.register("system.cpu.util", system_cpu_util);
+    registry.register("vm.memory.size", vm_memory_size);
+    registry.register("vfs.fs.size", vfs_fs_size);
+    registry.register("net.if.in", net_if_in);
+    registry.register("proc.num", proc_num);
+    cpu_collector();
+}
+
+fn check_params(key: &ItemKey, max: usize) -> Result<()> {
+    if key.params().len() > max {
+        return Err(format_err!("Too many parameters."));
+    }
+    Ok(())
+}
+
+///
+/// 第 i 个参数，不存在时返回空字符串，为数组时返回错误
+///
+fn param(key: &ItemKey, i: usize) -> Result<&str> {
+    match key.params().get(i) {
+        None => Ok(""),
+        Some(p) => p.as_str().ok_or_else(|| invalid(i)),
+    }
+}
+
+fn ordinal(i: usize) -> &'static str {
+    match i {
+        0 => "first",
+        1 => "second",
+        2 => "third",
+        3 => "fourth",
+        _ => "fifth",
+    }
+}
+
+fn invalid(i: usize) -> failure::Error {
+    format_err!("Invalid {} parameter.", ordinal(i))
+}
+
+fn read(path: &str) -> Result<String> {
+    fs::read_to_string(path).map_err(|e| format_err!("Cannot open {}: {}", path, e))
+}
+
+fn system_uptime(key: &ItemKey) -> Result<u64> {
+    check_params(key, 0)?;
+    let uptime = read("/proc/uptime")?;
+    let seconds: f64 = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| format_err!("Cannot parse /proc/uptime."))?;
+    Ok(seconds as u64)
+}
+
+fn system_cpu_load(key: &ItemKey) -> Result<f64> {
+    check_params(key, 2)?;
+    let percpu = match param(key, 0)? {
+        "" | "all" => false,
+        "percpu" => true,
+        _ => return Err(invalid(0)),
+    };
+    let index = match param(key, 1)? {
+        "" | "avg1" => 0,
+        "avg5" => 1,
+        "avg15" => 2,
+        _ => return Err(invalid(1)),
+    };
+
+    let load = parse_loadavg(&read("/proc/loadavg")?)?[index];
+    if !percpu {
+        return Ok(load);
+    }
+    let cpus = parse_cpu_stat(&read("/proc/stat")?)
+        .keys()
+        .filter(|name| *name != "cpu")
+        .count();
+    if cpus == 0 {
+        return Err(format_err!("Cannot obtain number of CPUs."));
+    }
+    Ok(load / cpus as f64)
+}
+
+fn parse_loadavg(input: &str) -> Result<[f64; 3]> {
+    let mut load = [0.0; 3];
+    let mut fields = input.split_whitespace();
+    for v in load.iter_mut() {
+        *v = fields
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| format_err!("Cannot parse /proc/loadavg."))?;
+    }
+    Ok(load)
+}
+
+/// `/proc/stat` 中 cpu 行的前 10 列：
+/// user nice system idle iowait irq softirq steal guest guest_nice
+type CpuTimes = [u64; 10];
+
+fn parse_cpu_stat(input: &str) -> HashMap<String, CpuTimes> {
+    let mut result = HashMap::new();
+    for line in input.lines().filter(|l| l.starts_with("cpu")) {
+        let mut fields = line.split_whitespace();
+        let name = match fields.next() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let mut times = [0; 10];
+        for (t, v) in times.iter_mut().zip(fields) {
+            *t = v.parse().unwrap_or(0);
+        }
+        result.insert(name, times);
+    }
+    result
+}
+
+/// 最多保留 15 分钟的采样
+const MAX_SAMPLES: usize = 15 * 60 + 1;
+
+type CpuSamples = Arc<Mutex<VecDeque<(Instant, HashMap<String, CpuTimes>)>>>;
+
+fn cpu_collector() -> CpuSamples {
+    static SAMPLES: OnceLock<CpuSamples> = OnceLock::new();
+
+    SAMPLES
+        .get_or_init(|| {
+            let samples: CpuSamples = Arc::default();
+            let collector = samples.clone();
+            thread::spawn(move || loop {
+                if let Ok(stat) = fs::read_to_string("/proc/stat") {
+                    let mut samples = collector.lock().unwrap_or_else(|e| e.into_inner());
+                    if samples.len() == MAX_SAMPLES {
+                        samples.pop_front();
+                    }
+                    samples.push_back((Instant::now(), parse_cpu_stat(&stat)));
+                }
+                thread::sleep(Duration::from_secs(1));
+            });
+            samples
+        })
+        .clone()
+}
+
+fn system_cpu_util(key: &ItemKey) -> Result<f64> {
+    check_params(key, 3)?;
+    let cpu = match param(key, 0)? {
+        "" | "all" => String::from("cpu"),
+        n => match n.parse::<u32>() {
+            Ok(n) => format!("cpu{}", n),
+            Err(_) => return Err(invalid(0)),
+        },
+    };
+    let index = match param(key, 1)? {
+        "" | "user" => 0,
+        "nice" => 1,
+        "system" => 2,
+        "idle" => 3,
+        "iowait" => 4,
+        "interrupt" => 5,
+        "softirq" => 6,
+        "steal" => 7,
+        "guest" => 8,
+        "guest_nice" => 9,
+        _ => return Err(invalid(1)),
+    };
+    let window = match param(key, 2)? {
+        "" | "avg1" => Duration::from_secs(60),
+        "avg5" => Duration::from_secs(5 * 60),
+        "avg15" => Duration::from_secs(15 * 60),
+        _ => return Err(invalid(2)),
+    };
+
+    let samples = cpu_collector();
+    let samples = samples.lock().unwrap_or_else(|e| e.into_inner());
+    let (last_time, last) = samples
+        .back()
+        .ok_or_else(|| format_err!("No data gathered yet."))?;
+    let (_, first) = samples
+        .iter()
+        .find(|(t, _)| *last_time - *t <= window)
+        .ok_or_else(|| format_err!("No data gathered yet."))?;
+
+    match (first.get(&cpu), last.get(&cpu)) {
+        (Some(first), Some(last)) => cpu_util(first, last, index),
+        _ => Err(format_err!("Cannot obtain CPU information.")),
+    }
+}
+
+///
+/// 两次采样之间某一类型的 CPU 时间占比，guest 时间已包含在 user 中，不计入总数
+///
+fn cpu_util(first: &CpuTimes, last: &CpuTimes, index: usize) -> Result<f64> {
+    let total: u64 = (0..8).map(|i| last[i].saturating_sub(first[i])).sum();
+    if total == 0 {
+        return Err(format_err!("No data gathered yet."));
+    }
+    let value = last[index].saturating_sub(first[index]);
+    Ok(100.0 * value as f64 / total as f64)
+}
+
+fn vm_memory_size(key: &ItemKey) -> Result<ItemValue> {
+    check_params(key, 1)?;
+    let mem = parse_meminfo(&read("/proc/meminfo")?);
+    let get = |name: &str| {
+        mem.get(name)
+            .cloned()
+            .ok_or_else(|| format_err!("Cannot obtain {} from /proc/meminfo.", name))
+    };
+    let total = get("MemTotal")?;
+    let free = get("MemFree")?;
+    let available = || -> Result<u64> {
+        match mem.get("MemAvailable") {
+            Some(v) => Ok(*v),
+            None => Ok(free + get("Buffers")? + get("Cached")?),
+        }
+    };
+    let percent = |v: u64| {
+        if total == 0 {
+            Err(format_err!(
+                "Cannot calculate percentage because total is zero."
+            ))
+        } else {
+            Ok(ItemValue::Float(100.0 * v as f64 / total as f64))
+        }
+    };
+
+    let value = match param(key, 0)? {
+        "" | "total" => total,
+        "free" => free,
+        "buffers" => get("Buffers")?,
+        "cached" => get("Cached")?,
+        "shared" => get("Shmem")?,
+        "active" => get("Active")?,
+        "inactive" => get("Inactive")?,
+        "slab" => get("Slab")?,
+        "used" => total.saturating_sub(free),
+        "available" => available()?,
+        "pused" => return percent(total.saturating_sub(free)),
+        "pavailable" => return percent(available()?),
+        _ => return Err(invalid(0)),
+    };
+    Ok(ItemValue::Unsigned(value))
+}
+
+///
+/// 解析 `/proc/meminfo`，单位转换为字节
+///
+fn parse_meminfo(input: &str) -> HashMap<String, u64> {
+    let mut result = HashMap::new();
+    for line in input.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(n), Some(v)) => (n.trim(), v.trim()),
+            _ => continue,
+        };
+        let mut fields = value.split_whitespace();
+        if let Some(Ok(v)) = fields.next().map(|v| v.parse::<u64>()) {
+            let v = match fields.next() {
+                Some("kB") => v * 1024,
+                _ => v,
+            };
+            result.insert(String::from(name), v);
+        }
+    }
+    result
+}
+
+fn vfs_fs_size(key: &ItemKey) -> Result<ItemValue> {
+    check_params(key, 2)?;
+    let fs = param(key, 0)?;
+    if fs.is_empty() {
+        return Err(invalid(0));
+    }
+    let mode = param(key, 1)?;
+    if !["", "total", "free", "used", "pfree", "pused"].contains(&mode) {
+        return Err(invalid(1));
+    }
+
+    let path = CString::new(fs).map_err(|_| invalid(0))?;
+    let mut s: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut s) } != 0 {
+        return Err(format_err!(
+            "Cannot obtain filesystem information: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    let frsize = s.f_frsize as u64;
+    let blocks = s.f_blocks as u64;
+    let bfree = s.f_bfree as u64;
+    let bavail = s.f_bavail as u64;
+    let used = blocks.saturating_sub(bfree);
+    let percent = |v: u64| {
+        if used + bavail == 0 {
+            Err(format_err!(
+                "Cannot calculate percentage because total is zero."
+            ))
+        } else {
+            Ok(ItemValue::Float(100.0 * v as f64 / (used + bavail) as f64))
+        }
+    };
+
+    match mode {
+        "" | "total" => Ok(ItemValue::Unsigned(blocks * frsize)),
+        "free" => Ok(ItemValue::Unsigned(bavail * frsize)),
+        "used" => Ok(ItemValue::Unsigned(used * frsize)),
+        "pfree" => percent(bavail),
+        _ => percent(used),
+    }
+}
+
+fn net_if_in(key: &ItemKey) -> Result<u64> {
+    check_params(key, 2)?;
+    let interface = param(key, 0)?;
+    if interface.is_empty() {
+        return Err(invalid(0));
+    }
+    let index = match param(key, 1)? {
+        "" | "bytes" => 0,
+        "packets" => 1,
+        "errors" => 2,
+        "dropped" => 3,
+        "overruns" => 4,
+        "frame" => 5,
+        "compressed" => 6,
+        "multicast" => 7,
+        _ => return Err(invalid(1)),
+    };
+
+    let dev = parse_net_dev(&read("/proc/net/dev")?);
+    match dev.get(interface) {
+        Some(stats) => Ok(stats[index]),
+        None => Err(format_err!(
+            "Cannot find information for this network interface in /proc/net/dev."
+        )),
+    }
+}
+
+///
+/// 解析 `/proc/net/dev`，返回每个接口的 16 列统计（前 8 列为接收）
+///
+fn parse_net_dev(input: &str) -> HashMap<String, [u64; 16]> {
+    let mut result = HashMap::new();
+    for line in input.lines().skip(2) {
+        let mut parts = line.splitn(2, ':');
+        let (name, values) = match (parts.next(), parts.next()) {
+            (Some(n), Some(v)) => (n.trim(), v),
+            _ => continue,
+        };
+        let mut stats = [0; 16];
+        for (s, v) in stats.iter_mut().zip(values.split_whitespace()) {
+            *s = v.parse().unwrap_or(0);
+        }
+        result.insert(String::from(name), stats);
+    }
+    result
+}
+
+/// 进程信息，来自 `/proc/<pid>/status` 和 `/proc/<pid>/cmdline`
+#[derive(Debug, Default)]
+struct ProcInfo {
+    name: String,
+    state: char,
+    uid: Option<u32>,
+    cmdline: String,
+    argv0: String,
+}
+
+fn parse_proc_status(input: &str) -> ProcInfo {
+    let mut info = ProcInfo::default();
+    for line in input.lines() {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(n), Some(v)) => (n, v.trim()),
+            _ => continue,
+        };
+        match name {
+            "Name" => info.name = String::from(value),
+            "State" => info.state = value.chars().next().unwrap_or(' '),
+            "Uid" => info.uid = value.split_whitespace().next().and_then(|v| v.parse().ok()),
+            _ => {}
+        }
+    }
+    info
+}
+
+fn read_proc(pid: &Path) -> Option<ProcInfo> {
+    let status = fs::read_to_string(pid.join("status")).ok()?;
+    let mut info = parse_proc_status(&status);
+    if let Ok(cmdline) = fs::read(pid.join("cmdline")) {
+        let args: Vec<String> = cmdline
+            .split(|b| *b == 0)
+            .filter(|a| !a.is_empty())
+            .map(|a| String::from_utf8_lossy(a).into_owned())
+            .collect();
+        info.argv0 = args
+            .first()
+            .map(|a| a.rsplit('/').next().unwrap_or(a).to_string())
+            .unwrap_or_default();
+        info.cmdline = args.join(" ");
+    }
+    Some(info)
+}
+
+fn lookup_uid(user: &str) -> Result<u32> {
+    let passwd = read("/etc/passwd")?;
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() > 2 && fields[0] == user {
+            if let Ok(uid) = fields[2].parse() {
+                return Ok(uid);
+            }
+        }
+    }
+    Err(format_err!("Cannot obtain user information."))
+}
+
+fn proc_num(key: &ItemKey) -> Result<u64> {
+    check_params(key, 4)?;
+    let name = param(key, 0)?;
+    let uid = match param(key, 1)? {
+        "" => None,
+        user => Some(lookup_uid(user)?),
+    };
+    let states: &[char] = match param(key, 2)? {
+        "" | "all" => &[],
+        "disk" => &['D'],
+        "run" => &['R'],
+        "sleep" => &['S'],
+        "zomb" => &['Z'],
+        "trace" => &['T', 't'],
+        _ => return Err(invalid(2)),
+    };
+    let cmdline = match param(key, 3)? {
+        "" => None,
+        re => Some(Regex::new(re).map_err(|_| invalid(3))?),
+    };
+
+    let entries = fs::read_dir("/proc").map_err(|e| format_err!("Cannot open /proc: {}", e))?;
+    let mut count = 0;
+    for entry in entries.flatten() {
+        let is_pid = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|n| n.chars().all(|c| c.is_ascii_digit()));
+        if !is_pid {
+            continue;
+        }
+        let info = match read_proc(&entry.path()) {
+            Some(info) => info,
+            None => continue,
+        };
+        if !name.is_empty() && info.name != name && info.argv0 != name {
+            continue;
+        }
+        if uid.is_some() && info.uid != uid {
+            continue;
+        }
+        if !states.is_empty() && !states.contains(&info.state) {
+            continue;
+        }
+        if let Some(re) = &cmdline {
+            if !re.is_match(&info.cmdline) {
+                continue;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> ItemRegistry {
+        let mut registry = ItemRegistry::new();
+        register_system_items(&mut registry, "host");
+        registry
+    }
+
+    #[test]
+    fn test_parse_proc_files() {
+        assert_eq!(
+            [0.63, 0.42, 0.29],
+            parse_loadavg("0.63 0.42 0.29 2/72 21472\n").unwrap()
+        );
+
+        let stat =
+            parse_cpu_stat("cpu  32475 0 4284 177249 343 0 9 1433 0 0\ncpu0 1 2 3 4\nintr 1 2\n");
+        assert_eq!(2, stat.len());
+        assert_eq!([1, 2, 3, 4, 0, 0, 0, 0, 0, 0], stat["cpu0"]);
+
+        let mem = parse_meminfo("MemTotal:        8048588 kB\nHugePages_Total:       0\n");
+        assert_eq!(8048588 * 1024, mem["MemTotal"]);
+        assert_eq!(0, mem["HugePages_Total"]);
+
+        let dev = parse_net_dev(
+            "Inter-|   Receive |  Transmit\n face |bytes packets|bytes\n  eth0: 1024 8 1 2 3 4 5 6 2048 16 0 0 0 0 0 0\n",
+        );
+        assert_eq!(1024, dev["eth0"][0]);
+        assert_eq!(2, dev["eth0"][3]);
+
+        let info = parse_proc_status("Name:\tsshd\nState:\tS (sleeping)\nUid:\t0\t0\t0\t0\n");
+        assert_eq!(
+            ("sshd", 'S', Some(0)),
+            (&info.name[..], info.state, info.uid)
+        );
+    }
+
+    #[test]
+    fn test_cpu_util() {
+        let first = [100, 0, 50, 800, 50, 0, 0, 0, 10, 0];
+        let last = [200, 0, 100, 1600, 100, 0, 0, 0, 20, 0];
+        assert_eq!(10.0, cpu_util(&first, &last, 0).unwrap());
+        assert_eq!(80.0, cpu_util(&first, &last, 3).unwrap());
+        assert!(cpu_util(&first, &first, 0).is_err());
+    }
+
+    #[test]
+    fn test_system_items() {
+        let registry = registry();
+        let get = |key: &str| registry.get(key);
+
+        assert_eq!(ItemValue::Unsigned(1), get("agent.ping").unwrap());
+        assert_eq!("host", get("agent.hostname").unwrap().to_string());
+        assert_eq!(ItemValue::from("6.4.0"), get("agent.version").unwrap());
+        assert!(get("agent.ping[a]").is_err());
+        assert!(matches!(
+            get("system.uptime").unwrap(),
+            ItemValue::Unsigned(_)
+        ));
+        assert!(matches!(
+            get("system.cpu.load[,avg5]").unwrap(),
+            ItemValue::Float(_)
+        ));
+        assert!(matches!(
+            get("system.cpu.load[percpu]").unwrap(),
+            ItemValue::Float(_)
+        ));
+        assert_eq!(
+            "Invalid second parameter.",
+            get("system.cpu.load[all,avg2]").unwrap_err().to_string()
+        );
+        assert!(matches!(
+            get("vm.memory.size[available]").unwrap(),
+            ItemValue::Unsigned(v) if v > 0
+        ));
+        assert!(matches!(
+            get("vfs.fs.size[/,pfree]").unwrap(),
+            ItemValue::Float(v) if (0.0..=100.0).contains(&v)
+        ));
+        assert!(get("vfs.fs.size[/nonexistent]").is_err());
+        assert!(matches!(
+            get("net.if.in[lo]").unwrap(),
+            ItemValue::Unsigned(_)
+        ));
+        assert!(get("net.if.in[nonexistent0]").is_err());
+        assert!(matches!(get("proc.num[]").unwrap(), ItemValue::Unsigned(v) if v > 0));
+        assert_eq!(
+            "Invalid third parameter.",
+            get("proc.num[,,bad]").unwrap_err().to_string()
+        );
+    }
+}
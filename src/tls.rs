@@ -0,0 +1,737 @@
+//! 基于 openssl 的 TLS 加密，支持证书和预共享密钥 (PSK)
+//!
+//! 对应 zabbix 配置中的 `TLSConnect`、`TLSAccept`、`TLSCAFile`、`TLSCertFile`、
+//! `TLSKeyFile`、`TLSServerCertSubject`、`TLSServerCertIssuer`、
+//! `TLSPSKIdentity` 和 `TLSPSKFile`。
+
+use openssl::ssl::{
+    ConnectConfiguration, SslAcceptor, SslConnector, SslContextBuilder, SslFiletype, SslMethod,
+    SslRef, SslStream, SslVerifyMode, SslVersion,
+};
+use openssl::x509::X509NameRef;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::error::ProtocolError;
+use super::Result;
+
+/// 证书方式使用的加密套件，与 zabbix 默认值相同
+const CERT_CIPHERS: &str = "EECDH+aRSA+AES128:RSA+aRSA+AES128";
+/// PSK 方式使用的加密套件，与 zabbix 默认值相同
+const PSK_CIPHERS: &str = "kECDHEPSK+AES128:kPSK+AES128";
+
+/// TLS 记录层握手消息的第一个字节
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// 连接加密方式，对应 TLSConnect / TLSAccept 的取值
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TlsMode {
+    Unencrypted,
+    Psk,
+    Cert,
+}
+
+impl FromStr for TlsMode {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "unencrypted" => Ok(TlsMode::Unencrypted),
+            "psk" => Ok(TlsMode::Psk),
+            "cert" => Ok(TlsMode::Cert),
+            _ => Err(format_err!("invalid TLS mode: {}", s)),
+        }
+    }
+}
+
+/// 预共享密钥，对应 TLSPSKIdentity 和 TLSPSKFile
+#[derive(Clone)]
+pub struct TlsPsk {
+    identity: String,
+    key: Vec<u8>,
+}
+
+impl std::fmt::Debug for TlsPsk {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // 不输出密钥
+        f.debug_struct("TlsPsk")
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+impl TlsPsk {
+    /// PSK 至少 128 位
+    pub const MIN_KEY_SIZE: usize = 16;
+    /// PSK 最多 2048 位
+    pub const MAX_KEY_SIZE: usize = 256;
+    /// identity 最多 128 字节
+    pub const MAX_IDENTITY_SIZE: usize = 128;
+
+    ///
+    /// 由 identity 和十六进制密钥生成
+    ///
+    pub fn new(identity: &str, hex: &str) -> Result<Self> {
+        if identity.is_empty() || identity.len() > Self::MAX_IDENTITY_SIZE {
+            return Err(format_err!(
+                "invalid PSK identity length: {}",
+                identity.len()
+            ));
+        }
+
+        let hex = hex.trim();
+        if hex.len() % 2 != 0 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format_err!(
+                "PSK must be an even number of hexadecimal digits"
+            ));
+        }
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()?;
+        if key.len() < Self::MIN_KEY_SIZE || key.len() > Self::MAX_KEY_SIZE {
+            return Err(format_err!(
+                "PSK must be {} to {} hexadecimal digits, got {}",
+                Self::MIN_KEY_SIZE * 2,
+                Self::MAX_KEY_SIZE * 2,
+                hex.len()
+            ));
+        }
+
+        Ok(Self {
+            identity: String::from(identity),
+            key,
+        })
+    }
+
+    ///
+    /// 从 TLSPSKFile 读取十六进制密钥
+    ///
+    pub fn from_file<P: AsRef<Path>>(identity: &str, path: P) -> Result<Self> {
+        let hex = fs::read_to_string(path.as_ref())
+            .map_err(|e| format_err!("cannot read PSK file {:?}: {}", path.as_ref(), e))?;
+        Self::new(identity, &hex)
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+}
+
+/// TLS 配置，同时用于连接端和监听端
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    connect: TlsMode,
+    accept: Vec<TlsMode>,
+    ca_file: Option<PathBuf>,
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    server_cert_subject: Option<String>,
+    server_cert_issuer: Option<String>,
+    psk: Option<TlsPsk>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            connect: TlsMode::Unencrypted,
+            accept: vec![TlsMode::Unencrypted],
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            server_cert_subject: None,
+            server_cert_issuer: None,
+            psk: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    ///
+    /// 使用证书加密 (TLSConnect=cert, TLSAccept=cert)
+    ///
+    pub fn new<P: AsRef<Path>>(ca_file: P, cert_file: P, key_file: P) -> Self {
+        Self::default()
+            .with_cert(ca_file, cert_file, key_file)
+            .with_connect(TlsMode::Cert)
+            .with_accept(&[TlsMode::Cert])
+    }
+
+    ///
+    /// 使用预共享密钥加密 (TLSConnect=psk, TLSAccept=psk)
+    ///
+    pub fn from_psk(psk: TlsPsk) -> Self {
+        Self::default()
+            .with_psk(psk)
+            .with_connect(TlsMode::Psk)
+            .with_accept(&[TlsMode::Psk])
+    }
+
+    ///
+    /// 设置证书文件，不改变连接方式
+    ///
+    pub fn with_cert<P: AsRef<Path>>(mut self, ca_file: P, cert_file: P, key_file: P) -> Self {
+        self.ca_file = Some(ca_file.as_ref().to_path_buf());
+        self.cert_file = Some(cert_file.as_ref().to_path_buf());
+        self.key_file = Some(key_file.as_ref().to_path_buf());
+        self
+    }
+
+    ///
+    /// 设置预共享密钥，不改变连接方式
+    ///
+    pub fn with_psk(mut self, psk: TlsPsk) -> Self {
+        self.psk = Some(psk);
+        self
+    }
+
+    ///
+    /// 主动连接时使用的加密方式 (TLSConnect)
+    ///
+    pub fn with_connect(mut self, mode: TlsMode) -> Self {
+        self.connect = mode;
+        self
+    }
+
+    ///
+    /// 监听端允许的加密方式 (TLSAccept)，可以同时允许多种
+    ///
+    pub fn with_accept(mut self, modes: &[TlsMode]) -> Self {
+        self.accept = modes.to_vec();
+        self
+    }
+
+    ///
+    /// 对端证书的 subject 必须与之相同，格式为 RFC 4514，如 `CN=Zabbix server,O=Zabbix SIA`
+    ///
+    pub fn with_server_cert_subject(mut self, subject: &str) -> Self {
+        self.server_cert_subject = Some(String::from(subject));
+        self
+    }
+
+    ///
+    /// 对端证书的 issuer 必须与之相同
+    ///
+    pub fn with_server_cert_issuer(mut self, issuer: &str) -> Self {
+        self.server_cert_issuer = Some(String::from(issuer));
+        self
+    }
+
+    pub fn connect_mode(&self) -> TlsMode {
+        self.connect
+    }
+
+    pub fn accepts(&self, mode: TlsMode) -> bool {
+        self.accept.contains(&mode)
+    }
+
+    fn configure_cert(&self, builder: &mut SslContextBuilder) -> Result<()> {
+        let (ca_file, cert_file, key_file) = match (&self.ca_file, &self.cert_file, &self.key_file)
+        {
+            (Some(ca), Some(cert), Some(key)) => (ca, cert, key),
+            _ => {
+                return Err(format_err!(
+                    "TLS certificate mode requires CA, cert and key files"
+                ))
+            }
+        };
+
+        builder.set_ca_file(ca_file)?;
+        builder.set_certificate_chain_file(cert_file)?;
+        builder.set_private_key_file(key_file, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        Ok(())
+    }
+
+    fn psk_key(&self) -> Result<TlsPsk> {
+        self.psk
+            .clone()
+            .ok_or_else(|| format_err!("TLS PSK mode requires PSK identity and key"))
+    }
+
+    ///
+    /// 在已建立的连接上按 TLSConnect 进行 TLS 握手（连接端）
+    ///
+    pub fn connect(&self, s: TcpStream) -> Result<TlsStream> {
+        let config = match self.connector()? {
+            Some(config) => config,
+            None => return Ok(TlsStream::Plain(s)),
+        };
+
+        let stream = config
+            .connect("", s)
+            .map_err(|e| format_err!("TLS handshake failed: {}", e))?;
+        self.verify_connected(stream.ssl())?;
+        Ok(TlsStream::Tls(stream, self.connect))
+    }
+
+    ///
+    /// 按 TLSConnect 生成连接端的 TLS 配置，未加密时返回 None
+    ///
+    pub(crate) fn connector(&self) -> Result<Option<ConnectConfiguration>> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+
+        match self.connect {
+            TlsMode::Unencrypted => return Ok(None),
+            TlsMode::Cert => {
+                builder.set_cipher_list(CERT_CIPHERS)?;
+                self.configure_cert(&mut builder)?;
+            }
+            TlsMode::Psk => {
+                let psk = self.psk_key()?;
+                builder.set_cipher_list(PSK_CIPHERS)?;
+                builder.set_verify(SslVerifyMode::NONE);
+                builder.set_psk_client_callback(move |_, _, identity, key| {
+                    let id = psk.identity.as_bytes();
+                    if id.len() >= identity.len() || psk.key.len() > key.len() {
+                        return Ok(0);
+                    }
+                    identity[..id.len()].copy_from_slice(id);
+                    identity[id.len()] = 0;
+                    key[..psk.key.len()].copy_from_slice(&psk.key);
+                    Ok(psk.key.len())
+                });
+            }
+        }
+
+        // zabbix 不校验主机名，只校验证书链以及 subject/issuer
+        let mut config = builder.build().configure()?;
+        config.set_verify_hostname(false);
+        config.set_use_server_name_indication(false);
+        Ok(Some(config))
+    }
+
+    ///
+    /// 握手完成后按 TLSServerCertSubject 和 TLSServerCertIssuer 校验服务端证书
+    ///
+    pub(crate) fn verify_connected(&self, ssl: &SslRef) -> Result<()> {
+        if self.connect == TlsMode::Cert {
+            self.verify_peer(ssl)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// 生成监听端使用的 TlsAcceptor
+    ///
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let accept_cert = self.accepts(TlsMode::Cert);
+        let accept_psk = self.accepts(TlsMode::Psk);
+        if !accept_cert && !accept_psk {
+            return Ok(TlsAcceptor {
+                acceptor: None,
+                config: self.clone(),
+            });
+        }
+
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+
+        let mut ciphers = vec![];
+        if accept_cert {
+            ciphers.push(CERT_CIPHERS);
+            self.configure_cert(&mut builder)?;
+        }
+        if accept_psk {
+            ciphers.push(PSK_CIPHERS);
+            let psk = self.psk_key()?;
+            builder.set_psk_server_callback(move |_, identity, key| {
+                if identity != Some(psk.identity.as_bytes()) || psk.key.len() > key.len() {
+                    return Ok(0);
+                }
+                key[..psk.key.len()].copy_from_slice(&psk.key);
+                Ok(psk.key.len())
+            });
+        }
+        builder.set_cipher_list(&ciphers.join(":"))?;
+
+        Ok(TlsAcceptor {
+            acceptor: Some(builder.build()),
+            config: self.clone(),
+        })
+    }
+
+    fn verify_peer(&self, ssl: &SslRef) -> Result<()> {
+        let cert = ssl
+            .peer_certificate()
+            .ok_or_else(|| format_err!("peer did not present a certificate"))?;
+
+        if let Some(subject) = &self.server_cert_subject {
+            let actual = rfc4514_name(cert.subject_name());
+            if subject != &actual {
+                return Err(format_err!(
+                    "certificate subject \"{}\" does not match \"{}\"",
+                    actual,
+                    subject
+                ));
+            }
+        }
+        if let Some(issuer) = &self.server_cert_issuer {
+            let actual = rfc4514_name(cert.issuer_name());
+            if issuer != &actual {
+                return Err(format_err!(
+                    "certificate issuer \"{}\" does not match \"{}\"",
+                    actual,
+                    issuer
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 监听端 TLS 握手，按 TLSAccept 同时支持未加密、PSK 和证书连接
+pub struct TlsAcceptor {
+    acceptor: Option<SslAcceptor>,
+    config: TlsConfig,
+}
+
+impl std::fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TlsAcceptor")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl TlsAcceptor {
+    ///
+    /// 根据第一个字节判断对端是否使用 TLS，完成握手并校验加密方式和对端证书
+    ///
+    pub fn accept(&self, s: TcpStream) -> Result<TlsStream> {
+        let mut first = [0; 1];
+        if s.peek(&mut first)? == 0 {
+            return Err(ProtocolError::UnexpectedEof.into());
+        }
+
+        if first[0] != TLS_HANDSHAKE {
+            if !self.config.accepts(TlsMode::Unencrypted) {
+                return Err(format_err!("unencrypted connections are not allowed"));
+            }
+            return Ok(TlsStream::Plain(s));
+        }
+
+        let acceptor = match &self.acceptor {
+            Some(acceptor) => acceptor,
+            None => return Err(format_err!("encrypted connections are not allowed")),
+        };
+        let stream = acceptor
+            .accept(s)
+            .map_err(|e| format_err!("TLS handshake failed: {}", e))?;
+
+        // PSK 握手时对端不发送证书
+        let mode = if stream.ssl().peer_certificate().is_some() {
+            TlsMode::Cert
+        } else {
+            TlsMode::Psk
+        };
+        if !self.config.accepts(mode) {
+            return Err(format_err!("{:?} connections are not allowed", mode));
+        }
+        if mode == TlsMode::Cert {
+            self.config.verify_peer(stream.ssl())?;
+        }
+        Ok(TlsStream::Tls(stream, mode))
+    }
+}
+
+/// 未加密或 TLS 加密的连接
+#[derive(Debug)]
+pub enum TlsStream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>, TlsMode),
+}
+
+impl TlsStream {
+    /// 实际使用的加密方式
+    pub fn mode(&self) -> TlsMode {
+        match self {
+            TlsStream::Plain(_) => TlsMode::Unencrypted,
+            TlsStream::Tls(_, mode) => *mode,
+        }
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        match self {
+            TlsStream::Plain(s) => s,
+            TlsStream::Tls(s, _) => s.get_ref(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Plain(s) => s.read(buf),
+            TlsStream::Tls(s, _) => s.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Plain(s) => s.write(buf),
+            TlsStream::Tls(s, _) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Plain(s) => s.flush(),
+            TlsStream::Tls(s, _) => s.flush(),
+        }
+    }
+}
+
+///
+/// 按 RFC 4514 格式输出证书名称，与 zabbix 的比较方式相同（逆序，逗号分隔）
+///
+fn rfc4514_name(name: &X509NameRef) -> String {
+    let mut parts = Vec::new();
+    for entry in name.entries() {
+        let key = entry.object().nid().short_name().unwrap_or("UNDEF");
+        let value = match entry.data().to_string() {
+            Ok(v) => escape_rfc4514(&v),
+            Err(_) => String::new(),
+        };
+        parts.push(format!("{}={}", key, value));
+    }
+    parts.reverse();
+    parts.join(",")
+}
+
+fn escape_rfc4514(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i == last && c == ' ');
+        if special {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::ZabbixProtocol;
+    use crate::ZbxStream;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509Name, X509};
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread;
+
+    fn name(cn: &str) -> X509Name {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("O", "Zabbix SIA").unwrap();
+        name.append_entry_by_text("CN", cn).unwrap();
+        name.build()
+    }
+
+    fn cert(cn: &str, issuer: Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(cn.len() as u32).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name(cn)).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.set_pubkey(&key).unwrap();
+        match issuer {
+            Some((ca, ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap();
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                let ca = openssl::x509::extension::BasicConstraints::new()
+                    .critical()
+                    .ca()
+                    .build()
+                    .unwrap();
+                builder.append_extension(ca).unwrap();
+                builder.set_issuer_name(&name(cn)).unwrap();
+                builder.sign(&key, MessageDigest::sha256()).unwrap();
+            }
+        }
+        (builder.build(), key)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("zabbix-tls-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    ///
+    /// 在 dir 下生成自签名 CA 及其签发的 server、agent 证书，返回 (server, agent) 配置
+    ///
+    pub(crate) fn certs(dir: &Path) -> (TlsConfig, TlsConfig) {
+        let (ca, ca_key) = cert("Zabbix CA", None);
+        fs::write(dir.join("ca.crt"), ca.to_pem().unwrap()).unwrap();
+
+        let mut result = vec![];
+        for cn in &["Zabbix server", "Zabbix agent"] {
+            let (c, k) = cert(cn, Some((&ca, &ca_key)));
+            let file = cn.replace(' ', "_");
+            let crt = dir.join(format!("{}.crt", file));
+            let key = dir.join(format!("{}.key", file));
+            fs::write(&crt, c.to_pem().unwrap()).unwrap();
+            fs::write(&key, k.private_key_to_pem_pkcs8().unwrap()).unwrap();
+            result.push(TlsConfig::new(dir.join("ca.crt"), crt, key));
+        }
+        let agent = result.pop().unwrap();
+        let server = result.pop().unwrap();
+        (server, agent)
+    }
+
+    #[test]
+    fn test_rfc4514_name() {
+        assert_eq!(
+            "CN=Zabbix agent,O=Zabbix SIA",
+            rfc4514_name(&name("Zabbix agent"))
+        );
+        assert_eq!("a\\,b\\+c", escape_rfc4514("a,b+c"));
+        assert_eq!("\\#a\\ ", escape_rfc4514("#a "));
+    }
+
+    #[test]
+    fn test_tls_cert() {
+        let dir = temp_dir("cert");
+        let (server, agent) = certs(&dir);
+        let acceptor = server
+            .with_server_cert_issuer("CN=Zabbix CA,O=Zabbix SIA")
+            .acceptor()
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                let (s, _) = listener.accept().unwrap();
+                if let Ok(s) = acceptor.accept(s) {
+                    // 第二次连接由客户端在校验 subject 后断开
+                    let mut stream = ZbxStream::new(s);
+                    if let Ok(req) = stream.read_frame() {
+                        stream.write_frame(&req).unwrap();
+                    }
+                }
+            }
+        });
+
+        let tls = agent.with_server_cert_subject("CN=Zabbix server,O=Zabbix SIA");
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_tls(tls.clone());
+        assert_eq!(b"ping".to_vec(), zbx.send("ping").unwrap());
+
+        let tls = tls.with_server_cert_subject("CN=Other server,O=Zabbix SIA");
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_tls(tls);
+        let e = zbx.send("ping").unwrap_err();
+        assert!(e.to_string().contains("does not match"), "{}", e);
+
+        handle.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    pub(crate) const PSK: &str = "1f87b595725ac58dd977beef14b97461a7c1045b9a1c963065002c5473194952";
+
+    pub(crate) fn echo_server(
+        config: TlsConfig,
+        count: usize,
+    ) -> (u16, thread::JoinHandle<Vec<TlsMode>>) {
+        let acceptor = config.acceptor().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut modes = vec![];
+            for _ in 0..count {
+                let (s, _) = listener.accept().unwrap();
+                if let Ok(s) = acceptor.accept(s) {
+                    modes.push(s.mode());
+                    let mut stream = ZbxStream::new(s);
+                    if let Ok(req) = stream.read_frame() {
+                        stream.write_frame(&req).unwrap();
+                    }
+                }
+            }
+            modes
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_tls_psk_key() {
+        assert!(TlsPsk::new("id", PSK).is_ok());
+        assert!(TlsPsk::new("id", "1f87b595725ac58d").is_err());
+        assert!(TlsPsk::new("id", &PSK.replace('f', "g")).is_err());
+        assert!(TlsPsk::new("", PSK).is_err());
+        assert_eq!(Ok(TlsMode::Psk), "psk".parse::<TlsMode>().map_err(|_| ()));
+    }
+
+    #[test]
+    fn test_tls_psk() {
+        let psk = TlsPsk::new("PSK 001", PSK).unwrap();
+        let server =
+            TlsConfig::from_psk(psk.clone()).with_accept(&[TlsMode::Unencrypted, TlsMode::Psk]);
+        let (port, handle) = echo_server(server, 3);
+
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_tls(TlsConfig::from_psk(psk));
+        assert_eq!(b"psk".to_vec(), zbx.send("psk").unwrap());
+
+        let zbx = ZabbixProtocol::new("127.0.0.1", port);
+        assert_eq!(b"plain".to_vec(), zbx.send("plain").unwrap());
+
+        let other = TlsPsk::new("PSK 002", PSK).unwrap();
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_tls(TlsConfig::from_psk(other));
+        assert!(zbx.send("other").is_err());
+
+        assert_eq!(
+            vec![TlsMode::Psk, TlsMode::Unencrypted],
+            handle.join().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tls_accept() {
+        let dir = temp_dir("accept");
+        let (server, agent) = certs(&dir);
+        let psk = TlsPsk::new("PSK 001", PSK).unwrap();
+        let (port, handle) = echo_server(server.clone().with_psk(psk.clone()), 3);
+
+        // 仅允许证书时拒绝未加密和 PSK 连接
+        let zbx =
+            ZabbixProtocol::new("127.0.0.1", port).with_timeout(std::time::Duration::from_secs(5));
+        assert!(zbx.send("plain").is_err());
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_tls(TlsConfig::from_psk(psk));
+        assert!(zbx.send("psk").is_err());
+        let zbx = ZabbixProtocol::new("127.0.0.1", port).with_tls(agent);
+        assert_eq!(b"cert".to_vec(), zbx.send("cert").unwrap());
+
+        assert_eq!(vec![TlsMode::Cert], handle.join().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,656 @@
+//! 用户宏
+//!
+//! 用户宏 `{$NAME}` 可以带上下文：`{$NAME:ctx}`、`{$NAME:"ctx"}`，定义时还可以使用
+//! 正则表达式上下文 `{$NAME:regex:"^ctx"}`。解析顺序与 zabbix 服务端相同：
+//! 先在主机上查找，再逐层查找关联的模板（同一层按模板编号顺序），最后查找全局宏。
+//! 上下文完全相同的宏优先于正则表达式匹配的宏；所有层都没有匹配的上下文时，
+//! 使用最近一层不带上下文的宏。
+use failure::Error;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use super::delay::Delay;
+use super::key::{ItemKey, KeyParam};
+use super::proxy::{compress_key, Item};
+use super::proxyconfig::{ConfigItem, ConfigMacro, ConfigPreproc, ProxyConfig};
+use super::Result;
+
+/// 密文宏在日志等处显示的值
+const MASKED: &str = "******";
+
+/// 宏的上下文
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MacroContext {
+    Static(String),
+    Regex(String),
+}
+
+/// 用户宏
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMacro {
+    pub name: String,
+    pub context: Option<MacroContext>,
+}
+
+impl UserMacro {
+    pub fn parse(s: &str) -> Result<Self> {
+        match Self::parse_prefix(s) {
+            Some((m, len)) if len == s.len() => Ok(m),
+            _ => Err(format_err!("invalid user macro: {}", s)),
+        }
+    }
+
+    ///
+    /// 解析 s 开头的宏，返回宏和占用的字节数
+    ///
+    fn parse_prefix(s: &str) -> Option<(Self, usize)> {
+        let rest = s.strip_prefix("{$")?;
+        let name_len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        if name_len == 0 {
+            return None;
+        }
+        let name = String::from(&rest[..name_len]);
+        let after = &rest[name_len..];
+
+        let (context, len) = if after.starts_with('}') {
+            (None, 1)
+        } else if let Some(ctx) = after.strip_prefix(':') {
+            let trimmed = ctx.trim_start_matches(' ');
+            if let Some(regex) = trimmed.strip_prefix("regex:") {
+                let skip = ctx.len() - regex.len();
+                let (value, n) = parse_context(regex)?;
+                (Some(MacroContext::Regex(value)), 1 + skip + n)
+            } else {
+                let (value, n) = parse_context(ctx)?;
+                (Some(MacroContext::Static(value)), 1 + n)
+            }
+        } else {
+            return None;
+        };
+        Some((Self { name, context }, 2 + name_len + len))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_' || c == '.'
+}
+
+///
+/// 解析上下文直到结束的 `}`，返回上下文和占用的字节数。
+/// 忽略开头的空格；加引号的上下文中 `\"` 表示双引号，结束引号后可以有空格
+///
+fn parse_context(s: &str) -> Option<(String, usize)> {
+    let start = s.len() - s.trim_start_matches(' ').len();
+    let body = &s[start..];
+    if !body.starts_with('"') {
+        let end = body.find('}')?;
+        return Some((String::from(&body[..end]), start + end + 1));
+    }
+
+    let mut value = String::new();
+    let mut chars = body.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, '"')) => value.push('"'),
+                Some((_, c)) => {
+                    value.push('\\');
+                    value.push(c);
+                }
+                None => return None,
+            },
+            '"' => {
+                let tail = &body[i + 1..];
+                let spaces = tail.len() - tail.trim_start_matches(' ').len();
+                return if tail[spaces..].starts_with('}') {
+                    Some((value, start + i + 1 + spaces + 1))
+                } else {
+                    None
+                };
+            }
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+impl FromStr for UserMacro {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl fmt::Display for UserMacro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let quote = |s: &str| s.replace('"', "\\\"");
+        match &self.context {
+            None => write!(f, "{{${}}}", self.name),
+            Some(MacroContext::Static(c)) => write!(f, "{{${}:\"{}\"}}", self.name, quote(c)),
+            Some(MacroContext::Regex(r)) => write!(f, "{{${}:regex:\"{}\"}}", self.name, quote(r)),
+        }
+    }
+}
+
+/// 一个宏定义
+#[derive(Debug, Clone)]
+struct Definition {
+    context: Option<MacroContext>,
+    /// 正则表达式上下文，无效的正则表达式为 None，不匹配任何上下文
+    regex: Option<Regex>,
+    value: String,
+    macro_type: u8,
+}
+
+impl Definition {
+    fn from(m: &ConfigMacro) -> Option<(String, Self)> {
+        let parsed = match UserMacro::parse(&m.macro_) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("{}", e);
+                return None;
+            }
+        };
+        let regex = match &parsed.context {
+            Some(MacroContext::Regex(r)) => match Regex::new(r) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    warn!(
+                        "invalid regular expression in user macro {}: {}",
+                        m.macro_, e
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+        let definition = Self {
+            context: parsed.context,
+            regex,
+            value: m.value.clone(),
+            macro_type: m.macro_type,
+        };
+        Some((parsed.name, definition))
+    }
+
+    fn is_secret(&self) -> bool {
+        self.macro_type == 1
+    }
+
+    ///
+    /// vault 宏的值是密钥的路径，proxy 不读取 vault，保持宏不变
+    ///
+    fn value(&self) -> Option<&str> {
+        if self.macro_type == 2 {
+            None
+        } else {
+            Some(&self.value)
+        }
+    }
+
+    ///
+    /// 同名宏中与 m 的上下文匹配的定义，完全相同的优先
+    ///
+    fn matching<'a>(defs: &'a [Self], m: &UserMacro) -> Option<&'a Self> {
+        let context = m.context.as_ref()?;
+        defs.iter()
+            .find(|d| d.context.as_ref() == Some(context))
+            .or_else(|| match context {
+                MacroContext::Static(c) => defs
+                    .iter()
+                    .find(|d| d.regex.as_ref().is_some_and(|r| r.is_match(c))),
+                MacroContext::Regex(_) => None,
+            })
+    }
+
+    fn default(defs: &[Self]) -> Option<&Self> {
+        defs.iter().find(|d| d.context.is_none())
+    }
+}
+
+type Macros = HashMap<String, Vec<Definition>>;
+
+/// 用户宏解析
+#[derive(Debug, Clone, Default)]
+pub struct MacroResolver {
+    global: Macros,
+    hosts: HashMap<i64, Macros>,
+    /// 主机或模板直接关联的模板，按编号排序
+    templates: HashMap<i64, Vec<i64>>,
+}
+
+impl MacroResolver {
+    /// 解析需要的表
+    pub(crate) const TABLES: &'static [&'static str] =
+        &["globalmacro", "hostmacro", "hosts_templates"];
+
+    pub fn new(config: &ProxyConfig) -> Self {
+        let mut resolver = Self::default();
+        for m in config.global_macros.values() {
+            if let Some((name, d)) = Definition::from(m) {
+                resolver.global.entry(name).or_default().push(d);
+            }
+        }
+        for m in config.host_macros.values() {
+            if let Some((name, d)) = Definition::from(m) {
+                let host = resolver.hosts.entry(m.hostid.unwrap_or(0)).or_default();
+                host.entry(name).or_default().push(d);
+            }
+        }
+        for t in config.hosts_templates.values() {
+            resolver
+                .templates
+                .entry(t.hostid)
+                .or_default()
+                .push(t.templateid);
+        }
+        for ids in resolver.templates.values_mut() {
+            ids.sort();
+            ids.dedup();
+        }
+        resolver
+    }
+
+    fn lookup(&self, hostid: i64, m: &UserMacro) -> Option<&Definition> {
+        let mut default = None;
+        let mut level = vec![hostid];
+        let mut seen = HashSet::new();
+        while !level.is_empty() {
+            for id in &level {
+                if let Some(defs) = self.hosts.get(id).and_then(|h| h.get(&m.name)) {
+                    if let Some(d) = Definition::matching(defs, m) {
+                        return Some(d);
+                    }
+                    default = default.or_else(|| Definition::default(defs));
+                }
+            }
+            seen.extend(level.iter().cloned());
+            // 下一层模板，跳过已经查找过的以防止循环关联
+            let mut next: Vec<i64> = level
+                .iter()
+                .filter_map(|id| self.templates.get(id))
+                .flatten()
+                .filter(|id| !seen.contains(*id))
+                .cloned()
+                .collect();
+            next.sort();
+            next.dedup();
+            level = next;
+        }
+
+        if let Some(defs) = self.global.get(&m.name) {
+            if let Some(d) = Definition::matching(defs, m) {
+                return Some(d);
+            }
+            default = default.or_else(|| Definition::default(defs));
+        }
+        default
+    }
+
+    ///
+    /// 主机上宏的值，找不到或为 vault 宏时返回 None
+    ///
+    pub fn value(&self, hostid: i64, m: &UserMacro) -> Option<&str> {
+        self.lookup(hostid, m).and_then(Definition::value)
+    }
+
+    ///
+    /// 替换 text 中的宏，无法解析的宏保持不变
+    ///
+    pub fn resolve(&self, hostid: i64, text: &str) -> String {
+        substitute(text, |m| self.value(hostid, m).map(String::from))
+    }
+
+    ///
+    /// 与 `resolve` 相同，但密文宏替换为 `******`，用于日志和显示
+    ///
+    pub fn resolve_masked(&self, hostid: i64, text: &str) -> String {
+        substitute(text, |m| {
+            let d = self.lookup(hostid, m)?;
+            if d.is_secret() {
+                Some(String::from(MASKED))
+            } else {
+                d.value().map(String::from)
+            }
+        })
+    }
+
+    ///
+    /// 替换监控项 key 参数中的宏，必要时为替换后的参数加上引号。
+    /// 无法解析的 key 按普通文本替换
+    ///
+    pub fn resolve_key(&self, hostid: i64, key: &str) -> String {
+        if !key.contains("{$") {
+            return String::from(key);
+        }
+        match ItemKey::parse(key) {
+            Ok(k) => {
+                let params = k
+                    .params()
+                    .iter()
+                    .map(|p| self.resolve_param(hostid, p))
+                    .collect();
+                ItemKey::new(k.name(), params).to_string()
+            }
+            Err(_) => self.resolve(hostid, key),
+        }
+    }
+
+    fn resolve_param(&self, hostid: i64, param: &KeyParam) -> KeyParam {
+        match param {
+            KeyParam::Value(v) => KeyParam::Value(self.resolve(hostid, v)),
+            KeyParam::Array(params) => KeyParam::Array(
+                params
+                    .iter()
+                    .map(|p| self.resolve_param(hostid, p))
+                    .collect(),
+            ),
+        }
+    }
+
+    ///
+    /// 监控项的预处理步骤，按步骤排序，参数和错误处理参数中的宏按监控项所属的主机解析
+    ///
+    pub fn item_preprocessing(&self, config: &ProxyConfig, itemid: i64) -> Vec<ConfigPreproc> {
+        let hostid = match config.items.get(&itemid) {
+            Some(item) => item.hostid,
+            None => return vec![],
+        };
+        config
+            .item_preprocessing(itemid)
+            .into_iter()
+            .map(|p| ConfigPreproc {
+                params: self.resolve(hostid, &p.params),
+                error_handler_params: self.resolve(hostid, &p.error_handler_params),
+                ..p.clone()
+            })
+            .collect()
+    }
+
+    ///
+    /// 解析 key 和间隔中的宏后生成 `Item`，停用或不需要检查的监控项返回 None
+    ///
+    pub(crate) fn item(&self, item: &ConfigItem, compress: &[&str]) -> Option<Item> {
+        if !item.is_monitored() {
+            return None;
+        }
+        let delay = match Delay::parse(&self.resolve(item.hostid, &item.delay)) {
+            Ok(delay) if delay.is_active() => delay,
+            _ => {
+                if contains_macro(&item.delay) {
+                    warn!(
+                        "item {} skipped, cannot resolve update interval \"{}\"",
+                        item.itemid,
+                        self.resolve_masked(item.hostid, &item.delay)
+                    );
+                }
+                return None;
+            }
+        };
+        let key_ = self.resolve_key(item.hostid, &item.key_);
+        Some(Item::new(
+            item.itemid,
+            item.hostid,
+            compress_key(&key_, compress),
+            delay,
+        ))
+    }
+}
+
+///
+/// text 中是否包含用户宏
+///
+pub(crate) fn contains_macro(text: &str) -> bool {
+    text.match_indices("{$")
+        .any(|(i, _)| UserMacro::parse_prefix(&text[i..]).is_some())
+}
+
+fn substitute<F: Fn(&UserMacro) -> Option<String>>(text: &str, f: F) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find("{$") {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        match UserMacro::parse_prefix(rest) {
+            Some((m, len)) => {
+                match f(&m) {
+                    Some(value) => result.push_str(&value),
+                    None => result.push_str(&rest[..len]),
+                }
+                rest = &rest[len..];
+            }
+            None => {
+                result.push_str("{$");
+                rest = &rest[2..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigCache, ConfigEvent};
+
+    fn config() -> ProxyConfig {
+        ProxyConfig::from_value(&json!({
+            "hosts": {
+                "fields": ["hostid", "host", "status"],
+                "data": [[1, "h1", 0], [2, "h2", 0], [100, "T1", 3], [101, "T2", 3], [102, "T3", 3]],
+            },
+            "hosts_templates": {
+                "fields": ["hosttemplateid", "hostid", "templateid"],
+                "data": [[1, 1, 101], [2, 1, 100], [3, 100, 102], [4, 102, 100], [5, 2, 102]],
+            },
+            "globalmacro": {
+                "fields": ["globalmacroid", "macro", "value", "type"],
+                "data": [
+                    [1, "{$INTERVAL}", "5m", 0],
+                    [2, "{$PORT}", "10050", 0],
+                    [3, "{$PORT:\"http\"}", "80", 0],
+                    [4, "{$FS:regex:\"^/var\"}", "var", 0],
+                    [5, "{$DB.PASS}", "secret", 1],
+                    [6, "{$VAULT}", "secret/zabbix:password", 2],
+                ],
+            },
+            "hostmacro": {
+                "fields": ["hostmacroid", "hostid", "macro", "value", "type"],
+                "data": [
+                    [10, 1, "{$INTERVAL}", "30s", 0],
+                    [11, 100, "{$PORT}", "20050", 0],
+                    [12, 101, "{$PORT}", "30050", 0],
+                    [13, 102, "{$FS}", "any", 0],
+                    [14, 102, "{$FS:\"/home\"}", "home", 0],
+                    [15, 102, "{$FS:regex:\"^/ho\"}", "ho", 0],
+                    [16, 102, "{$T3}", "deep", 0],
+                    [17, 2, "{$KEY.PARAM}", "a,b", 0],
+                ],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn value(r: &MacroResolver, hostid: i64, m: &str) -> Option<String> {
+        r.value(hostid, &m.parse().unwrap()).map(String::from)
+    }
+
+    #[test]
+    fn test_parse_user_macro() {
+        let m = UserMacro::parse("{$A.B_1}").unwrap();
+        assert_eq!(("A.B_1", None), (m.name.as_str(), m.context));
+
+        let ctx = |s: &str| UserMacro::parse(s).unwrap().context;
+        assert_eq!(Some(MacroContext::Static("x".into())), ctx("{$A:x}"));
+        assert_eq!(Some(MacroContext::Static("x y ".into())), ctx("{$A: x y }"));
+        assert_eq!(
+            Some(MacroContext::Static("a\"}b".into())),
+            ctx("{$A:\"a\\\"}b\"}")
+        );
+        assert_eq!(Some(MacroContext::Static("".into())), ctx("{$A:\"\" }"));
+        assert_eq!(
+            Some(MacroContext::Regex("^/v\\d".into())),
+            ctx("{$A:regex:\"^/v\\d\"}")
+        );
+        assert_eq!(Some(MacroContext::Regex("x".into())), ctx("{$A: regex:x}"));
+
+        for s in &[
+            "{$}",
+            "{$a}",
+            "{$A",
+            "{$A:\"x\"y}",
+            "{$A:\"x}",
+            "$A}",
+            "{$A}x",
+        ] {
+            assert!(UserMacro::parse(s).is_err(), "{}", s);
+        }
+
+        for s in &["{$A}", "{$A:\"a\\\"b\"}", "{$A:regex:\"^x\"}"] {
+            assert_eq!(*s, UserMacro::parse(s).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_macro_precedence() {
+        let r = config().macros();
+        // 主机优先于全局
+        assert_eq!(Some("30s".into()), value(&r, 1, "{$INTERVAL}"));
+        assert_eq!(Some("5m".into()), value(&r, 2, "{$INTERVAL}"));
+        // 同一层的模板按编号顺序
+        assert_eq!(Some("20050".into()), value(&r, 1, "{$PORT}"));
+        // 任何一层匹配的上下文优先于不带上下文的宏
+        assert_eq!(Some("80".into()), value(&r, 1, "{$PORT:http}"));
+        assert_eq!(Some("20050".into()), value(&r, 1, "{$PORT:ssh}"));
+        assert_eq!(Some("10050".into()), value(&r, 3, "{$PORT:ssh}"));
+        // 第二层模板，循环关联不影响查找
+        assert_eq!(Some("deep".into()), value(&r, 1, "{$T3}"));
+        assert_eq!(Some("deep".into()), value(&r, 100, "{$T3}"));
+        // 完全相同的上下文优先于正则表达式，正则表达式优先于不带上下文的宏
+        assert_eq!(Some("home".into()), value(&r, 2, "{$FS:\"/home\"}"));
+        assert_eq!(Some("ho".into()), value(&r, 2, "{$FS:/hope}"));
+        assert_eq!(Some("var".into()), value(&r, 2, "{$FS:/var/log}"));
+        assert_eq!(Some("any".into()), value(&r, 2, "{$FS:/tmp}"));
+        assert_eq!(Some("any".into()), value(&r, 2, "{$FS}"));
+        assert_eq!(None, value(&r, 3, "{$FS:/tmp}"));
+        // vault 宏不解析
+        assert_eq!(None, value(&r, 1, "{$VAULT}"));
+        assert_eq!(None, value(&r, 1, "{$UNKNOWN}"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let r = config().macros();
+        assert_eq!(
+            "30s;{$UNKNOWN}/1-5,09:00-18:00 {$ x",
+            r.resolve(1, "{$INTERVAL};{$UNKNOWN}/1-5,09:00-18:00 {$ x")
+        );
+        assert_eq!("db:secret", r.resolve(1, "db:{$DB.PASS}"));
+        assert_eq!("db:******", r.resolve_masked(1, "db:{$DB.PASS}"));
+        assert_eq!("{$VAULT}", r.resolve_masked(1, "{$VAULT}"));
+
+        assert_eq!("net.tcp.service", r.resolve_key(1, "net.tcp.service"));
+        assert_eq!(
+            "net.tcp.service[tcp,,20050]",
+            r.resolve_key(1, "net.tcp.service[tcp,,{$PORT}]")
+        );
+        assert_eq!(
+            "k[\"a,b\",[x,\"a,b\"]]",
+            r.resolve_key(2, "k[{$KEY.PARAM},[x,\"{$KEY.PARAM}\"]]")
+        );
+        assert_eq!("k[a,b", r.resolve_key(2, "k[{$KEY.PARAM}"));
+
+        assert!(contains_macro("a{$B:\"c\"}"));
+        assert!(!contains_macro("a{$b}"));
+    }
+
+    #[test]
+    fn test_resolve_items() {
+        let items = json!({
+            "fields": ["itemid", "hostid", "key_", "delay", "status"],
+            "data": [
+                [10, 1, "net.tcp.service[tcp,,{$PORT}]", "{$INTERVAL}", 0],
+                [11, 2, "agent.ping", "{$INTERVAL};1m/1-5,09:00-18:00", 0],
+                [12, 2, "agent.version", "{$MISSING}", 0],
+                [13, 2, "agent.hostname", "0;{$SCHEDULE}", 0],
+            ],
+        });
+        let mut value = json!({
+            "globalmacro": {"fields": ["globalmacroid", "macro", "value"], "data": [[1, "{$INTERVAL}", "5m"], [2, "{$SCHEDULE}", "wd1-5h9"]]},
+            "hostmacro": {
+                "fields": ["hostmacroid", "hostid", "macro", "value"],
+                "data": [[10, 1, "{$INTERVAL}", "30s"], [11, 100, "{$PORT}", "8080"]],
+            },
+            "hosts_templates": {"fields": ["hosttemplateid", "hostid", "templateid"], "data": [[1, 1, 100]]},
+            "items": items,
+        });
+        let config = ProxyConfig::from_value(&value).unwrap();
+        let items = config.monitored_items(&[]);
+        assert_eq!(3, items.len());
+        assert!(items.contains(&Item::new(10, 1, "net.tcp.service[tcp,,8080]".into(), 30)));
+        let delay = Delay::parse("5m;1m/1-5,09:00-18:00").unwrap();
+        assert!(items.contains(&Item::new(11, 2, "agent.ping".into(), delay)));
+        let delay = Delay::parse("0;wd1-5h9").unwrap();
+        assert!(items.contains(&Item::new(13, 2, "agent.hostname".into(), delay)));
+
+        let mut cache = ConfigCache::new();
+        cache.apply(&value).unwrap();
+        assert_eq!(items, cache.items(&[]));
+
+        // 宏变化时，使用宏的监控项产生事件
+        value = json!({
+            "config_revision": 2,
+            "data": {"globalmacro": {"fields": ["globalmacroid", "macro", "value"], "data": [[1, "{$INTERVAL}", "1m"], [2, "{$SCHEDULE}", "wd1-5h9"]]}},
+        });
+        let events = cache.apply(&value).unwrap();
+        assert_eq!(
+            vec![ConfigEvent::ItemDelayChanged {
+                itemid: 11,
+                old: Delay::parse("5m;1m/1-5,09:00-18:00").unwrap(),
+                new: Delay::parse("1m;1m/1-5,09:00-18:00").unwrap(),
+            }],
+            events
+        );
+    }
+
+    #[test]
+    fn test_resolve_preprocessing() {
+        let value = json!({
+            "hostmacro": {
+                "fields": ["hostmacroid", "hostid", "macro", "value", "type"],
+                "data": [[10, 1, "{$MULTIPLIER}", "8", 0], [11, 1, "{$PATTERN}", "secret", 1]],
+            },
+            "globalmacro": {"fields": ["globalmacroid", "macro", "value"], "data": [[1, "{$DEFAULT}", "0"]]},
+            "items": {
+                "fields": ["itemid", "hostid", "key_", "delay", "status"],
+                "data": [[10, 1, "net.if.in[eth0]", "1m", 0]],
+            },
+            "item_preproc": {
+                "fields": ["item_preprocid", "itemid", "step", "type", "params", "error_handler", "error_handler_params"],
+                "data": [
+                    [2, 10, 2, 1, "{$MULTIPLIER}", 3, "{$DEFAULT}"],
+                    [1, 10, 1, 5, "{$PATTERN}\n\\1", 0, ""],
+                ],
+            },
+        });
+        let config = ProxyConfig::from_value(&value).unwrap();
+        let macros = config.macros();
+
+        let steps = macros.item_preprocessing(&config, 10);
+        assert_eq!(2, steps.len());
+        assert_eq!(1, steps[0].step);
+        // 密文宏同样替换为实际的值
+        assert_eq!("secret\n\\1", steps[0].params);
+        assert_eq!("8", steps[1].params);
+        assert_eq!("0", steps[1].error_handler_params);
+        assert_eq!(
+            steps[1].preproc_type,
+            config.item_preprocessing(10)[1].preproc_type
+        );
+        assert!(macros.item_preprocessing(&config, 11).is_empty());
+    }
+}
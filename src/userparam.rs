@@ -0,0 +1,365 @@
+//! UserParameter
+//!
+//! 与 agent 配置文件的语法相同：`UserParameter=<key>,<command>`，key 以 `[*]` 结尾时
+//! 命令中的 `$1..$9` 替换为对应的参数，`$0` 替换为命令本身。
+//! 命令通过 `sh -c` 执行，去掉末尾空白的标准输出即为监控项的值。
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::handler::{ItemHandler, ItemValue};
+use super::key::{ItemKey, KeyParam};
+use super::Result;
+
+/// 未设置 UnsafeUserParameters 时参数中不允许的字符
+const UNSAFE_CHARS: &str = "\\'\"`*?[]{}~$!&;()<>|#@\n";
+
+/// 一条 UserParameter 定义
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserParameter {
+    name: String,
+    flexible: bool,
+    command: String,
+    unsafe_params: bool,
+    timeout: Duration,
+}
+
+impl UserParameter {
+    ///
+    /// 解析 `<key>,<command>`，即配置文件中 `UserParameter=` 之后的部分
+    ///
+    pub fn parse(definition: &str) -> Result<Self> {
+        let mut parts = definition.splitn(2, ',');
+        let key = parts.next().unwrap_or("").trim();
+        let command = match parts.next() {
+            Some(c) if !c.trim().is_empty() => c.trim(),
+            _ => {
+                return Err(format_err!(
+                    "user parameter \"{}\": command is missing",
+                    definition
+                ))
+            }
+        };
+
+        let (name, flexible) = match key.strip_suffix("[*]") {
+            Some(name) => (name, true),
+            None => (key, false),
+        };
+        let parsed = ItemKey::parse(name)
+            .map_err(|e| format_err!("user parameter \"{}\": {}", definition, e))?;
+        if !parsed.params().is_empty() {
+            return Err(format_err!(
+                "user parameter \"{}\": key parameters must be [*]",
+                definition
+            ));
+        }
+
+        Ok(Self {
+            name: String::from(name),
+            flexible,
+            command: String::from(command),
+            unsafe_params: false,
+            timeout: Duration::from_secs(3),
+        })
+    }
+
+    ///
+    /// 允许参数中包含特殊字符 (UnsafeUserParameters=1)
+    ///
+    pub fn with_unsafe_params(mut self, unsafe_params: bool) -> Self {
+        self.unsafe_params = unsafe_params;
+        self
+    }
+
+    ///
+    /// 命令执行超时 (Timeout)
+    ///
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    ///
+    /// 替换命令中的 `$0..$9`，返回要执行的命令
+    ///
+    pub fn command(&self, key: &ItemKey) -> Result<String> {
+        self.command_with(key, self.unsafe_params)
+    }
+
+    fn command_with(&self, key: &ItemKey, unsafe_params: bool) -> Result<String> {
+        if !self.flexible {
+            if !key.params().is_empty() {
+                return Err(format_err!("Item does not allow parameters."));
+            }
+            return Ok(self.command.clone());
+        }
+
+        let params: Vec<String> = key
+            .params()
+            .iter()
+            .map(|p| match p {
+                KeyParam::Value(v) => v.clone(),
+                KeyParam::Array(_) => p.to_string(),
+            })
+            .collect();
+        if !unsafe_params {
+            if let Some(p) = params
+                .iter()
+                .find(|p| p.contains(|c| UNSAFE_CHARS.contains(c)))
+            {
+                return Err(format_err!(
+                    "Special characters \"\\, ', \", `, *, ?, [, ], {{, }}, ~, $, !, &, ;, (, ), <, >, |, #, @, 0x0a\" are not allowed in the parameters: \"{}\".",
+                    p
+                ));
+            }
+        }
+
+        let mut result = String::with_capacity(self.command.len());
+        let mut chars = self.command.chars().peekable();
+        while let Some(c) = chars.next() {
+            let n = match (c, chars.peek().and_then(|d| d.to_digit(10))) {
+                ('$', Some(n)) => n as usize,
+                _ => {
+                    result.push(c);
+                    continue;
+                }
+            };
+            chars.next();
+            match n {
+                0 => result.push_str(&self.command),
+                n => result.push_str(params.get(n - 1).map_or("", |p| p.as_str())),
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl UserParameter {
+    fn run(&self, key: &ItemKey, unsafe_params: bool, timeout: Duration) -> Result<ItemValue> {
+        let command = self.command_with(key, unsafe_params)?;
+        let output = execute(&command, timeout)?;
+        Ok(ItemValue::Text(output))
+    }
+}
+
+impl ItemHandler for UserParameter {
+    fn get(&self, key: &ItemKey) -> Result<ItemValue> {
+        self.run(key, self.unsafe_params, self.timeout)
+    }
+}
+
+///
+/// 一个 agent 中所有 UserParameter 使用的 Timeout 和 UnsafeUserParameters
+///
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UserParamOptions {
+    pub timeout: Duration,
+    pub unsafe_params: bool,
+}
+
+///
+/// 使用 agent 选项执行的 UserParameter
+///
+pub(crate) struct SharedUserParameter {
+    param: UserParameter,
+    options: Arc<UserParamOptions>,
+}
+
+impl SharedUserParameter {
+    pub(crate) fn new(param: UserParameter, options: Arc<UserParamOptions>) -> Self {
+        Self { param, options }
+    }
+}
+
+impl ItemHandler for SharedUserParameter {
+    fn get(&self, key: &ItemKey) -> Result<ItemValue> {
+        self.param
+            .run(key, self.options.unsafe_params, self.options.timeout)
+    }
+}
+
+///
+/// 通过 `sh -c` 执行命令，超时后结束整个进程组。退出码非 0 时返回错误，原因为标准错误的内容
+///
+pub(crate) fn execute(command: &str, timeout: Duration) -> Result<String> {
+    trace!("execute: {}", command);
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // 在新的进程组中执行，超时后同时结束命令启动的子进程
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format_err!("Cannot execute command: {}", e))?;
+
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let timed_out = |child: &mut Child| {
+        kill(child);
+        Err(format_err!("Timeout while executing a shell script."))
+    };
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            return timed_out(&mut child);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    // 后台运行的子进程可能仍持有输出管道，读取同样受超时限制
+    let left = deadline.saturating_duration_since(Instant::now());
+    let stdout = match stdout.recv_timeout(left) {
+        Ok(stdout) => stdout,
+        Err(_) => return timed_out(&mut child),
+    };
+    if !status.success() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let stderr = stderr.recv_timeout(left).unwrap_or_default();
+        let reason = match stderr.trim_end() {
+            "" => stdout.trim_end().to_string(),
+            e => e.to_string(),
+        };
+        return Err(format_err!(
+            "Command exited with {}: {}",
+            status
+                .code()
+                .map_or_else(|| "signal".to_string(), |c| format!("code {}", c)),
+            reason
+        ));
+    }
+    Ok(stdout.trim_end().to_string())
+}
+
+///
+/// 结束进程组中的所有进程并回收 sh 进程
+///
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+///
+/// 在单独的线程中读取管道直到关闭，读取的内容通过 channel 返回
+///
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        let _ = tx.send(String::from_utf8_lossy(&output).into_owned());
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: &str) -> ItemKey {
+        ItemKey::parse(key).unwrap()
+    }
+
+    #[test]
+    fn test_user_parameter_parse() {
+        let up = UserParameter::parse("mysql.ping[*], mysqladmin -u$1 -p$2 ping").unwrap();
+        assert_eq!("mysql.ping", up.name());
+        assert_eq!(
+            "mysqladmin -uzabbix -p ping",
+            up.command(&key("mysql.ping[zabbix]")).unwrap()
+        );
+        let up = UserParameter::parse("a[*],echo $0 $1$").unwrap();
+        assert_eq!("echo echo $0 $1$ x$", up.command(&key("a[x]")).unwrap());
+
+        assert!(UserParameter::parse("key").is_err());
+        assert!(UserParameter::parse("key[a],echo").is_err());
+        assert!(UserParameter::parse("bad key,echo").is_err());
+
+        let up = UserParameter::parse("app.ok,echo ok").unwrap();
+        assert_eq!("echo ok", up.command(&key("app.ok")).unwrap());
+        assert!(up.command(&key("app.ok[1]")).is_err());
+    }
+
+    #[test]
+    fn test_user_parameter_unsafe() {
+        let up = UserParameter::parse("echo[*],echo $1").unwrap();
+        let e = up.command(&key("echo[\"a;reboot\"]")).unwrap_err();
+        assert!(e.to_string().starts_with("Special characters"));
+
+        let up = up.with_unsafe_params(true);
+        assert_eq!(
+            "echo a;reboot",
+            up.command(&key("echo[\"a;reboot\"]")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_user_parameter_execute() {
+        let up = UserParameter::parse("echo[*],echo \"$2-$1\"; echo").unwrap();
+        assert_eq!(
+            ItemValue::Text("b-a".to_string()),
+            up.get(&key("echo[a,b]")).unwrap()
+        );
+
+        let up = UserParameter::parse("fail,echo oops >&2; exit 3").unwrap();
+        assert_eq!(
+            "Command exited with code 3: oops",
+            up.get(&key("fail")).unwrap_err().to_string()
+        );
+
+        let up = UserParameter::parse("slow,sleep 5")
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        let start = Instant::now();
+        assert_eq!(
+            "Timeout while executing a shell script.",
+            up.get(&key("slow")).unwrap_err().to_string()
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_user_parameter_kill_group() {
+        let marker = std::env::temp_dir().join(format!("zabbix-up-{}", std::process::id()));
+        let command = format!("(sleep 1; touch {}) & sleep 100 & wait", marker.display());
+        let start = Instant::now();
+        assert_eq!(
+            "Timeout while executing a shell script.",
+            execute(&command, Duration::from_millis(200))
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        // 子进程随进程组一起结束
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+
+        // sh 退出后后台进程仍持有标准输出，同样在超时后返回
+        let start = Instant::now();
+        assert!(execute("sleep 100 &", Duration::from_millis(200)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+}